@@ -0,0 +1,92 @@
+use clap::{Parser, Subcommand};
+use std::io::{self, BufRead, IsTerminal};
+use std::net::IpAddr;
+
+use crate::domain::models::{DomainError, NewLocalUser, Role};
+use crate::usecase::LocalUserUseCase;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// Listen address
+    #[arg(short, long, default_value = "127.0.0.1")]
+    pub address: IpAddr,
+    /// Listen port
+    #[arg(short, long, default_value_t = 3217)]
+    pub port: u16,
+    /// Development mode: allow the placeholder session secret
+    #[arg(long)]
+    pub dev: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage dashboard users in the htpasswd file instead of starting the server
+    User {
+        #[command(subcommand)]
+        action: UserCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// List users and their roles
+    List,
+    /// Add a user
+    Add {
+        username: String,
+        /// Role written to the roles file, default_role applies without it
+        #[arg(long)]
+        role: Option<Role>,
+    },
+    /// Delete a user
+    Del { username: String },
+    /// Set a new password for a user
+    Passwd { username: String },
+}
+
+// Asks twice on a terminal; otherwise the first line of stdin is the
+// password, for scripts
+fn read_password() -> Result<String, DomainError> {
+    let io_error = |e: io::Error| DomainError::InvalidInput(format!("Cannot read password: {}", e));
+
+    if !io::stdin().is_terminal() {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line).map_err(io_error)?;
+        return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    let password = rpassword::prompt_password("New password: ").map_err(io_error)?;
+    let confirmation = rpassword::prompt_password("Repeat password: ").map_err(io_error)?;
+    if password != confirmation {
+        return Err(DomainError::InvalidInput("passwords do not match".to_string()));
+    }
+    Ok(password)
+}
+
+pub async fn run_user_command(use_case: &LocalUserUseCase, command: UserCommand) -> Result<(), DomainError> {
+    match command {
+        UserCommand::List => {
+            for user in use_case.list_users().await? {
+                println!("{}\t{}", user.username, user.role);
+            }
+        }
+        UserCommand::Add { username, role } => {
+            let password = read_password()?;
+            let user = use_case.create_user(NewLocalUser { username, password, role }).await?;
+            println!("Added user {} ({})", user.username, user.role);
+        }
+        UserCommand::Del { username } => {
+            use_case.delete_user(&username).await?;
+            println!("Deleted user {}", username);
+        }
+        UserCommand::Passwd { username } => {
+            let password = read_password()?;
+            use_case.reset_password(&username, &password).await?;
+            println!("Changed password of {}", username);
+        }
+    }
+    Ok(())
+}
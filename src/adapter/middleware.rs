@@ -0,0 +1,417 @@
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method, StatusCode},
+    web,
+    Error,
+    HttpMessage,
+    HttpResponse,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use log::{debug, error};
+use serde::Deserialize;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::adapter::session::csrf_token_matches;
+use crate::domain::models::{ApiToken, AuthUser, Role};
+use crate::usecase::ApiTokenUseCase;
+
+// Where state-changing requests send the session's CSRF token: scripts in
+// this header, HTML forms in this field
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+// A browser sends a certificate along with any request, like a cookie, but
+// cannot add this header to a cross-site request without a CORS preflight,
+// which this server never answers. Scripts using a certificate send it
+// instead of a CSRF token
+pub const REQUESTED_WITH_HEADER: &str = "X-Requested-With";
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf_token: Option<String>,
+}
+
+// Reads the logged-in user from the session, as stored by the login handler
+pub fn session_user(session: &Session) -> Option<AuthUser> {
+    let username = session.get::<String>("username").ok()??;
+    let role = session.get::<Role>("role").ok()??;
+    Some(AuthUser::new(username, role))
+}
+
+// Attached to the request by `ApiAuthentication` when it carried a valid
+// bearer token instead of a session cookie
+#[derive(Clone)]
+pub struct TokenPrincipal(pub ApiToken);
+
+impl TokenPrincipal {
+    pub fn user(&self) -> AuthUser {
+        AuthUser::new(format!("token:{}", self.0.name), self.0.scope.role())
+    }
+}
+
+// Attached to HTTPS connections whose client certificate is mapped to a role
+// in `tls.client_roles`, and to their /api requests by `ApiAuthentication`
+#[derive(Clone)]
+pub struct CertificatePrincipal(pub AuthUser);
+
+// The caller of a request, from its API token or client certificate, or else
+// its session
+pub fn request_user<R: HttpMessage + SessionExt>(req: &R) -> Option<AuthUser> {
+    let machine_user = {
+        let extensions = req.extensions();
+        extensions.get::<TokenPrincipal>().map(TokenPrincipal::user)
+            .or_else(|| extensions.get::<CertificatePrincipal>().map(|principal| principal.0.clone()))
+    };
+    machine_user.or_else(|| session_user(&req.get_session()))
+}
+
+// Requests made by scripts through an API token or a client certificate
+fn machine_request(req: &ServiceRequest) -> bool {
+    let extensions = req.extensions();
+    extensions.contains::<TokenPrincipal>() || extensions.contains::<CertificatePrincipal>()
+}
+
+fn json_error(status: StatusCode, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({
+        "success": false,
+        "message": message
+    }))
+}
+
+fn bearer_token(req: &ServiceRequest) -> Option<Result<String, ()>> {
+    let value = req.headers().get(header::AUTHORIZATION)?;
+    let token = value.to_str().ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+        .ok_or(());
+    Some(token)
+}
+
+// Authenticates everything under /api and /metrics, either through the session cookie, an
+// `Authorization: Bearer <token>` header or a client certificate. A request
+// that sends a header is judged by the token alone, a bad token is never
+// retried as a session
+pub struct ApiAuthentication {
+    api_token_use_case: Arc<ApiTokenUseCase>,
+}
+
+impl ApiAuthentication {
+    pub fn new(api_token_use_case: Arc<ApiTokenUseCase>) -> Self {
+        Self { api_token_use_case }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ApiAuthentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ApiAuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiAuthenticationMiddleware {
+            service: Rc::new(service),
+            api_token_use_case: self.api_token_use_case.clone(),
+        }))
+    }
+}
+
+pub struct ApiAuthenticationMiddleware<S> {
+    service: Rc<S>,
+    api_token_use_case: Arc<ApiTokenUseCase>,
+}
+
+impl<S, B> Service<ServiceRequest> for ApiAuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let api_token_use_case = self.api_token_use_case.clone();
+
+        Box::pin(async move {
+            let rejection = match bearer_token(&req) {
+                Some(Ok(secret)) => match api_token_use_case.authenticate(&secret).await {
+                    Ok(Some(token)) => {
+                        req.extensions_mut().insert(TokenPrincipal(token));
+                        None
+                    }
+                    Ok(None) => Some("Invalid API token"),
+                    Err(e) => {
+                        error!("API token check failed: {}", e);
+                        Some("Invalid API token")
+                    }
+                },
+                Some(Err(())) => Some("Malformed Authorization header, expected a bearer token"),
+                None if session_user(&req.get_session()).is_some() => None,
+                None => match req.conn_data::<CertificatePrincipal>().cloned() {
+                    Some(principal) => {
+                        req.extensions_mut().insert(principal);
+                        None
+                    }
+                    None => Some("Authentication required"),
+                },
+            };
+
+            match rejection {
+                None => service.call(req).await.map(ServiceResponse::map_into_left_body),
+                Some(message) => {
+                    debug!("Rejected {} {}: {}", req.method(), req.path(), message);
+                    let response = json_error(StatusCode::UNAUTHORIZED, message)
+                        .map_into_right_body();
+                    Ok(req.into_response(response))
+                }
+            }
+        })
+    }
+}
+
+// Rejects requests whose session or API token does not carry at least `role`.
+// Anonymous page requests are sent to the login page, anonymous API calls get a 401
+#[derive(Clone, Copy)]
+pub struct RequireRole {
+    role: Role,
+    allow_tokens: bool,
+}
+
+impl RequireRole {
+    pub fn viewer() -> Self {
+        Self { role: Role::Viewer, allow_tokens: true }
+    }
+
+    pub fn operator() -> Self {
+        Self { role: Role::Operator, allow_tokens: true }
+    }
+
+    pub fn admin() -> Self {
+        Self { role: Role::Admin, allow_tokens: true }
+    }
+
+    // For actions that must be done by a person, such as managing tokens
+    pub fn session_only(self) -> Self {
+        Self { allow_tokens: false, ..self }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireRoleMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireRoleMiddleware {
+            service,
+            requirement: *self,
+        }))
+    }
+}
+
+pub struct RequireRoleMiddleware<S> {
+    service: S,
+    requirement: RequireRole,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireRoleMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let RequireRole { role, allow_tokens } = self.requirement;
+        let machine = machine_request(&req);
+
+        let response = match request_user(&req) {
+            Some(user) if machine && !allow_tokens => {
+                debug!("API client {} denied access to {} {}", user.username, req.method(), req.path());
+                json_error(StatusCode::FORBIDDEN, "This action is not available to API tokens and client certificates")
+            }
+            Some(user) if user.role.allows(role) => {
+                let future = self.service.call(req);
+                return Box::pin(async move { future.await.map(ServiceResponse::map_into_left_body) });
+            }
+            Some(user) => {
+                debug!("User {} ({}) denied access to {} {}", user.username, user.role, req.method(), req.path());
+                json_error(
+                    StatusCode::FORBIDDEN,
+                    &format!("This action requires the {} role", role),
+                )
+            }
+            None if req.path().starts_with("/api") || req.path() == "/ws" || req.path() == "/metrics" => {
+                json_error(StatusCode::UNAUTHORIZED, "Authentication required")
+            }
+            None => HttpResponse::SeeOther()
+                .append_header(("Location", "/login"))
+                .finish(),
+        };
+
+        let response = req.into_response(response).map_into_right_body();
+        Box::pin(async move { Ok(response) })
+    }
+}
+
+// host[:port] of an Origin or Referer value
+fn url_host(url: &str) -> Option<&str> {
+    let rest = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"))?;
+    rest.split(['/', '?', '#']).next().filter(|host| !host.is_empty())
+}
+
+// Protects every POST, PUT, PATCH and DELETE that relies on the session
+// cookie. The request must carry the session's CSRF token, and its Origin,
+// or Referer when the browser sent no Origin, must name this server or one
+// of `trusted_origins`. /api requests with a bearer token are left to
+// `ApiAuthentication`, which then ignores the session; a browser never sends
+// a bearer token on its own, unlike cached Basic credentials.
+// Client certificate requests without a session have no token to send and
+// need a trusted Origin or an X-Requested-With header instead
+#[derive(Clone)]
+pub struct CsrfProtection {
+    trusted_origins: Rc<Vec<String>>,
+}
+
+impl CsrfProtection {
+    pub fn new(trusted_origins: &[String]) -> Self {
+        let trusted_origins = trusted_origins.iter()
+            .map(|origin| origin.trim_end_matches('/').to_ascii_lowercase())
+            .collect();
+        Self { trusted_origins: Rc::new(trusted_origins) }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CsrfProtection
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CsrfProtectionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfProtectionMiddleware {
+            service: Rc::new(service),
+            trusted_origins: self.trusted_origins.clone(),
+        }))
+    }
+}
+
+pub struct CsrfProtectionMiddleware<S> {
+    service: Rc<S>,
+    trusted_origins: Rc<Vec<String>>,
+}
+
+// Whether the Origin (or Referer without one) is this server or a trusted
+// origin, `None` when the request names neither
+fn origin_trusted(req: &ServiceRequest, trusted_origins: &[String]) -> Option<bool> {
+    let headers = req.headers();
+    let source = headers.get(header::ORIGIN).or_else(|| headers.get(header::REFERER))?;
+    let Ok(source) = source.to_str() else {
+        return Some(false);
+    };
+
+    let source = source.to_ascii_lowercase();
+    let trusted = trusted_origins.iter()
+        .any(|origin| source == *origin || source.starts_with(&format!("{}/", origin)));
+    Some(trusted || url_host(&source).is_some_and(|host| host.eq_ignore_ascii_case(req.connection_info().host())))
+}
+
+// The token from the header, or from the body of a form submission. The body
+// is read here and put back for the handler
+async fn submitted_token(req: &mut ServiceRequest) -> Result<Option<String>, Error> {
+    if let Some(value) = req.headers().get(CSRF_HEADER) {
+        return Ok(value.to_str().ok().map(str::to_string));
+    }
+    if req.content_type() != "application/x-www-form-urlencoded" {
+        return Ok(None);
+    }
+
+    let body = req.extract::<web::Bytes>().await?;
+    let token = serde_urlencoded::from_bytes::<CsrfForm>(&body).ok().and_then(|form| form.csrf_token);
+    req.set_payload(Payload::from(body));
+    Ok(token)
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfProtectionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let trusted_origins = self.trusted_origins.clone();
+
+        Box::pin(async move {
+            let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+            let token_request = req.path().starts_with("/api/") && matches!(bearer_token(&req), Some(Ok(_)));
+            if safe || token_request {
+                return service.call(req).await.map(ServiceResponse::map_into_left_body);
+            }
+
+            let certificate_only = req.conn_data::<CertificatePrincipal>().is_some()
+                && session_user(&req.get_session()).is_none();
+
+            // A missing Origin and Referer is let through where the token is
+            // still required, but not on the strength of a certificate alone
+            let origin = origin_trusted(&req, &trusted_origins);
+            let rejection = if origin == Some(false) {
+                Some("Cross-origin request refused")
+            } else if certificate_only {
+                let explicit = origin.is_some() || req.headers().contains_key(REQUESTED_WITH_HEADER);
+                (!explicit).then_some("Missing Origin or X-Requested-With header")
+            } else {
+                match submitted_token(&mut req).await? {
+                    Some(token) if csrf_token_matches(&req.get_session(), &token) => None,
+                    Some(_) => Some("Invalid CSRF token"),
+                    None => Some("Missing CSRF token"),
+                }
+            };
+
+            let Some(message) = rejection else {
+                return service.call(req).await.map(ServiceResponse::map_into_left_body);
+            };
+
+            debug!("Rejected {} {}: {}", req.method(), req.path(), message);
+            // Forms go back to the login page, which asks to try again
+            let response = if req.path().starts_with("/api") {
+                json_error(StatusCode::FORBIDDEN, message)
+            } else {
+                HttpResponse::SeeOther()
+                    .append_header(("Location", "/login?error=csrf"))
+                    .finish()
+            };
+            Ok(req.into_response(response).map_into_right_body())
+        })
+    }
+}
@@ -0,0 +1,506 @@
+use async_trait::async_trait;
+use md5::{Digest, Md5};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{
+    self, ClientConfig, DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+};
+use log::{debug, error, info, warn};
+
+use crate::domain::{
+    models::{VpnUser, MikrotikConfig, PppSecret, NewPppSecret, PppSecretUpdate, DomainError},
+    traits::{MetricsRecorder, MikrotikService, PppSecretService},
+};
+use super::RouterBackend;
+use super::types::{
+    MikrotikPppActiveResponse, MikrotikPppSecretResponse, MikrotikError, PPP_SECRET_PROPLIST,
+    new_secret_fields, secret_update_fields,
+};
+
+const PPP_ACTIVE_PROPLIST: &str = ".id,name,service,caller-id,address,uptime,comment";
+// Longest word accepted from the router. Real replies stay far below this;
+// the length prefix alone could otherwise make us allocate up to 4 GiB
+const MAX_WORD_LENGTH: usize = 4 * 1024 * 1024;
+
+// Attributes of a single `!re` or `!done` sentence, keyed without the leading `=`
+type ApiAttributes = HashMap<String, String>;
+
+trait ApiStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ApiStream for T {}
+
+struct ApiReply {
+    rows: Vec<ApiAttributes>,
+    done: ApiAttributes,
+}
+
+struct ApiConnection {
+    stream: Box<dyn ApiStream>,
+}
+
+impl ApiConnection {
+    async fn command(&mut self, words: &[String]) -> Result<ApiReply, MikrotikError> {
+        self.write_sentence(words).await?;
+
+        let mut rows = Vec::new();
+        let mut trap: Option<String> = None;
+
+        loop {
+            let sentence = self.read_sentence().await?;
+            let Some((reply_word, attributes)) = sentence.split_first() else {
+                continue;
+            };
+            let attributes = parse_attributes(attributes);
+
+            match reply_word.as_str() {
+                "!re" => rows.push(attributes),
+                "!trap" => {
+                    // A trap is always followed by `!done`, keep reading to stay in sync
+                    trap = Some(attributes.get("message").cloned().unwrap_or_default());
+                }
+                "!done" => {
+                    return match trap {
+                        Some(message) => Err(MikrotikError::ApiError(message)),
+                        None => Ok(ApiReply { rows, done: attributes }),
+                    };
+                }
+                "!fatal" => {
+                    let message = sentence.get(1).cloned().unwrap_or_default();
+                    return Err(MikrotikError::ProtocolError(format!("Router closed the session: {}", message)));
+                }
+                other => {
+                    warn!("Ignoring unexpected RouterOS API reply: {}", other);
+                }
+            }
+        }
+    }
+
+    async fn write_sentence(&mut self, words: &[String]) -> Result<(), MikrotikError> {
+        let mut buffer = Vec::new();
+
+        for word in words {
+            buffer.extend_from_slice(&encode_length(word.len()));
+            buffer.extend_from_slice(word.as_bytes());
+        }
+        buffer.push(0);
+
+        self.stream.write_all(&buffer).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn read_sentence(&mut self) -> Result<Vec<String>, MikrotikError> {
+        let mut words = Vec::new();
+
+        loop {
+            let length = self.read_length().await?;
+            if length == 0 {
+                return Ok(words);
+            }
+            if length > MAX_WORD_LENGTH {
+                return Err(MikrotikError::ProtocolError(format!(
+                    "Word of {} bytes exceeds the limit of {} bytes", length, MAX_WORD_LENGTH
+                )));
+            }
+
+            let mut word = vec![0u8; length];
+            self.stream.read_exact(&mut word).await?;
+            words.push(String::from_utf8_lossy(&word).into_owned());
+        }
+    }
+
+    async fn read_length(&mut self) -> Result<usize, MikrotikError> {
+        let first = self.stream.read_u8().await?;
+
+        let (extra_bytes, initial) = match first {
+            b if b & 0x80 == 0x00 => (0, b as usize),
+            b if b & 0xC0 == 0x80 => (1, (b & 0x3F) as usize),
+            b if b & 0xE0 == 0xC0 => (2, (b & 0x1F) as usize),
+            b if b & 0xF0 == 0xE0 => (3, (b & 0x0F) as usize),
+            0xF0 => (4, 0),
+            b => return Err(MikrotikError::ProtocolError(format!("Invalid word length prefix: {:#04x}", b))),
+        };
+
+        let mut length = initial;
+        for _ in 0..extra_bytes {
+            length = (length << 8) | self.stream.read_u8().await? as usize;
+        }
+
+        Ok(length)
+    }
+}
+
+fn encode_length(length: usize) -> Vec<u8> {
+    let len = length as u32;
+
+    match length {
+        0..=0x7F => vec![len as u8],
+        0x80..=0x3FFF => ((len | 0x8000) as u16).to_be_bytes().to_vec(),
+        0x4000..=0x1F_FFFF => (len | 0xC0_0000).to_be_bytes()[1..].to_vec(),
+        0x20_0000..=0xFFF_FFFF => (len | 0xE000_0000).to_be_bytes().to_vec(),
+        _ => {
+            let mut encoded = vec![0xF0];
+            encoded.extend_from_slice(&len.to_be_bytes());
+            encoded
+        }
+    }
+}
+
+fn parse_attributes(words: &[String]) -> ApiAttributes {
+    words.iter()
+        .filter_map(|word| {
+            let pair = word.strip_prefix('=')?;
+            let (key, value) = pair.split_once('=')?;
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+// RouterOS ships self-signed certificates for api-ssl, mirroring the REST
+// client which also accepts invalid certificates
+#[derive(Debug)]
+struct AcceptAnyServerCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+pub struct RouterOsApiClient {
+    config: MikrotikConfig,
+    tls_connector: Option<TlsConnector>,
+    connection: Mutex<Option<ApiConnection>>,
+    metrics: Arc<dyn MetricsRecorder + Send + Sync>,
+}
+
+impl RouterOsApiClient {
+    pub fn new(config: MikrotikConfig, metrics: Arc<dyn MetricsRecorder + Send + Sync>) -> Result<Self, DomainError> {
+        let tls_connector = if config.protocol == "api-ssl" {
+            let provider = Arc::new(ring::default_provider());
+            let tls_config = ClientConfig::builder_with_provider(provider.clone())
+                .with_safe_default_protocol_versions()
+                .map_err(|e| DomainError::ConfigurationError(e.to_string()))?
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyServerCert(provider)))
+                .with_no_client_auth();
+
+            Some(TlsConnector::from(Arc::new(tls_config)))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            config,
+            tls_connector,
+            connection: Mutex::new(None),
+            metrics,
+        })
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.timeout_seconds)
+    }
+
+    async fn connect(&self) -> Result<ApiConnection, MikrotikError> {
+        debug!("Opening RouterOS API connection to {}:{}", self.config.address, self.config.port);
+
+        let tcp_stream = TcpStream::connect((self.config.address.as_str(), self.config.port)).await?;
+
+        let stream: Box<dyn ApiStream> = match &self.tls_connector {
+            Some(connector) => {
+                let server_name = ServerName::try_from(self.config.address.clone())
+                    .map_err(|e| MikrotikError::ProtocolError(format!("Invalid router address: {}", e)))?;
+                Box::new(connector.connect(server_name, tcp_stream).await?)
+            }
+            None => Box::new(tcp_stream),
+        };
+
+        let mut connection = ApiConnection { stream };
+        self.login(&mut connection).await?;
+
+        info!("Logged in to RouterOS API at {}:{}", self.config.address, self.config.port);
+        Ok(connection)
+    }
+
+    async fn login(&self, connection: &mut ApiConnection) -> Result<(), MikrotikError> {
+        let reply = connection.command(&[
+            "/login".to_string(),
+            format!("=name={}", self.config.username),
+            format!("=password={}", self.config.password),
+        ]).await.map_err(login_error)?;
+
+        // RouterOS before 6.43 answers with an MD5 challenge instead of logging in
+        if let Some(challenge) = reply.done.get("ret") {
+            let challenge = hex::decode(challenge)
+                .map_err(|e| MikrotikError::ProtocolError(format!("Invalid login challenge: {}", e)))?;
+
+            let mut hasher = Md5::new();
+            hasher.update([0u8]);
+            hasher.update(self.config.password.as_bytes());
+            hasher.update(&challenge);
+            let response = format!("00{}", hex::encode(hasher.finalize()));
+
+            connection.command(&[
+                "/login".to_string(),
+                format!("=name={}", self.config.username),
+                format!("=response={}", response),
+            ]).await.map_err(login_error)?;
+        }
+
+        Ok(())
+    }
+
+    async fn execute_command(&self, words: Vec<String>) -> Result<Vec<ApiAttributes>, MikrotikError> {
+        // Labelled with the command path, such as /ppp/active/print
+        let operation = words.first().cloned().unwrap_or_default();
+        let started = Instant::now();
+        let result = self.send_command(words).await;
+
+        let error = result.as_ref().err().map(MikrotikError::kind);
+        self.metrics.router_request(&self.config.name, &operation, started.elapsed(), error);
+        result
+    }
+
+    async fn send_command(&self, words: Vec<String>) -> Result<Vec<ApiAttributes>, MikrotikError> {
+        let mut connection = self.connection.lock().await;
+
+        // A cached connection may have been closed by the router, so retry once on a fresh one
+        for attempt in 0..2 {
+            let reused = connection.is_some();
+
+            if connection.is_none() {
+                let fresh = timeout(self.timeout(), self.connect())
+                    .await
+                    .map_err(|_| MikrotikError::Timeout)??;
+                *connection = Some(fresh);
+            }
+
+            let Some(active) = connection.as_mut() else {
+                continue;
+            };
+
+            match timeout(self.timeout(), active.command(&words)).await {
+                Ok(Ok(reply)) => return Ok(reply.rows),
+                Ok(Err(MikrotikError::ApiError(message))) => {
+                    return Err(MikrotikError::ApiError(message));
+                }
+                Ok(Err(e)) => {
+                    *connection = None;
+                    if reused && attempt == 0 {
+                        debug!("RouterOS API connection lost, reconnecting: {}", e);
+                        continue;
+                    }
+                    return Err(e);
+                }
+                Err(_) => {
+                    *connection = None;
+                    return Err(MikrotikError::Timeout);
+                }
+            }
+        }
+
+        Err(MikrotikError::ProtocolError("Unable to reach RouterOS API".to_string()))
+    }
+
+    async fn print<T: DeserializeOwned>(&self, menu: &str, proplist: &str, name: Option<&str>) -> Result<Vec<T>, MikrotikError> {
+        let mut words = vec![
+            format!("{}/print", menu),
+            format!("=.proplist={}", proplist),
+        ];
+        if let Some(name) = name {
+            words.push(format!("?name={}", name));
+        }
+
+        let rows = self.execute_command(words).await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let value = serde_json::to_value(row)?;
+                Ok(serde_json::from_value(value)?)
+            })
+            .collect()
+    }
+
+    async fn find_sessions(&self, user_name: Option<&str>) -> Result<Vec<MikrotikPppActiveResponse>, MikrotikError> {
+        self.print("/ppp/active", PPP_ACTIVE_PROPLIST, user_name).await
+    }
+
+    async fn find_secret(&self, name: &str) -> Result<MikrotikPppSecretResponse, DomainError> {
+        let secrets: Vec<MikrotikPppSecretResponse> = self.print("/ppp/secret", PPP_SECRET_PROPLIST, Some(name)).await?;
+
+        secrets.into_iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| DomainError::SecretNotFound(name.to_string()))
+    }
+
+    // Traps on configuration commands are the router refusing the change,
+    // or the item having been removed since it was looked up
+    async fn secret_command(&self, command: &str, fields: Vec<(&'static str, String)>) -> Result<(), DomainError> {
+        let mut words = vec![command.to_string()];
+        words.extend(fields.into_iter().map(|(key, value)| format!("={}={}", key, value)));
+
+        match self.execute_command(words).await {
+            Ok(_) => Ok(()),
+            Err(MikrotikError::ApiError(message)) if message.contains("no such item") => {
+                Err(MikrotikError::NotFound(message).into())
+            }
+            Err(MikrotikError::ApiError(message)) => Err(MikrotikError::Rejected(message).into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn to_secret(&self, response: MikrotikPppSecretResponse) -> PppSecret {
+        let mut secret: PppSecret = response.into();
+        secret.router = Some(self.config.name.clone());
+        secret
+    }
+}
+
+fn login_error(err: MikrotikError) -> MikrotikError {
+    match err {
+        MikrotikError::ApiError(message) => {
+            error!("RouterOS API login rejected: {}", message);
+            MikrotikError::AuthenticationError
+        }
+        other => other,
+    }
+}
+
+#[async_trait]
+impl RouterBackend for RouterOsApiClient {
+    async fn poll_active_connections(&self) -> Result<Vec<VpnUser>, MikrotikError> {
+        info!("Fetching active connections from MikroTik API");
+
+        let mikrotik_users = self.find_sessions(None).await?;
+        debug!("Retrieved {} active connections from MikroTik API", mikrotik_users.len());
+
+        let vpn_users: Vec<VpnUser> = mikrotik_users
+            .into_iter()
+            .map(|mikrotik_user| mikrotik_user.into())
+            .collect();
+
+        info!("Successfully fetched {} VPN users", vpn_users.len());
+        Ok(vpn_users)
+    }
+}
+
+#[async_trait]
+impl MikrotikService for RouterOsApiClient {
+    async fn fetch_active_connections(&self) -> Result<Vec<VpnUser>, DomainError> {
+        Ok(self.poll_active_connections().await?)
+    }
+
+    async fn disconnect_user(&self, _router: Option<&str>, user_name: &str) -> Result<(), DomainError> {
+        info!("Disconnecting user: {}", user_name);
+
+        let sessions = self.find_sessions(Some(user_name)).await?;
+
+        if sessions.is_empty() {
+            error!("User not found for disconnection: {}", user_name);
+            return Err(MikrotikError::UserNotFound(user_name.to_string()).into());
+        }
+
+        for session in sessions {
+            let words = vec![
+                "/ppp/active/remove".to_string(),
+                format!("=.id={}", session.id),
+            ];
+
+            if let Err(e) = self.execute_command(words).await {
+                error!("Failed to disconnect session {} of user {}: {}", session.id, user_name, e);
+                return Err(e.into());
+            }
+
+            debug!("Removed session {} of user {}", session.id, user_name);
+        }
+
+        info!("Successfully disconnected user: {}", user_name);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PppSecretService for RouterOsApiClient {
+    async fn list_secrets(&self, _router: Option<&str>) -> Result<Vec<PppSecret>, DomainError> {
+        let secrets: Vec<MikrotikPppSecretResponse> = self.print("/ppp/secret", PPP_SECRET_PROPLIST, None).await?;
+        debug!("Retrieved {} PPP secrets from MikroTik API", secrets.len());
+
+        Ok(secrets.into_iter().map(|s| self.to_secret(s)).collect())
+    }
+
+    async fn create_secret(&self, _router: Option<&str>, secret: &NewPppSecret) -> Result<PppSecret, DomainError> {
+        self.secret_command("/ppp/secret/add", new_secret_fields(secret)).await?;
+
+        info!("Created PPP secret: {}", secret.name);
+        Ok(self.to_secret(self.find_secret(&secret.name).await?))
+    }
+
+    async fn update_secret(&self, _router: Option<&str>, name: &str, update: &PppSecretUpdate) -> Result<PppSecret, DomainError> {
+        let existing = self.find_secret(name).await?;
+
+        let mut fields = vec![(".id", existing.id)];
+        fields.extend(secret_update_fields(update));
+        self.secret_command("/ppp/secret/set", fields).await?;
+
+        info!("Updated PPP secret: {}", name);
+        Ok(self.to_secret(self.find_secret(name).await?))
+    }
+
+    async fn set_secret_disabled(&self, _router: Option<&str>, name: &str, disabled: bool) -> Result<PppSecret, DomainError> {
+        let existing = self.find_secret(name).await?;
+
+        let command = if disabled { "/ppp/secret/disable" } else { "/ppp/secret/enable" };
+        self.secret_command(command, vec![(".id", existing.id)]).await?;
+
+        info!("{} PPP secret: {}", if disabled { "Disabled" } else { "Enabled" }, name);
+        Ok(self.to_secret(self.find_secret(name).await?))
+    }
+
+    async fn delete_secret(&self, _router: Option<&str>, name: &str) -> Result<(), DomainError> {
+        let existing = self.find_secret(name).await?;
+
+        self.secret_command("/ppp/secret/remove", vec![(".id", existing.id)]).await?;
+
+        info!("Deleted PPP secret: {}", name);
+        Ok(())
+    }
+}
@@ -1,21 +1,27 @@
 use async_trait::async_trait;
 use reqwest::Client;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use log::{debug, error, info};
 
 use crate::domain::{
-    models::{VpnUser, MikrotikConfig, DomainError},
-    traits::MikrotikService,
+    models::{VpnUser, MikrotikConfig, PppSecret, NewPppSecret, PppSecretUpdate, DomainError},
+    traits::{MetricsRecorder, MikrotikService, PppSecretService},
+};
+use super::RouterBackend;
+use super::types::{
+    MikrotikApiRequest, MikrotikApiMethod, MikrotikPppActiveResponse, MikrotikPppSecretResponse, MikrotikError,
+    new_secret_fields, secret_update_fields,
 };
-use super::types::{MikrotikApiRequest, MikrotikApiMethod, MikrotikPppActiveResponse, MikrotikError};
 
 pub struct MikrotikClient {
     client: Client,
     config: MikrotikConfig,
+    metrics: Arc<dyn MetricsRecorder + Send + Sync>,
 }
 
 impl MikrotikClient {
-    pub fn new(config: MikrotikConfig) -> Result<Self, DomainError> {
+    pub fn new(config: MikrotikConfig, metrics: Arc<dyn MetricsRecorder + Send + Sync>) -> Result<Self, DomainError> {
         let mut client_builder = Client::builder()
             .timeout(Duration::from_secs(config.timeout_seconds));
 
@@ -28,24 +34,28 @@ impl MikrotikClient {
             .build()
             .map_err(|e| DomainError::NetworkError(e.to_string()))?;
 
-        Ok(Self { client, config })
+        Ok(Self { client, config, metrics })
     }
 
     async fn execute_request(&self, request: MikrotikApiRequest) -> Result<reqwest::Response, MikrotikError> {
+        let operation = request.method.as_str();
+        let started = Instant::now();
+        let result = self.send_request(request).await;
+
+        let error = result.as_ref().err().map(MikrotikError::kind);
+        self.metrics.router_request(&self.config.name, operation, started.elapsed(), error);
+        result
+    }
+
+    async fn send_request(&self, request: MikrotikApiRequest) -> Result<reqwest::Response, MikrotikError> {
         let url = request.build_url(&self.config.base_url());
-        debug!("Executing MikroTik request: {} {}", 
-               match request.method {
-                   MikrotikApiMethod::Get => "GET",
-                   MikrotikApiMethod::Post => "POST",
-                   MikrotikApiMethod::Put => "PUT",
-                   MikrotikApiMethod::Delete => "DELETE",
-               }, 
-               url);
+        debug!("Executing MikroTik request: {} {}", request.method.as_str(), url);
 
         let mut req_builder = match request.method {
             MikrotikApiMethod::Get => self.client.get(&url),
             MikrotikApiMethod::Post => self.client.post(&url),
             MikrotikApiMethod::Put => self.client.put(&url),
+            MikrotikApiMethod::Patch => self.client.patch(&url),
             MikrotikApiMethod::Delete => self.client.delete(&url),
         };
 
@@ -66,10 +76,17 @@ impl MikrotikClient {
             let error_text = response.text().await.unwrap_or_default();
             error!("MikroTik API error: {} - {}", status, error_text);
             
-            if status.as_u16() == 401 {
-                Err(MikrotikError::AuthenticationError)
-            } else {
-                Err(MikrotikError::ApiError(format!("HTTP {}: {}", status, error_text)))
+            // RouterOS explains rejected requests in the "detail" field
+            let detail = serde_json::from_str::<serde_json::Value>(&error_text)
+                .ok()
+                .and_then(|body| body.get("detail").and_then(|d| d.as_str()).map(str::to_string))
+                .unwrap_or_else(|| error_text.clone());
+            
+            match status.as_u16() {
+                401 => Err(MikrotikError::AuthenticationError),
+                400 => Err(MikrotikError::Rejected(detail)),
+                404 => Err(MikrotikError::NotFound(detail)),
+                _ => Err(MikrotikError::ApiError(format!("HTTP {}: {}", status, error_text))),
             }
         }
     }
@@ -83,11 +100,39 @@ impl MikrotikClient {
         
         Ok(mikrotik_users)
     }
+
+    async fn find_sessions_by_name(&self, user_name: &str) -> Result<Vec<MikrotikPppActiveResponse>, MikrotikError> {
+        let request = MikrotikApiRequest::get_user_details(user_name);
+        let response = self.execute_request(request).await?;
+        
+        let sessions: Vec<MikrotikPppActiveResponse> = response.json().await?;
+        
+        // Guard against routers that ignore the query filter
+        Ok(sessions.into_iter().filter(|s| s.name == user_name).collect())
+    }
+
+    async fn find_secret(&self, name: &str) -> Result<MikrotikPppSecretResponse, DomainError> {
+        let request = MikrotikApiRequest::get_secret_by_name(name);
+        let response = self.execute_request(request).await?;
+        
+        let secrets: Vec<MikrotikPppSecretResponse> = response.json().await
+            .map_err(MikrotikError::from)?;
+        
+        secrets.into_iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| DomainError::SecretNotFound(name.to_string()))
+    }
+
+    fn to_secret(&self, response: MikrotikPppSecretResponse) -> PppSecret {
+        let mut secret: PppSecret = response.into();
+        secret.router = Some(self.config.name.clone());
+        secret
+    }
 }
 
 #[async_trait]
-impl MikrotikService for MikrotikClient {
-    async fn fetch_active_connections(&self) -> Result<Vec<VpnUser>, DomainError> {
+impl RouterBackend for MikrotikClient {
+    async fn poll_active_connections(&self) -> Result<Vec<VpnUser>, MikrotikError> {
         info!("Fetching active connections from MikroTik");
         
         let mikrotik_users = self.get_active_connections_raw().await?;
@@ -100,31 +145,97 @@ impl MikrotikService for MikrotikClient {
         info!("Successfully fetched {} VPN users", vpn_users.len());
         Ok(vpn_users)
     }
+}
+
+#[async_trait]
+impl MikrotikService for MikrotikClient {
+    async fn fetch_active_connections(&self) -> Result<Vec<VpnUser>, DomainError> {
+        Ok(self.poll_active_connections().await?)
+    }
 
-    async fn disconnect_user(&self, user_name: &str) -> Result<(), DomainError> {
+    async fn disconnect_user(&self, _router: Option<&str>, user_name: &str) -> Result<(), DomainError> {
         info!("Disconnecting user: {}", user_name);
         
-        // Note: MikroTik REST API requires specific .id to disconnect
-        // This is a simplified implementation. In production, you'd need to:
-        // 1. First get the connection to find its .id
-        // 2. Then use that .id to disconnect
+        let sessions = self.find_sessions_by_name(user_name).await?;
         
-        // For now, we'll implement a basic approach
-        let request = MikrotikApiRequest::disconnect_user(user_name);
+        if sessions.is_empty() {
+            error!("User not found for disconnection: {}", user_name);
+            return Err(MikrotikError::UserNotFound(user_name.to_string()).into());
+        }
         
-        match self.execute_request(request).await {
-            Ok(_) => {
-                info!("Successfully disconnected user: {}", user_name);
-                Ok(())
-            }
-            Err(MikrotikError::UserNotFound(_)) => {
-                error!("User not found for disconnection: {}", user_name);
-                Err(DomainError::UserNotFound(user_name.to_string()))
-            }
-            Err(e) => {
-                error!("Failed to disconnect user {}: {}", user_name, e);
-                Err(e.into())
+        // The same account may hold several sessions (e.g. multiple devices)
+        for session in sessions {
+            let request = MikrotikApiRequest::disconnect_session(&session.id);
+            
+            if let Err(e) = self.execute_request(request).await {
+                error!("Failed to disconnect session {} of user {}: {}", session.id, user_name, e);
+                return Err(e.into());
             }
+            
+            debug!("Removed session {} of user {}", session.id, user_name);
         }
+        
+        info!("Successfully disconnected user: {}", user_name);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PppSecretService for MikrotikClient {
+    async fn list_secrets(&self, _router: Option<&str>) -> Result<Vec<PppSecret>, DomainError> {
+        let response = self.execute_request(MikrotikApiRequest::get_secrets()).await?;
+        
+        let secrets: Vec<MikrotikPppSecretResponse> = response.json().await
+            .map_err(MikrotikError::from)?;
+        debug!("Retrieved {} PPP secrets from MikroTik", secrets.len());
+        
+        Ok(secrets.into_iter().map(|s| self.to_secret(s)).collect())
+    }
+
+    async fn create_secret(&self, _router: Option<&str>, secret: &NewPppSecret) -> Result<PppSecret, DomainError> {
+        let request = MikrotikApiRequest::create_secret(new_secret_fields(secret));
+        let response = self.execute_request(request).await?;
+        
+        let created: MikrotikPppSecretResponse = response.json().await
+            .map_err(MikrotikError::from)?;
+        
+        info!("Created PPP secret: {}", secret.name);
+        Ok(self.to_secret(created))
+    }
+
+    async fn update_secret(&self, _router: Option<&str>, name: &str, update: &PppSecretUpdate) -> Result<PppSecret, DomainError> {
+        let existing = self.find_secret(name).await?;
+        
+        let request = MikrotikApiRequest::update_secret(&existing.id, secret_update_fields(update));
+        let response = self.execute_request(request).await?;
+        
+        let updated: MikrotikPppSecretResponse = response.json().await
+            .map_err(MikrotikError::from)?;
+        
+        info!("Updated PPP secret: {}", name);
+        Ok(self.to_secret(updated))
+    }
+
+    async fn set_secret_disabled(&self, _router: Option<&str>, name: &str, disabled: bool) -> Result<PppSecret, DomainError> {
+        let existing = self.find_secret(name).await?;
+        
+        let value = if disabled { "yes" } else { "no" }.to_string();
+        let request = MikrotikApiRequest::update_secret(&existing.id, vec![("disabled", value)]);
+        let response = self.execute_request(request).await?;
+        
+        let updated: MikrotikPppSecretResponse = response.json().await
+            .map_err(MikrotikError::from)?;
+        
+        info!("{} PPP secret: {}", if disabled { "Disabled" } else { "Enabled" }, name);
+        Ok(self.to_secret(updated))
+    }
+
+    async fn delete_secret(&self, _router: Option<&str>, name: &str) -> Result<(), DomainError> {
+        let existing = self.find_secret(name).await?;
+        
+        self.execute_request(MikrotikApiRequest::delete_secret(&existing.id)).await?;
+        
+        info!("Deleted PPP secret: {}", name);
+        Ok(())
     }
 }
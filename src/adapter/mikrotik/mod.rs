@@ -1,6 +1,41 @@
+pub mod api;
 pub mod client;
+pub mod registry;
 pub mod types;
 
+pub use api::*;
 pub use client::*;
+pub use registry::*;
 #[allow(unused_imports)]
-pub use types::*;
\ No newline at end of file
+pub use types::*;
+
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use crate::domain::{
+    models::{MikrotikConfig, DomainError, VpnUser},
+    traits::{MetricsRecorder, MikrotikService, PppSecretService},
+};
+
+// Everything a single router connection can do, regardless of transport
+#[async_trait]
+pub trait RouterBackend: MikrotikService + PppSecretService {
+    // Like fetch_active_connections, but keeps the transport error so the
+    // registry can tell an unreachable router from a refused login
+    async fn poll_active_connections(&self) -> Result<Vec<VpnUser>, MikrotikError>;
+}
+
+// Picks the transport matching the configured protocol
+pub fn create_mikrotik_service(
+    config: MikrotikConfig,
+    metrics: Arc<dyn MetricsRecorder + Send + Sync>,
+) -> Result<Arc<dyn RouterBackend + Send + Sync>, DomainError> {
+    match config.protocol.as_str() {
+        "http" | "https" => Ok(Arc::new(MikrotikClient::new(config, metrics)?)),
+        "api" | "api-ssl" => Ok(Arc::new(RouterOsApiClient::new(config, metrics)?)),
+        other => Err(DomainError::ConfigurationError(format!(
+            "Unsupported MikroTik protocol '{}', expected http, https, api or api-ssl",
+            other
+        ))),
+    }
+}
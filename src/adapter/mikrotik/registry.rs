@@ -0,0 +1,273 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::future::join_all;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use log::{debug, error, info, warn};
+
+use crate::domain::{
+    models::{VpnUser, MikrotikConfig, PppSecret, NewPppSecret, PppSecretUpdate, DomainError, HealthStatus, RouterStatus},
+    traits::{MetricsRecorder, MikrotikService, PppSecretService, RouterStatusService},
+};
+use super::{MikrotikError, RouterBackend, create_mikrotik_service};
+
+pub struct RouterHandle {
+    pub name: String,
+    pub service: Arc<dyn RouterBackend + Send + Sync>,
+}
+
+// Polls in a row a router may miss before its last sessions are given up
+const MAX_MISSED_POLLS: u32 = 3;
+
+// Last successful inventory of a router, used when it misses a poll so a
+// short outage does not report its sessions as disconnected
+struct Inventory {
+    users: Vec<VpnUser>,
+    missed_polls: u32,
+}
+
+pub struct RouterRegistry {
+    routers: Vec<RouterHandle>,
+    inventories: RwLock<HashMap<String, Inventory>>,
+    statuses: RwLock<HashMap<String, RouterStatus>>,
+}
+
+// Outcome of one poll; a refused login proves the router answered, and any
+// other reply from it proves it was reachable without telling whether the
+// login was accepted
+fn poll_status(name: &str, result: &Result<Vec<VpnUser>, MikrotikError>) -> RouterStatus {
+    let (status, reachable, authenticated) = match result {
+        Ok(_) => (HealthStatus::Ok, Some(true), Some(true)),
+        Err(MikrotikError::AuthenticationError) => (HealthStatus::Down, Some(true), Some(false)),
+        Err(MikrotikError::Timeout | MikrotikError::IoError(_)) => (HealthStatus::Down, Some(false), None),
+        Err(MikrotikError::HttpError(e)) if e.is_connect() || e.is_timeout() => (HealthStatus::Down, Some(false), None),
+        Err(_) => (HealthStatus::Down, Some(true), None),
+    };
+
+    RouterStatus {
+        name: name.to_string(),
+        status,
+        reachable,
+        authenticated,
+        checked_at: Some(Utc::now()),
+    }
+}
+
+impl RouterRegistry {
+    pub fn new(routers: Vec<RouterHandle>) -> Self {
+        Self {
+            routers,
+            inventories: RwLock::new(HashMap::new()),
+            statuses: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_configs(
+        configs: Vec<MikrotikConfig>,
+        metrics: Arc<dyn MetricsRecorder + Send + Sync>,
+    ) -> Result<Self, DomainError> {
+        let routers = configs
+            .into_iter()
+            .map(|config| {
+                let name = config.name.clone();
+                info!("Registering router {} ({}://{}:{})", name, config.protocol, config.address, config.port);
+                Ok(RouterHandle {
+                    name,
+                    service: create_mikrotik_service(config, metrics.clone())?,
+                })
+            })
+            .collect::<Result<Vec<_>, DomainError>>()?;
+
+        Ok(Self::new(routers))
+    }
+
+    fn find_router(&self, name: &str) -> Option<&RouterHandle> {
+        self.routers.iter().find(|router| router.name == name)
+    }
+
+    fn select_router(&self, router: Option<&str>) -> Result<&RouterHandle, DomainError> {
+        match router {
+            Some(name) => self.find_router(name)
+                .ok_or_else(|| DomainError::InvalidInput(format!("Unknown router: {}", name))),
+            None if self.routers.len() == 1 => Ok(&self.routers[0]),
+            None => Err(DomainError::InvalidInput(
+                "router must be specified when several routers are configured".to_string()
+            )),
+        }
+    }
+
+    async fn owning_routers(&self, user_name: &str) -> Vec<String> {
+        let inventories = self.inventories.read().await;
+
+        inventories
+            .iter()
+            .filter(|(_, inventory)| inventory.users.iter().any(|u| u.name == user_name))
+            .map(|(router, _)| router.clone())
+            .collect()
+    }
+
+    // The router a user is connected to when the caller did not name one
+    async fn owning_router(&self, user_name: &str) -> Result<&RouterHandle, DomainError> {
+        if let [router] = self.routers.as_slice() {
+            return Ok(router);
+        }
+
+        let mut owners = self.owning_routers(user_name).await;
+        if owners.is_empty() {
+            // The session may be newer than the last poll
+            self.fetch_active_connections().await?;
+            owners = self.owning_routers(user_name).await;
+        }
+
+        match owners.as_slice() {
+            [owner] => self.find_router(owner).ok_or_else(|| DomainError::UserNotFound(user_name.to_string())),
+            [] => Err(DomainError::UserNotFound(user_name.to_string())),
+            _ => Err(DomainError::InvalidInput(format!(
+                "{} is connected to {}, router must be specified", user_name, owners.join(", ")
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl MikrotikService for RouterRegistry {
+    async fn fetch_active_connections(&self) -> Result<Vec<VpnUser>, DomainError> {
+        debug!("Polling {} routers", self.routers.len());
+
+        let results = join_all(self.routers.iter().map(|router| async move {
+            (router.name.as_str(), router.service.poll_active_connections().await)
+        }))
+        .await;
+
+        let mut inventories = self.inventories.write().await;
+        let mut statuses = self.statuses.write().await;
+        let mut all_users = Vec::new();
+        let mut last_error = None;
+        let mut failed = 0;
+        let mut dropped = false;
+
+        for (router_name, result) in results {
+            statuses.insert(router_name.to_string(), poll_status(router_name, &result));
+            match result.map_err(DomainError::from) {
+                Ok(mut users) => {
+                    for user in users.iter_mut() {
+                        user.router = Some(router_name.to_string());
+                    }
+                    inventories.insert(router_name.to_string(), Inventory { users: users.clone(), missed_polls: 0 });
+                    all_users.extend(users);
+                }
+                Err(e) => {
+                    error!("Failed to poll router {}: {}", router_name, e);
+                    failed += 1;
+                    if let Some(inventory) = inventories.get_mut(router_name) {
+                        inventory.missed_polls += 1;
+                        if inventory.missed_polls > MAX_MISSED_POLLS {
+                            warn!("Dropping {} sessions of router {}, it missed {} polls", inventory.users.len(), router_name, inventory.missed_polls);
+                            inventories.remove(router_name);
+                            dropped = true;
+                        } else {
+                            warn!("Keeping {} last known sessions of router {}", inventory.users.len(), router_name);
+                            all_users.extend(inventory.users.iter().cloned());
+                        }
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        // Sessions that were given up have to reach the caller as gone, even
+        // when no router answered
+        match last_error {
+            Some(e) if failed == self.routers.len() && !dropped => Err(e),
+            _ => Ok(all_users),
+        }
+    }
+
+    async fn disconnect_user(&self, router: Option<&str>, user_name: &str) -> Result<(), DomainError> {
+        let router = match router {
+            Some(name) => self.select_router(Some(name))?,
+            None => self.owning_router(user_name).await?,
+        };
+
+        info!("Disconnecting user {} on router {}", user_name, router.name);
+        router.service.disconnect_user(None, user_name).await?;
+
+        let mut inventories = self.inventories.write().await;
+        if let Some(inventory) = inventories.get_mut(&router.name) {
+            inventory.users.retain(|u| u.name != user_name);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RouterStatusService for RouterRegistry {
+    async fn router_statuses(&self) -> Vec<RouterStatus> {
+        let statuses = self.statuses.read().await;
+
+        self.routers
+            .iter()
+            .map(|router| statuses.get(&router.name).cloned().unwrap_or_else(|| RouterStatus {
+                name: router.name.clone(),
+                status: HealthStatus::Down,
+                reachable: None,
+                authenticated: None,
+                checked_at: None,
+            }))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl PppSecretService for RouterRegistry {
+    async fn list_secrets(&self, router: Option<&str>) -> Result<Vec<PppSecret>, DomainError> {
+        if let Some(name) = router {
+            return self.select_router(Some(name))?.service.list_secrets(None).await;
+        }
+
+        let results = join_all(self.routers.iter().map(|router| router.service.list_secrets(None))).await;
+
+        let mut secrets = Vec::new();
+        for result in results {
+            secrets.extend(result?);
+        }
+        Ok(secrets)
+    }
+
+    async fn create_secret(&self, router: Option<&str>, secret: &NewPppSecret) -> Result<PppSecret, DomainError> {
+        self.select_router(router)?.service.create_secret(None, secret).await
+    }
+
+    async fn update_secret(&self, router: Option<&str>, name: &str, update: &PppSecretUpdate) -> Result<PppSecret, DomainError> {
+        self.select_router(router)?.service.update_secret(None, name, update).await
+    }
+
+    async fn set_secret_disabled(&self, router: Option<&str>, name: &str, disabled: bool) -> Result<PppSecret, DomainError> {
+        self.select_router(router)?.service.set_secret_disabled(None, name, disabled).await
+    }
+
+    async fn delete_secret(&self, router: Option<&str>, name: &str) -> Result<(), DomainError> {
+        self.select_router(router)?.service.delete_secret(None, name).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reachability(error: MikrotikError) -> (Option<bool>, Option<bool>) {
+        let status = poll_status("core", &Err(error));
+        (status.reachable, status.authenticated)
+    }
+
+    #[test]
+    fn poll_errors_tell_unreachable_from_refused() {
+        assert_eq!(reachability(MikrotikError::AuthenticationError), (Some(true), Some(false)));
+        assert_eq!(reachability(MikrotikError::Timeout), (Some(false), None));
+        assert_eq!(reachability(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into()), (Some(false), None));
+        assert_eq!(reachability(MikrotikError::ApiError("no such command".to_string())), (Some(true), None));
+        assert_eq!(reachability(MikrotikError::ProtocolError("Router closed the session".to_string())), (Some(true), None));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::domain::models::VpnUser;
+use crate::domain::models::{VpnUser, PppSecret, NewPppSecret, PppSecretUpdate};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,14 +16,75 @@ pub struct MikrotikPppActiveResponse {
 
 impl From<MikrotikPppActiveResponse> for VpnUser {
     fn from(mikrotik_user: MikrotikPppActiveResponse) -> Self {
-        VpnUser::new(
+        let mut user = VpnUser::new(
             mikrotik_user.name,
             mikrotik_user.service,
             mikrotik_user.caller_id,
             mikrotik_user.address,
             mikrotik_user.uptime,
             mikrotik_user.comment,
-        )
+        );
+        user.session_id = Some(mikrotik_user.id);
+        user
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MikrotikPppSecretResponse {
+    #[serde(rename = ".id")]
+    pub id: String,
+    pub name: String,
+    pub service: Option<String>,
+    pub profile: Option<String>,
+    #[serde(rename = "remote-address")]
+    pub remote_address: Option<String>,
+    pub comment: Option<String>,
+    pub disabled: Option<String>,
+}
+
+impl From<MikrotikPppSecretResponse> for PppSecret {
+    fn from(secret: MikrotikPppSecretResponse) -> Self {
+        PppSecret {
+            id: secret.id,
+            name: secret.name,
+            service: secret.service,
+            profile: secret.profile,
+            remote_address: secret.remote_address,
+            comment: secret.comment,
+            disabled: matches!(secret.disabled.as_deref(), Some("true") | Some("yes")),
+            router: None,
+        }
+    }
+}
+
+pub const PPP_SECRET_PROPLIST: &str = ".id,name,service,profile,remote-address,comment,disabled";
+
+// RouterOS attribute names and values shared by the REST and binary API clients
+pub fn new_secret_fields(secret: &NewPppSecret) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("name", secret.name.clone()),
+        ("password", secret.password.clone()),
+        ("disabled", if secret.disabled { "yes" } else { "no" }.to_string()),
+    ];
+    push_optional(&mut fields, "service", &secret.service);
+    push_optional(&mut fields, "profile", &secret.profile);
+    push_optional(&mut fields, "remote-address", &secret.remote_address);
+    push_optional(&mut fields, "comment", &secret.comment);
+    fields
+}
+
+pub fn secret_update_fields(update: &PppSecretUpdate) -> Vec<(&'static str, String)> {
+    let mut fields = Vec::new();
+    push_optional(&mut fields, "password", &update.password);
+    push_optional(&mut fields, "profile", &update.profile);
+    push_optional(&mut fields, "remote-address", &update.remote_address);
+    push_optional(&mut fields, "comment", &update.comment);
+    fields
+}
+
+fn push_optional(fields: &mut Vec<(&'static str, String)>, key: &'static str, value: &Option<String>) {
+    if let Some(value) = value {
+        fields.push((key, value.clone()));
     }
 }
 
@@ -63,9 +124,22 @@ pub enum MikrotikApiMethod {
     Get,
     Post,
     Put,
+    Patch,
     Delete,
 }
 
+impl MikrotikApiMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MikrotikApiMethod::Get => "GET",
+            MikrotikApiMethod::Post => "POST",
+            MikrotikApiMethod::Put => "PUT",
+            MikrotikApiMethod::Patch => "PATCH",
+            MikrotikApiMethod::Delete => "DELETE",
+        }
+    }
+}
+
 impl MikrotikApiRequest {
     pub fn new(path: MikrotikApiPath, method: MikrotikApiMethod) -> Self {
         Self {
@@ -81,7 +155,6 @@ impl MikrotikApiRequest {
         self
     }
 
-    #[allow(dead_code)]
     pub fn with_body(mut self, body: serde_json::Value) -> Self {
         self.body = Some(body);
         self
@@ -106,23 +179,51 @@ impl MikrotikApiRequest {
 impl MikrotikApiRequest {
     pub fn get_active_connections() -> Self {
         Self::new(MikrotikApiPath::PppActive, MikrotikApiMethod::Get)
-            .with_query_param(".proplist".to_string(), "name,service,caller-id,address,uptime,comment".to_string())
+            .with_query_param(".proplist".to_string(), ".id,name,service,caller-id,address,uptime,comment".to_string())
     }
 
-    pub fn disconnect_user(user_name: &str) -> Self {
-        // In MikroTik, disconnecting requires finding the connection ID first
-        // This is a simplified approach - in real implementation, you'd need to:
-        // 1. Get the connection by name
-        // 2. Use the .id to disconnect
-        Self::new(MikrotikApiPath::PppActive, MikrotikApiMethod::Delete)
-            .with_query_param("name".to_string(), user_name.to_string())
+    // RouterOS only removes active sessions by their internal .id, so callers
+    // resolve the id through get_user_details first
+    pub fn disconnect_session(session_id: &str) -> Self {
+        Self::new(MikrotikApiPath::PppActiveById(session_id.to_string()), MikrotikApiMethod::Delete)
     }
 
-    #[allow(dead_code)]
     pub fn get_user_details(user_name: &str) -> Self {
         Self::new(MikrotikApiPath::PppActive, MikrotikApiMethod::Get)
             .with_query_param("name".to_string(), user_name.to_string())
     }
+
+    pub fn get_secrets() -> Self {
+        Self::new(MikrotikApiPath::PppSecrets, MikrotikApiMethod::Get)
+            .with_query_param(".proplist".to_string(), PPP_SECRET_PROPLIST.to_string())
+    }
+
+    pub fn get_secret_by_name(name: &str) -> Self {
+        Self::get_secrets()
+            .with_query_param("name".to_string(), name.to_string())
+    }
+
+    pub fn create_secret(fields: Vec<(&'static str, String)>) -> Self {
+        Self::new(MikrotikApiPath::PppSecrets, MikrotikApiMethod::Put)
+            .with_body(fields_to_json(fields))
+    }
+
+    pub fn update_secret(secret_id: &str, fields: Vec<(&'static str, String)>) -> Self {
+        Self::new(MikrotikApiPath::PppSecretById(secret_id.to_string()), MikrotikApiMethod::Patch)
+            .with_body(fields_to_json(fields))
+    }
+
+    pub fn delete_secret(secret_id: &str) -> Self {
+        Self::new(MikrotikApiPath::PppSecretById(secret_id.to_string()), MikrotikApiMethod::Delete)
+    }
+}
+
+fn fields_to_json(fields: Vec<(&'static str, String)>) -> serde_json::Value {
+    serde_json::Value::Object(
+        fields.into_iter()
+            .map(|(key, value)| (key.to_string(), serde_json::Value::String(value)))
+            .collect()
+    )
 }
 
 #[allow(dead_code)]
@@ -145,6 +246,36 @@ pub enum MikrotikError {
 
     #[error("Connection timeout")]
     Timeout,
+
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("RouterOS API protocol error: {0}")]
+    ProtocolError(String),
+
+    #[error("Request rejected: {0}")]
+    Rejected(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+}
+
+impl MikrotikError {
+    // Short label for the failure, used in metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MikrotikError::HttpError(e) if e.is_timeout() => "timeout",
+            MikrotikError::HttpError(_) => "http",
+            MikrotikError::AuthenticationError => "auth",
+            MikrotikError::ApiError(_) => "api",
+            MikrotikError::SerializationError(_) => "serialization",
+            MikrotikError::UserNotFound(_) | MikrotikError::NotFound(_) => "not_found",
+            MikrotikError::Timeout => "timeout",
+            MikrotikError::IoError(_) => "io",
+            MikrotikError::ProtocolError(_) => "protocol",
+            MikrotikError::Rejected(_) => "rejected",
+        }
+    }
 }
 
 impl From<MikrotikError> for crate::domain::models::DomainError {
@@ -156,6 +287,10 @@ impl From<MikrotikError> for crate::domain::models::DomainError {
             MikrotikError::SerializationError(e) => crate::domain::models::DomainError::SerializationError(e.to_string()),
             MikrotikError::UserNotFound(name) => crate::domain::models::DomainError::UserNotFound(name),
             MikrotikError::Timeout => crate::domain::models::DomainError::NetworkError("Request timeout".to_string()),
+            MikrotikError::IoError(e) => crate::domain::models::DomainError::NetworkError(e.to_string()),
+            MikrotikError::ProtocolError(msg) => crate::domain::models::DomainError::NetworkError(msg),
+            MikrotikError::Rejected(msg) => crate::domain::models::DomainError::RouterRejected(msg),
+            MikrotikError::NotFound(msg) => crate::domain::models::DomainError::RouterItemNotFound(msg),
         }
     }
 }
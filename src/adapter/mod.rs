@@ -1,4 +1,8 @@
+pub mod cli;
+pub mod middleware;
 pub mod rest_api;
+pub mod session;
+pub mod tls;
 pub mod websocket;
 pub mod mikrotik;
 
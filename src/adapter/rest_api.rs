@@ -1,7 +1,7 @@
 use actix_files as fs;
-use actix_session::{Session, SessionMiddleware, storage::CookieSessionStore};
+use actix_session::Session;
 use actix_web::{
-    cookie::Key, 
+    http::StatusCode,
     web, 
     App, 
     Error, 
@@ -13,15 +13,31 @@ use actix_web::{
     middleware::Logger,
 };
 use actix_web_actors::ws;
-use actix::{Actor, Addr};
-use clap::Parser;
-use log::{info, debug, error};
+use actix::Addr;
+use chrono::{DateTime, Duration, Utc};
+use log::{info, debug, error, warn};
 use serde::Deserialize;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
 
-use crate::domain::traits::ConfigService;
-use crate::usecase::{VpnUserUseCase, AuthUseCase};
+use crate::domain::{
+    models::{
+        AlertState, AuditAction, AuditQuery, AuditRecord, AuthUser, DomainError, HealthStatus, LockoutScope, NewAlertSilence, NewApiToken,
+        NewLocalUser, NewPppSecret, PasswordChange, PasswordReset, PppSecretUpdate,
+    },
+    traits::{ConfigService, MetricsRecorder},
+};
+use crate::usecase::{
+    VpnUserUseCase, AuthUseCase, PppSecretUseCase, ApiTokenUseCase, TotpUseCase, AuditUseCase, LocalUserUseCase, HealthUseCase,
+    WebhookUseCase, AlertUseCase,
+};
+use crate::adapter::cli::Args;
+use crate::adapter::middleware::{request_user, session_user, ApiAuthentication, CsrfProtection, RequireRole};
+use crate::adapter::session::{
+    csrf_token, pending_login, record_pending_attempt, set_session_credential, start_pending_login, start_session, store_oidc_request,
+    take_oidc_request, SessionSettings, SessionTimeout, MAX_PENDING_ATTEMPTS,
+};
+use crate::adapter::tls::TlsSettings;
 use crate::adapter::websocket::{WebSocketActor, WebSocketManager};
 
 #[derive(Debug, Deserialize)]
@@ -30,87 +46,393 @@ struct LoginForm {
     password: String,
 }
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Listen address
-    #[arg(short, long, default_value = "127.0.0.1")]
-    address: IpAddr,
-    /// Listen port
-    #[arg(short, long, default_value_t = 3217)]
-    port: u16,
+#[derive(Debug, Deserialize)]
+struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TotpCodeForm {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionsQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LatencyQuery {
+    router: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    step: Option<u64>,
+}
+
+// Upper bound on points returned by a single latency query
+const MAX_LATENCY_POINTS: i64 = 10_000;
+
+#[derive(Debug, Deserialize)]
+struct RouterQuery {
+    router: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AlertQuery {
+    state: Option<AlertState>,
 }
 
 struct AppState {
     vpn_user_use_case: Arc<VpnUserUseCase>,
     auth_use_case: Arc<AuthUseCase>,
+    ppp_secret_use_case: Arc<PppSecretUseCase>,
+    api_token_use_case: Arc<ApiTokenUseCase>,
+    totp_use_case: Arc<TotpUseCase>,
+    audit_use_case: Arc<AuditUseCase>,
+    local_user_use_case: Arc<LocalUserUseCase>,
+    health_use_case: Arc<HealthUseCase>,
+    webhook_use_case: Arc<WebhookUseCase>,
+    alert_use_case: Arc<AlertUseCase>,
     websocket_manager: Addr<WebSocketManager>,
+    metrics: Arc<dyn MetricsRecorder + Send + Sync>,
 }
 
-// Route handlers
-async fn index(session: Session, _data: web::Data<AppState>) -> impl Responder {
-    match session.get::<String>("username") {
-        Ok(Some(_)) => {
-            // Serve the index.html file
-            match std::fs::read_to_string("./asset/index.html") {
-                Ok(content) => HttpResponse::Ok()
-                    .content_type("text/html; charset=utf-8")
-                    .body(content),
-                Err(_) => HttpResponse::InternalServerError()
-                    .body("Failed to load index.html"),
-            }
-        }
-        _ => HttpResponse::SeeOther()
-            .append_header(("Location", "/login"))
-            .finish(),
-    }
+fn error_response(e: &DomainError) -> HttpResponse {
+    let status = match e {
+        DomainError::InvalidInput(_) | DomainError::InvalidIpAddress(_) => StatusCode::BAD_REQUEST,
+        DomainError::AuthenticationFailed => StatusCode::UNAUTHORIZED,
+        DomainError::UserNotFound(_)
+        | DomainError::SecretNotFound(_)
+        | DomainError::TokenNotFound(_)
+        | DomainError::SilenceNotFound(_)
+        | DomainError::RouterItemNotFound(_) => StatusCode::NOT_FOUND,
+        DomainError::RouterRejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        DomainError::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+        DomainError::AlreadyExists(_) => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    
+    HttpResponse::build(status).json(serde_json::json!({
+        "success": false,
+        "message": e.to_string()
+    }))
 }
 
-async fn login_page() -> impl Responder {
-    match std::fs::read_to_string("./asset/login.html") {
+// Serves a page with the session's CSRF token in place of `{{csrf_token}}`
+fn html_page(session: &Session, file: &str) -> HttpResponse {
+    let token = match csrf_token(session) {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Failed to store CSRF token in session: {}", e);
+            return HttpResponse::InternalServerError().body("Session error");
+        }
+    };
+
+    match std::fs::read_to_string(format!("./asset/{}", file)) {
         Ok(content) => HttpResponse::Ok()
             .content_type("text/html; charset=utf-8")
-            .body(content),
+            .body(content.replace("{{csrf_token}}", &token)),
         Err(_) => HttpResponse::InternalServerError()
-            .body("Failed to load login.html"),
+            .body(format!("Failed to load {}", file)),
+    }
+}
+
+// Route handlers
+async fn index(session: Session) -> impl Responder {
+    html_page(&session, "index.html")
+}
+
+async fn login_page(session: Session) -> impl Responder {
+    html_page(&session, "login.html")
+}
+
+async fn totp_page(session: Session) -> HttpResponse {
+    html_page(&session, "totp.html")
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .append_header(("Location", location))
+        .finish()
+}
+
+// Peer address of the connection. Forwarded headers are ignored on purpose,
+// they would let clients pick the IP their failed logins are counted against
+fn client_ip(req: &HttpRequest) -> IpAddr {
+    req.peer_addr()
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+// Audit record of an action taken by the caller of `req`
+fn audit_record(req: &HttpRequest, action: AuditAction) -> AuditRecord {
+    let actor = request_user(req).map(|user| user.username).unwrap_or_else(|| "-".to_string());
+    AuditRecord::new(actor, Some(client_ip(req)), action)
+}
+
+// Logins are recorded under the username that was tried, with the login step
+fn login_record(username: &str, client_ip: IpAddr, action: AuditAction, step: &str) -> AuditRecord {
+    AuditRecord::new(username, Some(client_ip), action).detail(step)
+}
+
+fn throttled(seconds: u64) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .append_header(("Location", format!("/login?error=locked&retry={}", seconds)))
+        .append_header(("Retry-After", seconds.to_string()))
+        .finish()
+}
+
+// `method` is the last login step the user passed, recorded in the audit log
+async fn begin_session(session: &Session, auth_user: &AuthUser, client_ip: IpAddr, method: &str, data: &AppState) -> HttpResponse {
+    // Users from LDAP or single sign-on usually have no local account
+    let credential = match data.local_user_use_case.credential_stamp(&auth_user.username).await {
+        Ok(credential) => credential,
+        Err(e) => {
+            error!("Failed to read the account of {}: {}", auth_user.username, e);
+            return HttpResponse::InternalServerError().body("Authentication error");
+        }
+    };
+    if let Err(e) = start_session(session, auth_user, credential.as_deref()) {
+        error!("Failed to create session: {}", e);
+        return HttpResponse::InternalServerError().body("Session error");
+    }
+    if let Err(e) = data.auth_use_case.login_completed(&auth_user.username).await {
+        error!("Failed to reset failed logins of {}: {}", auth_user.username, e);
+    }
+    data.metrics.login(method, true);
+    data.audit_use_case.record(
+        login_record(&auth_user.username, client_ip, AuditAction::Login, &format!("{} as {}", method, auth_user.role))
+    ).await;
+    
+    debug!("User {} logged in successfully as {}", auth_user.username, auth_user.role);
+    redirect("/")
+}
+
+// Logs the user in right away, or starts the TOTP step for users who have
+// it enabled or whose role requires it
+async fn complete_login(session: &Session, auth_user: AuthUser, client_ip: IpAddr, method: &str, data: &AppState) -> HttpResponse {
+    let second_factor = match data.totp_use_case.is_enabled(&auth_user.username).await {
+        Ok(enabled) => enabled || data.totp_use_case.is_required(auth_user.role),
+        Err(e) => {
+            error!("Failed to read TOTP state of {}: {}", auth_user.username, e);
+            return HttpResponse::InternalServerError().body("Authentication error");
+        }
+    };
+    
+    if !second_factor {
+        return begin_session(session, &auth_user, client_ip, method, data).await;
+    }
+    
+    if let Err(e) = start_pending_login(session, &auth_user) {
+        error!("Failed to create session: {}", e);
+        return HttpResponse::InternalServerError().body("Session error");
     }
+    debug!("User {} passed the first login step, waiting for TOTP", auth_user.username);
+    redirect("/login/totp")
 }
 
 async fn login(
+    req: HttpRequest,
     form: web::Form<LoginForm>,
     session: Session,
     data: web::Data<AppState>,
 ) -> impl Responder {
     let LoginForm { username, password } = form.into_inner();
+    let client_ip = client_ip(&req);
     
-    match data.auth_use_case.authenticate(&username, &password).await {
-        Ok(auth_user) if auth_user.is_authenticated => {
-            if let Err(e) = session.insert("username", &username) {
-                error!("Failed to create session: {}", e);
+    match data.auth_use_case.authenticate(&username, &password, client_ip).await {
+        Ok(auth_user) if auth_user.is_authenticated => complete_login(&session, auth_user, client_ip, "password", &data).await,
+        Ok(_) | Err(DomainError::AuthenticationFailed) => {
+            debug!("Authentication failed for user: {}", username);
+            data.metrics.login("password", false);
+            data.audit_use_case.record(
+                login_record(&username, client_ip, AuditAction::LoginFailed, "password").failed()
+            ).await;
+            redirect("/login?error=1")
+        }
+        Err(DomainError::TooManyAttempts(seconds)) => {
+            data.metrics.login("password", false);
+            data.audit_use_case.record(
+                login_record(&username, client_ip, AuditAction::LoginFailed, "password")
+                    .failed()
+                    .detail(format!("locked for {} seconds", seconds))
+            ).await;
+            throttled(seconds)
+        }
+        Err(e) => {
+            error!("Login error: {}", e);
+            HttpResponse::InternalServerError().body("Authentication error")
+        }
+    }
+}
+
+// Tells the login page which alternatives to the password form exist
+async fn login_options(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "oidc": data.auth_use_case.oidc_display_name()
+    }))
+}
+
+async fn oidc_login(session: Session, data: web::Data<AppState>) -> impl Responder {
+    if data.auth_use_case.oidc_display_name().is_none() {
+        return HttpResponse::NotFound().body("Single sign-on is not configured");
+    }
+    
+    match data.auth_use_case.start_oidc_login().await {
+        Ok((url, request)) => {
+            if let Err(e) = store_oidc_request(&session, &request) {
+                error!("Failed to store OIDC request in session: {}", e);
                 return HttpResponse::InternalServerError().body("Session error");
             }
-            
-            debug!("User {} logged in successfully", username);
-            HttpResponse::SeeOther()
-                .append_header(("Location", "/"))
-                .finish()
+            redirect(&url)
         }
-        Ok(_) => {
-            debug!("Authentication failed for user: {}", username);
-            HttpResponse::SeeOther()
-                .append_header(("Location", "/login?error=1"))
-                .finish()
+        Err(e) => {
+            error!("Failed to start OIDC login: {}", e);
+            redirect("/login?error=sso")
+        }
+    }
+}
+
+async fn oidc_callback(
+    req: HttpRequest,
+    query: web::Query<OidcCallbackQuery>,
+    session: Session,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let Some(request) = take_oidc_request(&session) else {
+        debug!("OIDC callback without a pending request");
+        return redirect("/login?error=sso");
+    };
+    
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => {
+            info!("OIDC login was not completed: {}", query.error.as_deref().unwrap_or("missing code"));
+            return redirect("/login?error=sso");
         }
+    };
+    
+    let client_ip = client_ip(&req);
+    match data.auth_use_case.finish_oidc_login(code, state, &request).await {
+        Ok(auth_user) => complete_login(&session, auth_user, client_ip, "oidc", &data).await,
         Err(e) => {
-            error!("Login error: {}", e);
+            if !matches!(e, DomainError::AuthenticationFailed) {
+                error!("OIDC login error: {}", e);
+            }
+            // The username is only known from a valid ID token
+            data.metrics.login("oidc", false);
+            data.audit_use_case.record(
+                login_record("-", client_ip, AuditAction::LoginFailed, "oidc").failed().detail(e.to_string())
+            ).await;
+            redirect("/login?error=sso")
+        }
+    }
+}
+
+async fn login_totp_page(session: Session) -> impl Responder {
+    match pending_login(&session) {
+        Some(_) => totp_page(session).await,
+        None => redirect("/login"),
+    }
+}
+
+// Tells the second login step whether the user still has to enroll
+async fn login_totp_state(session: Session, data: web::Data<AppState>) -> impl Responder {
+    let Some(pending) = pending_login(&session) else {
+        return error_response(&DomainError::AuthenticationFailed);
+    };
+    
+    match data.totp_use_case.is_enabled(&pending.username).await {
+        Ok(enabled) => HttpResponse::Ok().json(serde_json::json!({
+            "username": pending.username,
+            "enrolled": enabled
+        })),
+        Err(e) => {
+            error!("Failed to read TOTP state of {}: {}", pending.username, e);
+            error_response(&e)
+        }
+    }
+}
+
+// Forced enrollment for a role that requires TOTP but has none set up yet
+async fn login_totp_enrollment(session: Session, data: web::Data<AppState>) -> impl Responder {
+    let Some(pending) = pending_login(&session) else {
+        return error_response(&DomainError::AuthenticationFailed);
+    };
+    
+    match data.totp_use_case.start_enrollment(&pending.username).await {
+        Ok(enrollment) => HttpResponse::Ok().json(enrollment),
+        Err(e) => {
+            error!("Failed to start TOTP enrollment of {}: {}", pending.username, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn login_totp(
+    req: HttpRequest,
+    form: web::Form<TotpCodeForm>,
+    session: Session,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let Some(pending) = pending_login(&session) else {
+        return redirect("/login");
+    };
+    
+    // Wrong codes count like wrong passwords
+    let client_ip = client_ip(&req);
+    if let Err(DomainError::TooManyAttempts(seconds)) = data.auth_use_case.check_throttle(client_ip, &pending.username).await {
+        session.purge();
+        data.metrics.login("totp", false);
+        data.audit_use_case.record(
+            login_record(&pending.username, client_ip, AuditAction::LoginFailed, "totp")
+                .failed()
+                .detail(format!("locked for {} seconds", seconds))
+        ).await;
+        return throttled(seconds);
+    }
+    
+    let verified = match data.totp_use_case.is_enabled(&pending.username).await {
+        Ok(true) => data.totp_use_case.verify(&pending.username, &form.code).await,
+        Ok(false) => match data.totp_use_case.confirm_enrollment(&pending.username, &form.code).await {
+            Err(DomainError::InvalidInput(_)) => Ok(false),
+            other => other.map(|_| true),
+        },
+        Err(e) => Err(e),
+    };
+    
+    match verified {
+        Ok(true) => begin_session(&session, &pending, client_ip, "totp", &data).await,
+        Ok(false) => {
+            debug!("Invalid TOTP code for user: {}", pending.username);
+            data.metrics.login("totp", false);
+            data.audit_use_case.record(
+                login_record(&pending.username, client_ip, AuditAction::LoginFailed, "totp").failed()
+            ).await;
+            if let Err(e) = data.auth_use_case.record_failure(client_ip, &pending.username).await {
+                error!("Failed to record failed login of {}: {}", pending.username, e);
+            }
+            match record_pending_attempt(&session) {
+                Ok(attempts) if attempts < MAX_PENDING_ATTEMPTS => redirect("/login/totp?error=1"),
+                _ => {
+                    session.purge();
+                    redirect("/login?error=1")
+                }
+            }
+        }
+        Err(e) => {
+            error!("TOTP verification error: {}", e);
             HttpResponse::InternalServerError().body("Authentication error")
         }
     }
 }
 
-async fn logout(session: Session) -> impl Responder {
+async fn logout(req: HttpRequest, session: Session, data: web::Data<AppState>) -> impl Responder {
     if let Ok(Some(username)) = session.get::<String>("username") {
         debug!("User {} logged out", username);
+        data.audit_use_case.record(AuditRecord::new(username, Some(client_ip(&req)), AuditAction::Logout)).await;
     }
     
     session.purge();
@@ -122,20 +444,27 @@ async fn logout(session: Session) -> impl Responder {
 async fn websocket_handler(
     req: HttpRequest,
     stream: web::Payload,
-    session: Session,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
-    match session.get::<String>("username")? {
-        Some(_) => {
-            let websocket_actor = WebSocketActor::new(data.websocket_manager.clone());
-            ws::start(websocket_actor, &req, stream)
-        }
-        None => Ok(HttpResponse::Unauthorized().body("Unauthorized")),
+    let websocket_actor = WebSocketActor::new(data.websocket_manager.clone());
+    ws::start(websocket_actor, &req, stream)
+}
+
+async fn current_user(req: HttpRequest) -> impl Responder {
+    match request_user(&req) {
+        Some(user) => HttpResponse::Ok().json(user),
+        None => HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Authentication required"
+        })),
     }
 }
 
-async fn trigger_update(data: web::Data<AppState>) -> impl Responder {
-    match data.vpn_user_use_case.fetch_and_update_users().await {
+async fn trigger_update(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let result = data.vpn_user_use_case.fetch_and_update_users().await;
+    data.audit_use_case.record(audit_record(&req, AuditAction::UpdateTriggered).result(&result)).await;
+    
+    match result {
         Ok(users) => {
             info!("Manual update triggered, fetched {} users", users.len());
             HttpResponse::Ok().json(serde_json::json!({
@@ -165,13 +494,94 @@ async fn get_users(data: web::Data<AppState>) -> impl Responder {
     }
 }
 
+// Liveness: answers as long as the server handles requests
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": HealthStatus::Ok }))
+}
+
+// Readiness with the state of each component, 503 while any is down
+async fn readyz(data: web::Data<AppState>) -> impl Responder {
+    let report = data.health_use_case.readiness().await;
+    let status = match report.status {
+        HealthStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+        HealthStatus::Ok | HealthStatus::Degraded => StatusCode::OK,
+    };
+    HttpResponse::build(status).json(report)
+}
+
+// Prometheus text exposition of the current sessions and the counters
+// recorded since startup
+async fn metrics(data: web::Data<AppState>) -> impl Responder {
+    let rendered = data.vpn_user_use_case.get_all_users()
+        .await
+        .and_then(|users| data.metrics.render(&users));
+    
+    match rendered {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4; charset=utf-8")
+            .body(body),
+        Err(e) => {
+            error!("Failed to render metrics: {}", e);
+            HttpResponse::InternalServerError().body("Failed to render metrics")
+        }
+    }
+}
+
+async fn get_user_sessions(
+    path: web::Path<String>,
+    query: web::Query<SessionsQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = path.into_inner();
+    let limit = query.limit.unwrap_or(20).clamp(1, 500);
+    
+    match data.vpn_user_use_case.get_user_sessions(&username, limit).await {
+        Ok(sessions) => HttpResponse::Ok().json(sessions),
+        Err(e) => {
+            error!("Failed to get sessions of user {}: {}", username, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn get_user_latency(
+    path: web::Path<String>,
+    query: web::Query<LatencyQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = path.into_inner();
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - Duration::hours(1));
+    let step = query.step.unwrap_or(60).max(1);
+    
+    if (to - from).num_seconds() / step as i64 > MAX_LATENCY_POINTS {
+        return error_response(&DomainError::InvalidInput(format!(
+            "range too large for step {}s, at most {} points may be requested", step, MAX_LATENCY_POINTS
+        )));
+    }
+    
+    match data.vpn_user_use_case.get_latency_series(query.router.as_deref(), &username, from, to, step).await {
+        Ok(points) => HttpResponse::Ok().json(points),
+        Err(e) => {
+            error!("Failed to get latency of user {}: {}", username, e);
+            error_response(&e)
+        }
+    }
+}
+
 async fn disconnect_user(
+    req: HttpRequest,
     path: web::Path<String>,
+    query: web::Query<RouterQuery>,
     data: web::Data<AppState>,
 ) -> impl Responder {
     let username = path.into_inner();
+    let record = router_record(&req, AuditAction::UserDisconnected, &username, &query);
+    
+    let result = data.vpn_user_use_case.disconnect_user(query.router.as_deref(), &username).await;
+    data.audit_use_case.record(record.result(&result)).await;
     
-    match data.vpn_user_use_case.disconnect_user(&username).await {
+    match result {
         Ok(()) => {
             info!("User {} disconnected", username);
             HttpResponse::Ok().json(serde_json::json!({
@@ -179,6 +589,13 @@ async fn disconnect_user(
                 "message": format!("User {} disconnected", username)
             }))
         }
+        Err(DomainError::UserNotFound(_)) => {
+            HttpResponse::NotFound().json(serde_json::json!({
+                "success": false,
+                "message": format!("User {} has no active session", username)
+            }))
+        }
+        Err(e @ DomainError::InvalidInput(_)) => error_response(&e),
         Err(e) => {
             error!("Failed to disconnect user {}: {}", username, e);
             HttpResponse::InternalServerError().json(serde_json::json!({
@@ -189,53 +606,842 @@ async fn disconnect_user(
     }
 }
 
-fn configure_routes(cfg: &mut web::ServiceConfig) {
+fn router_record(req: &HttpRequest, action: AuditAction, name: &str, query: &RouterQuery) -> AuditRecord {
+    let record = audit_record(req, action).target(name);
+    match &query.router {
+        Some(router) => record.detail(format!("router {}", router)),
+        None => record,
+    }
+}
+
+async fn list_secrets(
+    query: web::Query<RouterQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.ppp_secret_use_case.list_secrets(query.router.as_deref()).await {
+        Ok(secrets) => HttpResponse::Ok().json(secrets),
+        Err(e) => {
+            error!("Failed to list PPP secrets: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn create_secret(
+    req: HttpRequest,
+    query: web::Query<RouterQuery>,
+    body: web::Json<NewPppSecret>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let secret = body.into_inner();
+    let record = router_record(&req, AuditAction::SecretCreated, &secret.name, &query);
+    
+    let result = data.ppp_secret_use_case.create_secret(query.router.as_deref(), secret).await;
+    data.audit_use_case.record(record.result(&result)).await;
+    
+    match result {
+        Ok(secret) => HttpResponse::Created().json(secret),
+        Err(e) => {
+            error!("Failed to create PPP secret: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn update_secret(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<RouterQuery>,
+    body: web::Json<PppSecretUpdate>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let name = path.into_inner();
+    let update = body.into_inner();
+    
+    // Only which fields changed is recorded, never the new password
+    let changed: Vec<&str> = [
+        ("password", update.password.is_some()),
+        ("profile", update.profile.is_some()),
+        ("remote_address", update.remote_address.is_some()),
+        ("comment", update.comment.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(field, set)| set.then_some(field))
+    .collect();
+    let record = router_record(&req, AuditAction::SecretUpdated, &name, &query)
+        .detail(format!("changed {}", changed.join(", ")));
+    
+    let result = data.ppp_secret_use_case.update_secret(query.router.as_deref(), &name, update).await;
+    data.audit_use_case.record(record.result(&result)).await;
+    
+    match result {
+        Ok(secret) => HttpResponse::Ok().json(secret),
+        Err(e) => {
+            error!("Failed to update PPP secret {}: {}", name, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn enable_secret(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<RouterQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    set_secret_disabled(req, path.into_inner(), query.into_inner(), data, false).await
+}
+
+async fn disable_secret(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<RouterQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    set_secret_disabled(req, path.into_inner(), query.into_inner(), data, true).await
+}
+
+async fn set_secret_disabled(
+    req: HttpRequest,
+    name: String,
+    query: RouterQuery,
+    data: web::Data<AppState>,
+    disabled: bool,
+) -> HttpResponse {
+    let action = if disabled { AuditAction::SecretDisabled } else { AuditAction::SecretEnabled };
+    let record = router_record(&req, action, &name, &query);
+    
+    let result = data.ppp_secret_use_case.set_secret_disabled(query.router.as_deref(), &name, disabled).await;
+    data.audit_use_case.record(record.result(&result)).await;
+    
+    match result {
+        Ok(secret) => HttpResponse::Ok().json(secret),
+        Err(e) => {
+            error!("Failed to change PPP secret {}: {}", name, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn delete_secret(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<RouterQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let name = path.into_inner();
+    let record = router_record(&req, AuditAction::SecretDeleted, &name, &query);
+    
+    let result = data.ppp_secret_use_case.delete_secret(query.router.as_deref(), &name).await;
+    data.audit_use_case.record(record.result(&result)).await;
+    
+    match result {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": format!("PPP secret {} deleted", name)
+        })),
+        Err(e) => {
+            error!("Failed to delete PPP secret {}: {}", name, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn list_tokens(data: web::Data<AppState>) -> impl Responder {
+    match data.api_token_use_case.list_tokens().await {
+        Ok(tokens) => HttpResponse::Ok().json(tokens),
+        Err(e) => {
+            error!("Failed to list API tokens: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn issue_token(
+    req: HttpRequest,
+    body: web::Json<NewApiToken>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let issuer = request_user(&req).map(|user| user.username).unwrap_or_default();
+    let request = body.into_inner();
+    let record = audit_record(&req, AuditAction::TokenIssued)
+        .target(request.name.trim())
+        .detail(format!("scope {}", request.scope));
+    
+    let result = data.api_token_use_case.issue_token(request, &issuer).await;
+    data.audit_use_case.record(record.result(&result)).await;
+    
+    match result {
+        Ok((token, secret)) => HttpResponse::Created().json(serde_json::json!({
+            "token": secret,
+            "details": token
+        })),
+        Err(e) => {
+            error!("Failed to issue API token: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn revoke_token(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    
+    let result = data.api_token_use_case.revoke_token(&id).await;
+    data.audit_use_case.record(audit_record(&req, AuditAction::TokenRevoked).target(&id).result(&result)).await;
+    
+    match result {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": format!("API token {} revoked", id)
+        })),
+        Err(e) => {
+            error!("Failed to revoke API token {}: {}", id, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn totp_status(session: Session, data: web::Data<AppState>) -> impl Responder {
+    let Some(user) = session_user(&session) else {
+        return error_response(&DomainError::AuthenticationFailed);
+    };
+    
+    match data.totp_use_case.is_enabled(&user.username).await {
+        Ok(enabled) => HttpResponse::Ok().json(serde_json::json!({
+            "enabled": enabled,
+            "required": data.totp_use_case.is_required(user.role)
+        })),
+        Err(e) => {
+            error!("Failed to read TOTP state of {}: {}", user.username, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn start_totp_enrollment(session: Session, data: web::Data<AppState>) -> impl Responder {
+    let Some(user) = session_user(&session) else {
+        return error_response(&DomainError::AuthenticationFailed);
+    };
+    
+    match data.totp_use_case.start_enrollment(&user.username).await {
+        Ok(enrollment) => HttpResponse::Ok().json(enrollment),
+        Err(e) => {
+            error!("Failed to start TOTP enrollment of {}: {}", user.username, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn confirm_totp_enrollment(
+    req: HttpRequest,
+    session: Session,
+    body: web::Json<TotpCodeForm>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let Some(user) = session_user(&session) else {
+        return error_response(&DomainError::AuthenticationFailed);
+    };
+    
+    let result = data.totp_use_case.confirm_enrollment(&user.username, &body.code).await;
+    data.audit_use_case.record(
+        audit_record(&req, AuditAction::TotpEnabled).target(&user.username).result(&result)
+    ).await;
+    
+    match result {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "TOTP enabled"
+        })),
+        Err(e) => {
+            debug!("TOTP confirmation of {} failed: {}", user.username, e);
+            error_response(&e)
+        }
+    }
+}
+
+// Turning TOTP off needs a valid code, so a hijacked session cannot do it
+async fn disable_own_totp(
+    req: HttpRequest,
+    session: Session,
+    body: web::Json<TotpCodeForm>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let Some(user) = session_user(&session) else {
+        return error_response(&DomainError::AuthenticationFailed);
+    };
+    
+    if data.totp_use_case.is_required(user.role) {
+        return error_response(&DomainError::InvalidInput(format!("TOTP is required for role {}", user.role)));
+    }
+    
+    let result = match data.totp_use_case.verify(&user.username, &body.code).await {
+        Ok(true) => data.totp_use_case.disable(&user.username).await,
+        Ok(false) => Err(DomainError::InvalidInput("Invalid TOTP code".to_string())),
+        Err(e) => Err(e),
+    };
+    data.audit_use_case.record(
+        audit_record(&req, AuditAction::TotpDisabled).target(&user.username).result(&result)
+    ).await;
+    
+    match result {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "TOTP disabled"
+        })),
+        Err(e) => {
+            debug!("Disabling TOTP of {} failed: {}", user.username, e);
+            error_response(&e)
+        }
+    }
+}
+
+// Lets an admin reset the second factor of a user who lost their device
+async fn reset_totp(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = path.into_inner();
+    
+    let result = data.totp_use_case.disable(&username).await;
+    let record = audit_record(&req, AuditAction::TotpReset).target(&username);
+    let record = match &result {
+        Ok(false) => record.failed().detail("not enrolled"),
+        _ => record.result(&result),
+    };
+    data.audit_use_case.record(record).await;
+    
+    match result {
+        Ok(true) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": format!("TOTP of {} reset", username)
+        })),
+        Ok(false) => error_response(&DomainError::UserNotFound(username)),
+        Err(e) => {
+            error!("Failed to reset TOTP of {}: {}", username, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn list_accounts(data: web::Data<AppState>) -> impl Responder {
+    match data.local_user_use_case.list_users().await {
+        Ok(users) => HttpResponse::Ok().json(users),
+        Err(e) => {
+            error!("Failed to list login users: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn create_account(
+    req: HttpRequest,
+    body: web::Json<NewLocalUser>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let user = body.into_inner();
+    let record = audit_record(&req, AuditAction::AccountCreated).target(&user.username);
+    let record = match user.role {
+        Some(role) => record.detail(format!("role {}", role)),
+        None => record,
+    };
+    
+    let result = data.local_user_use_case.create_user(user).await;
+    data.audit_use_case.record(record.result(&result)).await;
+    
+    match result {
+        Ok(user) => HttpResponse::Created().json(user),
+        Err(e) => {
+            error!("Failed to create login user: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn delete_account(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = path.into_inner();
+    
+    // Keeps admins from locking themselves out
+    let result = match request_user(&req) {
+        Some(user) if user.username == username => {
+            Err(DomainError::InvalidInput("you cannot delete your own account".to_string()))
+        }
+        _ => data.local_user_use_case.delete_user(&username).await,
+    };
+    data.audit_use_case.record(audit_record(&req, AuditAction::AccountDeleted).target(&username).result(&result)).await;
+    
+    match result {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": format!("User {} deleted", username)
+        })),
+        Err(e) => {
+            error!("Failed to delete login user {}: {}", username, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn reset_account_password(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<PasswordReset>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let username = path.into_inner();
+    
+    let result = data.local_user_use_case.reset_password(&username, &body.password).await;
+    data.audit_use_case.record(audit_record(&req, AuditAction::PasswordReset).target(&username).result(&result)).await;
+    
+    match result {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": format!("Password of {} changed", username)
+        })),
+        Err(e) => {
+            error!("Failed to reset password of {}: {}", username, e);
+            error_response(&e)
+        }
+    }
+}
+
+// A wrong current password counts as a failed login, so a hijacked session
+// cannot be used to guess it
+async fn change_own_password(
+    req: HttpRequest,
+    session: Session,
+    body: web::Json<PasswordChange>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let Some(user) = session_user(&session) else {
+        return error_response(&DomainError::AuthenticationFailed);
+    };
+    
+    let client_ip = client_ip(&req);
+    if let Err(e) = data.auth_use_case.check_throttle(client_ip, &user.username).await {
+        return error_response(&e);
+    }
+    
+    let PasswordChange { current_password, new_password } = body.into_inner();
+    let result = match data.local_user_use_case.change_password(&user.username, &current_password, &new_password).await {
+        Err(DomainError::AuthenticationFailed) => {
+            if let Err(e) = data.auth_use_case.record_failure(client_ip, &user.username).await {
+                error!("Failed to record failed login of {}: {}", user.username, e);
+            }
+            Err(DomainError::InvalidInput("current password is wrong".to_string()))
+        }
+        other => other,
+    };
+    data.audit_use_case.record(audit_record(&req, AuditAction::PasswordChanged).target(&user.username).result(&result)).await;
+    
+    // Other sessions of the user end with the old password, this one stays
+    let result = match result {
+        Ok(()) => data.local_user_use_case.credential_stamp(&user.username).await
+            .and_then(|credential| set_session_credential(&session, credential.as_deref())
+                .map_err(|e| DomainError::StorageError(format!("Session error: {}", e)))),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Password changed"
+        })),
+        Err(e) => {
+            debug!("Password change of {} failed: {}", user.username, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn list_lockouts(data: web::Data<AppState>) -> impl Responder {
+    match data.auth_use_case.list_lockouts().await {
+        Ok(lockouts) => HttpResponse::Ok().json(lockouts),
+        Err(e) => {
+            error!("Failed to list login lockouts: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn clear_lockout(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let (scope, subject) = path.into_inner();
+    let scope = match scope.parse::<LockoutScope>() {
+        Ok(scope) => scope,
+        Err(e) => return error_response(&e),
+    };
+    
+    let result = data.auth_use_case.clear_lockout(scope, &subject).await;
+    let record = audit_record(&req, AuditAction::LockoutCleared).target(format!("{} {}", scope, subject));
+    let record = match &result {
+        Ok(false) => record.failed().detail("no failed logins recorded"),
+        _ => record.result(&result),
+    };
+    data.audit_use_case.record(record).await;
+    
+    match result {
+        Ok(true) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": format!("Failed logins of {} {} cleared", scope, subject)
+        })),
+        Ok(false) => HttpResponse::NotFound().json(serde_json::json!({
+            "success": false,
+            "message": format!("No failed logins recorded for {} {}", scope, subject)
+        })),
+        Err(e) => {
+            error!("Failed to clear lockout of {} {}: {}", scope, subject, e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn list_audit_entries(
+    query: web::Query<AuditQuery>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match data.audit_use_case.query(&query).await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            error!("Failed to query audit log: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+// The complete hash-chained file, as it is archived
+async fn export_audit_log(data: web::Data<AppState>) -> impl Responder {
+    match data.audit_use_case.export().await {
+        Ok(content) => HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .append_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"audit-{}.jsonl\"", Utc::now().format("%Y%m%dT%H%M%SZ")),
+            ))
+            .body(content),
+        Err(e) => {
+            error!("Failed to export audit log: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn verify_audit_log(data: web::Data<AppState>) -> impl Responder {
+    match data.audit_use_case.verify().await {
+        Ok(verification) => HttpResponse::Ok().json(verification),
+        Err(e) => {
+            error!("Failed to verify audit log: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn list_dead_letters(data: web::Data<AppState>) -> impl Responder {
+    match data.webhook_use_case.list_dead_letters().await {
+        Ok(letters) => HttpResponse::Ok().json(letters),
+        Err(e) => {
+            error!("Failed to list webhook dead letters: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn redeliver_dead_letters(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let result = data.webhook_use_case.redeliver_dead_letters().await;
+    let record = audit_record(&req, AuditAction::DeadLettersRedelivered).result(&result);
+    let record = match &result {
+        Ok(redelivery) => record.detail(format!("{} delivered, {} failed", redelivery.delivered, redelivery.failed)),
+        Err(_) => record,
+    };
+    data.audit_use_case.record(record).await;
+    
+    match result {
+        Ok(redelivery) => HttpResponse::Ok().json(redelivery),
+        Err(e) => {
+            error!("Failed to redeliver webhook dead letters: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn clear_dead_letters(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let result = data.webhook_use_case.clear_dead_letters().await;
+    let record = audit_record(&req, AuditAction::DeadLettersCleared).result(&result);
+    let record = match &result {
+        Ok(cleared) => record.detail(format!("{} entries", cleared)),
+        Err(_) => record,
+    };
+    data.audit_use_case.record(record).await;
+    
+    match result {
+        Ok(cleared) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": format!("{} dead letters cleared", cleared)
+        })),
+        Err(e) => {
+            error!("Failed to clear webhook dead letters: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn list_alerts(query: web::Query<AlertQuery>, data: web::Data<AppState>) -> impl Responder {
+    match data.alert_use_case.list_alerts(query.state).await {
+        Ok(alerts) => HttpResponse::Ok().json(alerts),
+        Err(e) => {
+            error!("Failed to list alerts: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn list_alert_rules(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.alert_use_case.list_rules())
+}
+
+async fn list_alert_silences(data: web::Data<AppState>) -> impl Responder {
+    match data.alert_use_case.list_silences().await {
+        Ok(silences) => HttpResponse::Ok().json(silences),
+        Err(e) => {
+            error!("Failed to list alert silences: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn create_alert_silence(
+    req: HttpRequest,
+    body: web::Json<NewAlertSilence>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let creator = request_user(&req).map(|user| user.username).unwrap_or_default();
+    let request = body.into_inner();
+    let record = audit_record(&req, AuditAction::AlertSilenced)
+        .target(format!(
+            "rule {}, user {}",
+            request.rule.as_deref().unwrap_or("*"),
+            request.user.as_deref().unwrap_or("*")
+        ))
+        .detail(format!("{} minutes", request.duration_minutes))
+        .detail(request.comment.trim());
+    
+    let result = data.alert_use_case.create_silence(request, &creator).await;
+    data.audit_use_case.record(record.result(&result)).await;
+    
+    match result {
+        Ok(silence) => HttpResponse::Created().json(silence),
+        Err(e) => {
+            error!("Failed to create alert silence: {}", e);
+            error_response(&e)
+        }
+    }
+}
+
+async fn remove_alert_silence(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    
+    let result = data.alert_use_case.remove_silence(&id).await;
+    data.audit_use_case.record(audit_record(&req, AuditAction::AlertSilenceRemoved).target(&id).result(&result)).await;
+    
+    match result {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": format!("Alert silence {} removed", id)
+        })),
+        Err(e) => {
+            error!("Failed to remove alert silence {}: {}", id, e);
+            error_response(&e)
+        }
+    }
+}
+
+// Same host and path on the HTTPS port. 308 keeps the method and body of
+// API calls made to the old address
+async fn redirect_to_https(req: HttpRequest, tls_port: u16) -> HttpResponse {
+    let connection_info = req.connection_info();
+    let authority = connection_info.host();
+    // Host without its port, IPv6 literals keep their brackets
+    let host = match authority.find(']') {
+        Some(end) => &authority[..=end],
+        None => authority.split(':').next().unwrap_or(authority),
+    };
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    let location = match tls_port {
+        443 => format!("https://{}{}", host, path),
+        port => format!("https://{}:{}{}", host, port, path),
+    };
+    
+    HttpResponse::PermanentRedirect()
+        .append_header(("Location", location))
+        .finish()
+}
+
+// Every route except the login steps, logout and static assets requires a
+// role; /api additionally accepts bearer API tokens instead of the session cookie.
+// POST, PUT, PATCH and DELETE with the session cookie also need its CSRF token
+fn configure_routes(cfg: &mut web::ServiceConfig, api_token_use_case: Arc<ApiTokenUseCase>) {
     cfg
-        .route("/", web::get().to(index))
+        .route("/", web::get().to(index).wrap(RequireRole::viewer()))
         .route("/login", web::get().to(login_page))
         .route("/login", web::post().to(login))
-        .route("/logout", web::get().to(logout))
-        .route("/ws", web::get().to(websocket_handler))
-        .route("/api/trigger-update", web::post().to(trigger_update))
-        .route("/api/users", web::get().to(get_users))
-        .route("/api/users/{username}/disconnect", web::post().to(disconnect_user))
+        .route("/login/options", web::get().to(login_options))
+        .route("/login/oidc", web::get().to(oidc_login))
+        .route("/login/oidc/callback", web::get().to(oidc_callback))
+        .route("/login/totp", web::get().to(login_totp_page))
+        .route("/login/totp", web::post().to(login_totp))
+        .route("/login/totp/state", web::get().to(login_totp_state))
+        .route("/login/totp/enrollment", web::post().to(login_totp_enrollment))
+        .route("/account/totp", web::get().to(totp_page).wrap(RequireRole::viewer()))
+        .route("/logout", web::post().to(logout))
+        .route("/ws", web::get().to(websocket_handler).wrap(RequireRole::viewer()))
+        .service(
+            web::scope("/api")
+                .wrap(ApiAuthentication::new(api_token_use_case.clone()))
+                .route("/me", web::get().to(current_user).wrap(RequireRole::viewer()))
+                .route("/me/totp", web::get().to(totp_status).wrap(RequireRole::viewer().session_only()))
+                .route("/me/totp", web::post().to(start_totp_enrollment).wrap(RequireRole::viewer().session_only()))
+                .route("/me/totp/confirm", web::post().to(confirm_totp_enrollment).wrap(RequireRole::viewer().session_only()))
+                .route("/me/totp/disable", web::post().to(disable_own_totp).wrap(RequireRole::viewer().session_only()))
+                .route("/me/password", web::post().to(change_own_password).wrap(RequireRole::viewer().session_only()))
+                .route("/accounts", web::get().to(list_accounts).wrap(RequireRole::admin().session_only()))
+                .route("/accounts", web::post().to(create_account).wrap(RequireRole::admin().session_only()))
+                .route("/accounts/{username}", web::delete().to(delete_account).wrap(RequireRole::admin().session_only()))
+                .route("/accounts/{username}/password", web::put().to(reset_account_password).wrap(RequireRole::admin().session_only()))
+                .route("/totp/{username}", web::delete().to(reset_totp).wrap(RequireRole::admin().session_only()))
+                .route("/trigger-update", web::post().to(trigger_update).wrap(RequireRole::operator()))
+                .route("/users", web::get().to(get_users).wrap(RequireRole::viewer()))
+                .route("/users/{username}/sessions", web::get().to(get_user_sessions).wrap(RequireRole::viewer()))
+                .route("/users/{username}/latency", web::get().to(get_user_latency).wrap(RequireRole::viewer()))
+                .route("/users/{username}/disconnect", web::post().to(disconnect_user).wrap(RequireRole::operator()))
+                .route("/secrets", web::get().to(list_secrets).wrap(RequireRole::operator()))
+                .route("/secrets", web::post().to(create_secret).wrap(RequireRole::admin()))
+                .route("/secrets/{name}", web::patch().to(update_secret).wrap(RequireRole::admin()))
+                .route("/secrets/{name}", web::delete().to(delete_secret).wrap(RequireRole::admin()))
+                .route("/secrets/{name}/enable", web::post().to(enable_secret).wrap(RequireRole::operator()))
+                .route("/secrets/{name}/disable", web::post().to(disable_secret).wrap(RequireRole::operator()))
+                .route("/lockouts", web::get().to(list_lockouts).wrap(RequireRole::admin()))
+                .route("/lockouts/{scope}/{subject}", web::delete().to(clear_lockout).wrap(RequireRole::admin()))
+                .route("/audit", web::get().to(list_audit_entries).wrap(RequireRole::admin()))
+                .route("/audit/export", web::get().to(export_audit_log).wrap(RequireRole::admin()))
+                .route("/audit/verify", web::get().to(verify_audit_log).wrap(RequireRole::admin()))
+                .route("/webhooks/dead-letters", web::get().to(list_dead_letters).wrap(RequireRole::admin()))
+                .route("/webhooks/dead-letters", web::delete().to(clear_dead_letters).wrap(RequireRole::admin()))
+                .route("/webhooks/dead-letters/redeliver", web::post().to(redeliver_dead_letters).wrap(RequireRole::admin()))
+                .route("/alerts", web::get().to(list_alerts).wrap(RequireRole::viewer()))
+                .route("/alerts/rules", web::get().to(list_alert_rules).wrap(RequireRole::viewer()))
+                .route("/alerts/silences", web::get().to(list_alert_silences).wrap(RequireRole::viewer()))
+                .route("/alerts/silences", web::post().to(create_alert_silence).wrap(RequireRole::operator()))
+                .route("/alerts/silences/{id}", web::delete().to(remove_alert_silence).wrap(RequireRole::operator()))
+                .route("/tokens", web::get().to(list_tokens).wrap(RequireRole::admin().session_only()))
+                .route("/tokens", web::post().to(issue_token).wrap(RequireRole::admin().session_only()))
+                .route("/tokens/{id}", web::delete().to(revoke_token).wrap(RequireRole::admin().session_only()))
+        )
+        .route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz))
+        .route(
+            "/metrics",
+            web::get().to(metrics).wrap(RequireRole::viewer()).wrap(ApiAuthentication::new(api_token_use_case)),
+        )
         .service(fs::Files::new("/static", "./asset").show_files_listing());
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn start_server(
     vpn_user_use_case: Arc<VpnUserUseCase>,
     auth_use_case: Arc<AuthUseCase>,
+    ppp_secret_use_case: Arc<PppSecretUseCase>,
+    api_token_use_case: Arc<ApiTokenUseCase>,
+    totp_use_case: Arc<TotpUseCase>,
+    audit_use_case: Arc<AuditUseCase>,
+    local_user_use_case: Arc<LocalUserUseCase>,
+    health_use_case: Arc<HealthUseCase>,
+    webhook_use_case: Arc<WebhookUseCase>,
+    alert_use_case: Arc<AlertUseCase>,
+    websocket_manager: Addr<WebSocketManager>,
+    metrics: Arc<dyn MetricsRecorder + Send + Sync>,
     config_service: Arc<dyn ConfigService + Send + Sync>,
+    args: &Args,
 ) -> std::io::Result<()> {
-    let args = Args::parse();
-    
     let app_config = config_service.get_app_config()
         .expect("Failed to get app config");
+    let tls_settings = config_service.get_tls_config()
+        .and_then(|tls_config| tls_config.as_ref().map(TlsSettings::from_config).transpose())
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
     
     info!("Starting mikriting-tool server on http://{}:{}", args.address, args.port);
     info!("Static files served from: {}", app_config.static_files_path);
     
-    let websocket_manager = WebSocketManager::new().start();
-    
     let app_state = web::Data::new(AppState {
         vpn_user_use_case,
         auth_use_case,
+        ppp_secret_use_case,
+        api_token_use_case: api_token_use_case.clone(),
+        totp_use_case,
+        audit_use_case: audit_use_case.clone(),
+        local_user_use_case: local_user_use_case.clone(),
+        health_use_case,
+        webhook_use_case,
+        alert_use_case,
         websocket_manager,
+        metrics,
     });
     
-    let secret_key = Key::generate();
+    let session_settings = SessionSettings::from_config(&app_config, args.dev)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
     
-    HttpServer::new(move || {
+    if tls_settings.is_some() && !app_config.cookie_secure {
+        warn!("TLS is enabled but app.cookie_secure is false, session cookies are also sent over plain HTTP");
+    }
+    
+    let connection_tls = tls_settings.clone();
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
-            .wrap(Logger::default())
-            .wrap(SessionMiddleware::new(
-                CookieSessionStore::default(),
-                secret_key.clone(),
-            ))
-            .configure(configure_routes)
+            .wrap(CsrfProtection::new(&app_config.trusted_origins))
+            .wrap(SessionTimeout::new(&app_config, local_user_use_case.clone()))
+            .wrap(Logger::default().exclude("/healthz").exclude("/readyz"))
+            .wrap(session_settings.middleware())
+            .configure(|cfg| configure_routes(cfg, api_token_use_case.clone()))
+    })
+    .on_connect(move |connection, extensions| {
+        if let Some(principal) = connection_tls.as_ref().and_then(|tls| tls.client_principal(connection)) {
+            extensions.insert(principal);
+        }
+    });
+    
+    let Some(tls) = tls_settings else {
+        return server.bind((args.address, args.port))?.run().await;
+    };
+    
+    info!("Starting HTTPS on https://{}:{}", args.address, tls.port());
+    tls.watch(audit_use_case);
+    let server = server.bind_rustls_0_23((args.address, tls.port()), tls.server_config())?;
+    if !tls.redirect_http() {
+        return server.bind((args.address, args.port))?.run().await;
+    }
+    
+    // The plain HTTP port only sends browsers to the HTTPS port
+    info!("Redirecting http://{}:{} to HTTPS", args.address, args.port);
+    let tls_port = tls.port();
+    let redirect_server = HttpServer::new(move || {
+        App::new().default_service(web::to(move |req: HttpRequest| redirect_to_https(req, tls_port)))
     })
     .bind((args.address, args.port))?
-    .run()
-    .await
+    .run();
+    
+    futures::future::try_join(server.run(), redirect_server).await.map(|_| ())
 }
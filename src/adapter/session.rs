@@ -0,0 +1,482 @@
+use actix_session::{
+    config::PersistentSession,
+    storage::CookieSessionStore,
+    Session,
+    SessionExt,
+    SessionInsertError,
+    SessionMiddleware,
+};
+use actix_web::{
+    cookie::{time, Key, SameSite},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use log::{debug, info, warn};
+
+use crate::domain::models::{AppConfig, AuthUser, DomainError, OidcAuthorization, Role};
+use crate::usecase::LocalUserUseCase;
+
+// Default value of `session_secret`, refused outside of development mode
+const PLACEHOLDER_SECRET: &str = "change-me-in-production";
+
+// Key::derive_from needs at least 32 bytes, Key::from at least 64
+const MIN_SECRET_LEN: usize = 32;
+const KEY_FILE_LEN: usize = 64;
+
+// Session entries holding the login time and the time of the last request
+pub const SESSION_ISSUED_AT: &str = "issued_at";
+pub const SESSION_LAST_SEEN: &str = "last_seen";
+
+// Credential stamp of the local account at login, absent for users that have
+// none, such as those from LDAP or single sign-on
+const SESSION_CREDENTIAL: &str = "credential";
+
+// Session entries of a login that passed the password check and still has to
+// enter its TOTP code. They never grant access on their own
+const PENDING_USERNAME: &str = "pending_username";
+const PENDING_ROLE: &str = "pending_role";
+const PENDING_AT: &str = "pending_at";
+const PENDING_ATTEMPTS: &str = "pending_attempts";
+
+// OpenID Connect request waiting for the identity provider's callback
+const OIDC_REQUEST: &str = "oidc_request";
+
+// Token that state-changing requests of the session must send back
+const CSRF_TOKEN: &str = "csrf_token";
+
+// Time and number of tries allowed for the second login step
+const PENDING_LOGIN_SECONDS: i64 = 300;
+pub const MAX_PENDING_ATTEMPTS: u32 = 5;
+
+// Logs the user in, replacing any previous session to prevent fixation
+pub fn start_session(session: &Session, user: &AuthUser, credential: Option<&str>) -> Result<(), SessionInsertError> {
+    let now = Utc::now().timestamp();
+    session.renew();
+    session.clear();
+    session.insert("username", &user.username)?;
+    session.insert("role", user.role)?;
+    session.insert(SESSION_ISSUED_AT, now)?;
+    session.insert(SESSION_LAST_SEEN, now)?;
+    set_session_credential(session, credential)
+}
+
+// Keeps the session of a user who changed their own password
+pub fn set_session_credential(session: &Session, credential: Option<&str>) -> Result<(), SessionInsertError> {
+    match credential {
+        Some(credential) => session.insert(SESSION_CREDENTIAL, credential),
+        None => {
+            session.remove(SESSION_CREDENTIAL);
+            Ok(())
+        }
+    }
+}
+
+pub fn start_pending_login(session: &Session, user: &AuthUser) -> Result<(), SessionInsertError> {
+    session.renew();
+    session.clear();
+    session.insert(PENDING_USERNAME, &user.username)?;
+    session.insert(PENDING_ROLE, user.role)?;
+    session.insert(PENDING_AT, Utc::now().timestamp())?;
+    session.insert(PENDING_ATTEMPTS, 0u32)
+}
+
+// The user waiting for the second login step, unless it timed out
+pub fn pending_login(session: &Session) -> Option<AuthUser> {
+    let username = session.get::<String>(PENDING_USERNAME).ok()??;
+    let role = session.get::<Role>(PENDING_ROLE).ok()??;
+    let pending_at = session.get::<i64>(PENDING_AT).ok()??;
+
+    if Utc::now().timestamp() - pending_at > PENDING_LOGIN_SECONDS {
+        session.purge();
+        return None;
+    }
+    Some(AuthUser::new(username, role))
+}
+
+pub fn store_oidc_request(session: &Session, request: &OidcAuthorization) -> Result<(), SessionInsertError> {
+    session.insert(OIDC_REQUEST, request)
+}
+
+// Each request can be answered once
+pub fn take_oidc_request(session: &Session) -> Option<OidcAuthorization> {
+    session.remove_as::<OidcAuthorization>(OIDC_REQUEST)?.ok()
+}
+
+// The session's CSRF token, created on first use. Logging in clears the
+// session, so every login starts with a new token
+pub fn csrf_token(session: &Session) -> Result<String, SessionInsertError> {
+    if let Ok(Some(token)) = session.get::<String>(CSRF_TOKEN) {
+        return Ok(token);
+    }
+    let token = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+    session.insert(CSRF_TOKEN, &token)?;
+    Ok(token)
+}
+
+// Compared without short-circuiting, a session without a token matches nothing
+pub fn csrf_token_matches(session: &Session, candidate: &str) -> bool {
+    let Ok(Some(token)) = session.get::<String>(CSRF_TOKEN) else {
+        return false;
+    };
+    token.len() == candidate.len()
+        && token.bytes().zip(candidate.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Counts a wrong code and returns the tries made so far
+pub fn record_pending_attempt(session: &Session) -> Result<u32, SessionInsertError> {
+    let attempts = session.get::<u32>(PENDING_ATTEMPTS).ok().flatten().unwrap_or(0) + 1;
+    session.insert(PENDING_ATTEMPTS, attempts)?;
+    Ok(attempts)
+}
+
+// Picks the cookie signing/encryption key. A key file wins over the secret so
+// several instances can share sessions by sharing the file; a missing key
+// file is created with fresh random bytes
+fn session_key(config: &AppConfig, dev: bool) -> Result<Key, DomainError> {
+    if !config.session_key_file.is_empty() {
+        return load_key_file(&config.session_key_file);
+    }
+
+    if config.session_secret == PLACEHOLDER_SECRET {
+        if dev {
+            warn!("Using a random session key, sessions will not survive a restart");
+            return Ok(Key::generate());
+        }
+        return Err(DomainError::ConfigurationError(format!(
+            "app.session_secret is still the placeholder '{}'; set a secret of at least {} bytes or a session_key_file, or start with --dev",
+            PLACEHOLDER_SECRET, MIN_SECRET_LEN
+        )));
+    }
+
+    if config.session_secret.len() < MIN_SECRET_LEN {
+        return Err(DomainError::ConfigurationError(format!(
+            "app.session_secret must be at least {} bytes long", MIN_SECRET_LEN
+        )));
+    }
+
+    Ok(Key::derive_from(config.session_secret.as_bytes()))
+}
+
+fn load_key_file(path: &str) -> Result<Key, DomainError> {
+    let key_error = |e: std::io::Error| {
+        DomainError::ConfigurationError(format!("Failed to access session key file {}: {}", path, e))
+    };
+
+    match fs::read(path) {
+        Ok(bytes) if bytes.len() >= KEY_FILE_LEN => {
+            info!("Loaded session key from {}", path);
+            Ok(Key::from(&bytes))
+        }
+        Ok(_) => Err(DomainError::ConfigurationError(format!(
+            "Session key file {} must hold at least {} bytes", path, KEY_FILE_LEN
+        ))),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let key = Key::generate();
+            write_private_file(path, key.master()).map_err(key_error)?;
+            info!("Generated new session key file {}", path);
+            Ok(key)
+        }
+        Err(e) => Err(key_error(e)),
+    }
+}
+
+fn write_private_file(path: &str, content: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(content)
+}
+
+fn same_site(value: &str) -> Result<SameSite, DomainError> {
+    match value.to_ascii_lowercase().as_str() {
+        "strict" => Ok(SameSite::Strict),
+        "lax" => Ok(SameSite::Lax),
+        "none" => Ok(SameSite::None),
+        other => Err(DomainError::ConfigurationError(format!(
+            "app.cookie_same_site must be strict, lax or none, got '{}'", other
+        ))),
+    }
+}
+
+// Validated cookie settings, turned into a session middleware per worker
+#[derive(Clone)]
+pub struct SessionSettings {
+    key: Key,
+    secure: bool,
+    same_site: SameSite,
+    max_age_minutes: u64,
+}
+
+impl SessionSettings {
+    pub fn from_config(config: &AppConfig, dev: bool) -> Result<Self, DomainError> {
+        let same_site = same_site(&config.cookie_same_site)?;
+        if same_site == SameSite::None && !config.cookie_secure {
+            return Err(DomainError::ConfigurationError(
+                "app.cookie_same_site = \"none\" requires app.cookie_secure = true".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            key: session_key(config, dev)?,
+            secure: config.cookie_secure,
+            same_site,
+            max_age_minutes: config.session_max_age_minutes,
+        })
+    }
+
+    pub fn middleware(&self) -> SessionMiddleware<CookieSessionStore> {
+        let mut builder = SessionMiddleware::builder(CookieSessionStore::default(), self.key.clone())
+            .cookie_secure(self.secure)
+            .cookie_same_site(self.same_site)
+            .cookie_http_only(true);
+
+        // Without a max-age the cookie lives until the browser is closed
+        if self.max_age_minutes > 0 {
+            builder = builder.session_lifecycle(
+                PersistentSession::default()
+                    .session_ttl(time::Duration::minutes(self.max_age_minutes as i64)),
+            );
+        }
+
+        builder.build()
+    }
+}
+
+// Ends sessions older than `max_age_minutes` or idle longer than
+// `idle_timeout_minutes`; a zero disables the respective check. Sessions of
+// a local account also end once the account is deleted or its password is
+// changed. The cookie store encrypts the session, so the timestamps and the
+// credential stamp cannot be tampered with
+#[derive(Clone)]
+pub struct SessionTimeout {
+    max_age_minutes: u64,
+    idle_timeout_minutes: u64,
+    local_user_use_case: Arc<LocalUserUseCase>,
+}
+
+impl SessionTimeout {
+    pub fn new(config: &AppConfig, local_user_use_case: Arc<LocalUserUseCase>) -> Self {
+        Self {
+            max_age_minutes: config.session_max_age_minutes,
+            idle_timeout_minutes: config.session_idle_timeout_minutes,
+            local_user_use_case,
+        }
+    }
+
+    fn expired(&self, session: &Session, now: i64) -> bool {
+        let issued_at = session.get::<i64>(SESSION_ISSUED_AT).ok().flatten().unwrap_or(0);
+        let last_seen = session.get::<i64>(SESSION_LAST_SEEN).ok().flatten().unwrap_or(0);
+
+        let expired = |since: i64, minutes: u64| {
+            minutes > 0 && now - since > Duration::minutes(minutes as i64).num_seconds()
+        };
+        expired(issued_at, self.max_age_minutes) || expired(last_seen, self.idle_timeout_minutes)
+    }
+
+    // A storage error ends the session too, rather than keep a credential
+    // that may have been revoked
+    async fn revoked(&self, session: &Session, username: &str) -> bool {
+        let Ok(Some(credential)) = session.get::<String>(SESSION_CREDENTIAL) else {
+            return false;
+        };
+        match self.local_user_use_case.credential_stamp(username).await {
+            Ok(current) => current.as_deref() != Some(credential.as_str()),
+            Err(e) => {
+                warn!("Failed to check the account of user {}: {}", username, e);
+                true
+            }
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SessionTimeout
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SessionTimeoutMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SessionTimeoutMiddleware {
+            service: Rc::new(service),
+            timeout: self.clone(),
+        }))
+    }
+}
+
+pub struct SessionTimeoutMiddleware<S> {
+    service: Rc<S>,
+    timeout: SessionTimeout,
+}
+
+impl<S, B> Service<ServiceRequest> for SessionTimeoutMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let timeout = self.timeout.clone();
+
+        Box::pin(async move {
+            let session = req.get_session();
+
+            if let Ok(Some(username)) = session.get::<String>("username") {
+                let now = Utc::now().timestamp();
+                if timeout.expired(&session, now) {
+                    debug!("Session of user {} expired", username);
+                    session.purge();
+                } else if timeout.revoked(&session, &username).await {
+                    debug!("Session of user {} ended, the account was deleted or its password changed", username);
+                    session.purge();
+                } else if let Err(e) = session.insert(SESSION_LAST_SEEN, now) {
+                    warn!("Failed to refresh session of user {}: {}", username, e);
+                }
+            }
+
+            service.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::middleware::RequireRole;
+    use crate::domain::models::NewLocalUser;
+    use crate::infrastructure::HtpasswdAuthRepository;
+    use actix_web::{
+        body::MessageBody,
+        cookie::Cookie,
+        dev::ServiceFactory,
+        http::StatusCode,
+        test::{self, TestRequest},
+        web,
+        App,
+        HttpResponse,
+    };
+
+    // Logs in without a password check, the way the login handlers do once
+    // it passed
+    async fn login(path: web::Path<String>, session: Session, users: web::Data<LocalUserUseCase>) -> HttpResponse {
+        let username = path.into_inner();
+        let credential = users.credential_stamp(&username).await.unwrap();
+        start_session(&session, &AuthUser::new(username, Role::Viewer), credential.as_deref()).unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    // Local accounts in temporary files, removed when dropped
+    struct Accounts {
+        use_case: Arc<LocalUserUseCase>,
+        htpasswd_path: String,
+        roles_path: String,
+    }
+
+    impl Accounts {
+        async fn new(usernames: &[&str]) -> Self {
+            let base = std::env::temp_dir().join(format!("mikriting-session-{}", uuid::Uuid::new_v4()));
+            let (htpasswd_path, roles_path) = (format!("{}.htpasswd", base.display()), format!("{}.roles", base.display()));
+            let repository = HtpasswdAuthRepository::new(htpasswd_path.clone(), roles_path.clone(), Role::Viewer);
+            let use_case = Arc::new(LocalUserUseCase::new(Arc::new(repository)));
+
+            for username in usernames {
+                let user = NewLocalUser { username: username.to_string(), password: "correct horse".to_string(), role: None };
+                use_case.create_user(user).await.unwrap();
+            }
+            Self { use_case, htpasswd_path, roles_path }
+        }
+    }
+
+    impl Drop for Accounts {
+        fn drop(&mut self) {
+            for path in [self.htpasswd_path.clone(), self.roles_path.clone(), format!("{}.lock", self.htpasswd_path)] {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    fn app(use_case: Arc<LocalUserUseCase>) -> App<impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >> {
+        let config = AppConfig::default();
+        let settings = SessionSettings::from_config(&config, true).unwrap();
+        App::new()
+            .app_data(web::Data::from(use_case.clone()))
+            .wrap(SessionTimeout::new(&config, use_case))
+            .wrap(settings.middleware())
+            .route("/login/{username}", web::post().to(login))
+            .route("/api/me", web::get().to(HttpResponse::Ok).wrap(RequireRole::viewer()))
+    }
+
+    fn login_request(username: &str) -> TestRequest {
+        TestRequest::post().uri(&format!("/login/{}", username))
+    }
+
+    fn me_request(cookie: &Cookie<'static>) -> TestRequest {
+        TestRequest::get().uri("/api/me").cookie(cookie.clone())
+    }
+
+    fn session_cookie<B>(response: ServiceResponse<B>) -> Cookie<'static> {
+        assert_eq!(response.status(), StatusCode::OK);
+        response.response().cookies().next().unwrap().into_owned()
+    }
+
+    #[actix_web::test]
+    async fn deleted_account_loses_its_session() {
+        let accounts = Accounts::new(&["alice", "bob"]).await;
+        let app = test::init_service(app(accounts.use_case.clone())).await;
+        let alice = session_cookie(test::call_service(&app, login_request("alice").to_request()).await);
+        let bob = session_cookie(test::call_service(&app, login_request("bob").to_request()).await);
+        assert_eq!(test::call_service(&app, me_request(&alice).to_request()).await.status(), StatusCode::OK);
+
+        accounts.use_case.delete_user("alice").await.unwrap();
+        assert_eq!(test::call_service(&app, me_request(&alice).to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(test::call_service(&app, me_request(&bob).to_request()).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn password_reset_ends_sessions_but_not_later_logins() {
+        let accounts = Accounts::new(&["alice"]).await;
+        let app = test::init_service(app(accounts.use_case.clone())).await;
+        let before = session_cookie(test::call_service(&app, login_request("alice").to_request()).await);
+
+        accounts.use_case.reset_password("alice", "battery staple").await.unwrap();
+        assert_eq!(test::call_service(&app, me_request(&before).to_request()).await.status(), StatusCode::UNAUTHORIZED);
+
+        let after = session_cookie(test::call_service(&app, login_request("alice").to_request()).await);
+        assert_eq!(test::call_service(&app, me_request(&after).to_request()).await.status(), StatusCode::OK);
+    }
+
+    // Users from LDAP or single sign-on have no local account to check
+    #[actix_web::test]
+    async fn session_without_local_account_is_kept() {
+        let accounts = Accounts::new(&[]).await;
+        let app = test::init_service(app(accounts.use_case.clone())).await;
+        let carol = session_cookie(test::call_service(&app, login_request("carol").to_request()).await);
+        assert_eq!(test::call_service(&app, me_request(&carol).to_request()).await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,193 @@
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::rt::net::TcpStream;
+use std::any::Any;
+use std::fs;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore,
+    ServerConfig,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+use log::{debug, error, info};
+
+use crate::adapter::middleware::CertificatePrincipal;
+use crate::domain::models::{AuditAction, AuditRecord, AuthUser, DomainError, TlsConfig};
+use crate::usecase::AuditUseCase;
+
+fn tls_error(field: &str, path: &str, e: impl std::fmt::Display) -> DomainError {
+    DomainError::ConfigurationError(format!("tls.{} {}: {}", field, path, e))
+}
+
+// Modification time and length, compared to notice a renewed file. Symlinks
+// are followed, so certbot's switch to a new archive file counts as a change
+fn file_stamp(path: &str) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+// The certificate chain with its private key, refused when they do not match
+fn load_certified_key(config: &TlsConfig, provider: &CryptoProvider) -> Result<CertifiedKey, DomainError> {
+    let chain = CertificateDer::pem_file_iter(&config.cert_file)
+        .map_err(|e| tls_error("cert_file", &config.cert_file, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| tls_error("cert_file", &config.cert_file, e))?;
+    if chain.is_empty() {
+        return Err(tls_error("cert_file", &config.cert_file, "no certificates found"));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(&config.key_file)
+        .map_err(|e| tls_error("key_file", &config.key_file, e))?;
+    CertifiedKey::from_der(chain, key, provider)
+        .map_err(|e| tls_error("key_file", &config.key_file, e))
+}
+
+fn client_roots(path: &str) -> Result<RootCertStore, DomainError> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path).map_err(|e| tls_error("client_ca_file", path, e))? {
+        roots.add(cert.map_err(|e| tls_error("client_ca_file", path, e))?)
+            .map_err(|e| tls_error("client_ca_file", path, e))?;
+    }
+    if roots.is_empty() {
+        return Err(tls_error("client_ca_file", path, "no certificates found"));
+    }
+    Ok(roots)
+}
+
+fn common_name(certificate: &CertificateDer<'_>) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(certificate.as_ref()).ok()?;
+    let common_name = certificate.subject().iter_common_name().next()?;
+    common_name.as_str().ok().map(str::to_string)
+}
+
+// Hands every handshake the certificate loaded last
+#[derive(Debug)]
+struct ReloadingCertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCertResolver {
+    fn replace(&self, key: CertifiedKey) {
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(key);
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap_or_else(PoisonError::into_inner).clone())
+    }
+}
+
+// Validated `[tls]` section, turned into the rustls configuration of the
+// HTTPS listener. Client certificates are optional on the handshake so
+// browsers connect without one; they only identify API clients
+#[derive(Clone)]
+pub struct TlsSettings {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    resolver: Arc<ReloadingCertResolver>,
+    server_config: ServerConfig,
+}
+
+impl TlsSettings {
+    pub fn from_config(config: &TlsConfig) -> Result<Self, DomainError> {
+        let provider = Arc::new(ring::default_provider());
+        let resolver = Arc::new(ReloadingCertResolver {
+            current: RwLock::new(Arc::new(load_certified_key(config, &provider)?)),
+        });
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| DomainError::ConfigurationError(format!("TLS: {}", e)))?;
+        let builder = if config.client_ca_file.is_empty() {
+            builder.with_no_client_auth()
+        } else {
+            let roots = client_roots(&config.client_ca_file)?;
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| tls_error("client_ca_file", &config.client_ca_file, e))?;
+            builder.with_client_cert_verifier(verifier)
+        };
+
+        info!("Loaded TLS certificate from {}", config.cert_file);
+        Ok(Self {
+            config: config.clone(),
+            provider,
+            server_config: builder.with_cert_resolver(resolver.clone()),
+            resolver,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.config.port
+    }
+
+    pub fn redirect_http(&self) -> bool {
+        self.config.redirect_http
+    }
+
+    pub fn server_config(&self) -> ServerConfig {
+        self.server_config.clone()
+    }
+
+    // Checks the certificate and key files every `reload_interval_seconds`
+    // and serves new handshakes from the changed files. A renewal that does
+    // not load, such as a key written after its certificate, keeps the
+    // previous certificate until the files change again. Either outcome is
+    // recorded in the audit log
+    pub fn watch(&self, audit_use_case: Arc<AuditUseCase>) {
+        let config = self.config.clone();
+        let provider = self.provider.clone();
+        let resolver = self.resolver.clone();
+
+        tokio::spawn(async move {
+            let stamps = || (file_stamp(&config.cert_file), file_stamp(&config.key_file));
+            let mut last = stamps();
+            let mut interval = tokio::time::interval(Duration::from_secs(config.reload_interval_seconds));
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+                let current = stamps();
+                if current == last {
+                    continue;
+                }
+                last = current;
+
+                let result = load_certified_key(&config, &provider).map(|key| resolver.replace(key));
+                match &result {
+                    Ok(()) => info!("Reloaded TLS certificate from {}", config.cert_file),
+                    Err(e) => error!("Keeping the previous TLS certificate: {}", e),
+                }
+                audit_use_case.record(
+                    AuditRecord::new("system", None, AuditAction::ConfigReloaded)
+                        .target(&config.cert_file)
+                        .detail("TLS certificate")
+                        .result(&result)
+                ).await;
+            }
+        });
+    }
+
+    // The API client behind an HTTPS connection, from the common name of the
+    // certificate it presented. Certificates were already verified against
+    // `client_ca_file` during the handshake
+    pub fn client_principal(&self, connection: &dyn Any) -> Option<CertificatePrincipal> {
+        let stream = connection.downcast_ref::<TlsStream<TcpStream>>()?;
+        let certificate = stream.get_ref().1.peer_certificates()?.first()?;
+        let name = common_name(certificate)?;
+
+        match self.config.client_roles.iter().find(|entry| entry.common_name == name) {
+            Some(entry) => Some(CertificatePrincipal(AuthUser::new(format!("cert:{}", name), entry.role))),
+            None => {
+                debug!("Client certificate {} is not listed in tls.client_roles", name);
+                None
+            }
+        }
+    }
+}
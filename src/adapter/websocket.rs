@@ -3,10 +3,11 @@ use actix_web_actors::ws;
 use async_trait::async_trait;
 use log::{debug, error, info};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use crate::domain::{
-    models::{VpnUser, LatencyUpdate, WebSocketMessage, DomainError},
-    traits::EventPublisher,
+    models::{VpnUser, LatencyUpdate, UserChanged, UserConnected, UserDisconnected, WebSocketMessage, DomainError},
+    traits::{EventPublisher, MetricsRecorder},
 };
 
 // WebSocket Actor
@@ -110,19 +111,21 @@ impl Handler<BroadcastMessage> for WebSocketActor {
 // WebSocket Manager
 pub struct WebSocketManager {
     connections: HashMap<u64, Addr<WebSocketActor>>,
+    metrics: Arc<dyn MetricsRecorder + Send + Sync>,
 }
 
 impl WebSocketManager {
-    pub fn new() -> Self {
+    pub fn new(metrics: Arc<dyn MetricsRecorder + Send + Sync>) -> Self {
         Self {
             connections: HashMap::new(),
+            metrics,
         }
     }
 
     fn broadcast(&self, message: &str) {
         debug!("Broadcasting message to {} connections", self.connections.len());
         
-        for (_id, addr) in &self.connections {
+        for addr in self.connections.values() {
             addr.do_send(BroadcastMessage {
                 message: message.to_string(),
             });
@@ -130,12 +133,6 @@ impl WebSocketManager {
     }
 }
 
-impl Default for WebSocketManager {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
 impl Actor for WebSocketManager {
     type Context = Context<Self>;
 
@@ -150,6 +147,7 @@ impl Handler<Connect> for WebSocketManager {
     fn handle(&mut self, msg: Connect, _: &mut Self::Context) {
         debug!("WebSocket connection {} registered", msg.id);
         self.connections.insert(msg.id, msg.addr);
+        self.metrics.websocket_connections(self.connections.len());
     }
 }
 
@@ -159,6 +157,7 @@ impl Handler<Disconnect> for WebSocketManager {
     fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) {
         debug!("WebSocket connection {} disconnected", msg.id);
         self.connections.remove(&msg.id);
+        self.metrics.websocket_connections(self.connections.len());
     }
 }
 
@@ -179,25 +178,35 @@ impl WebSocketEventPublisher {
     pub fn new(manager: Addr<WebSocketManager>) -> Self {
         Self { manager }
     }
-}
 
-#[async_trait]
-impl EventPublisher for WebSocketEventPublisher {
-    async fn publish_vpn_users_update(&self, users: Vec<VpnUser>) -> Result<(), DomainError> {
-        let message = WebSocketMessage::vpn_users_update(users);
+    fn send(&self, message: WebSocketMessage) -> Result<(), DomainError> {
         let json = serde_json::to_string(&message)
             .map_err(|e| DomainError::SerializationError(e.to_string()))?;
-        
+
         self.manager.do_send(BroadcastMessage { message: json });
         Ok(())
     }
+}
+
+#[async_trait]
+impl EventPublisher for WebSocketEventPublisher {
+    async fn publish_vpn_users_update(&self, users: Vec<VpnUser>) -> Result<(), DomainError> {
+        self.send(WebSocketMessage::vpn_users_update(users))
+    }
 
     async fn publish_latency_update(&self, update: LatencyUpdate) -> Result<(), DomainError> {
-        let message = WebSocketMessage::latency_update(update.user_name, update.latency);
-        let json = serde_json::to_string(&message)
-            .map_err(|e| DomainError::SerializationError(e.to_string()))?;
-        
-        self.manager.do_send(BroadcastMessage { message: json });
-        Ok(())
+        self.send(WebSocketMessage::latency_update(update))
+    }
+
+    async fn publish_user_connected(&self, event: UserConnected) -> Result<(), DomainError> {
+        self.send(WebSocketMessage::user_connected(&event))
+    }
+
+    async fn publish_user_disconnected(&self, event: UserDisconnected) -> Result<(), DomainError> {
+        self.send(WebSocketMessage::user_disconnected(&event))
+    }
+
+    async fn publish_user_changed(&self, event: UserChanged) -> Result<(), DomainError> {
+        self.send(WebSocketMessage::user_changed(&event))
     }
 }
@@ -1,4 +1,6 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::net::IpAddr;
 use std::str::FromStr;
 use uuid::Uuid;
@@ -13,7 +15,11 @@ pub struct VpnUser {
     pub uptime: String,
     pub comment: Option<String>,
     pub latency: Option<f64>,
+    pub latency_stats: Option<LatencyStats>,
     pub is_active: bool,
+    pub router: Option<String>,
+    // RouterOS `.id` of the session, unique on its router while it lasts
+    pub session_id: Option<String>,
 }
 
 #[allow(dead_code)]
@@ -35,7 +41,10 @@ impl VpnUser {
             uptime,
             comment,
             latency: None,
+            latency_stats: None,
             is_active: true,
+            router: None,
+            session_id: None,
         }
     }
 
@@ -43,6 +52,36 @@ impl VpnUser {
         IpAddr::from_str(&self.address).ok()
     }
     
+    // Per-user probe override, written as `probe=tcp:3389` in the PPP comment
+    pub fn probe_tag(&self) -> Option<&str> {
+        self.comment
+            .as_deref()?
+            .split_whitespace()
+            .find_map(|token| token.strip_prefix("probe="))
+    }
+
+    pub fn key(&self) -> UserKey {
+        UserKey::new(self.router.as_deref(), &self.name)
+    }
+
+    pub fn session_key(&self) -> SessionKey {
+        SessionKey::new(self.router.as_deref(), self.session_id.as_deref(), &self.name)
+    }
+
+    // Whether this is a later sighting of the session `earlier`. Going back in
+    // uptime means the user reconnected in between
+    pub fn continues(&self, earlier: &VpnUser) -> bool {
+        let reconnected = matches!(
+            (parse_uptime(&self.uptime), parse_uptime(&earlier.uptime)),
+            (Some(uptime), Some(earlier_uptime)) if uptime < earlier_uptime
+        );
+
+        self.router == earlier.router
+            && self.session_id == earlier.session_id
+            && self.name == earlier.name
+            && !reconnected
+    }
+
     pub fn update_latency(&mut self, latency: Option<f64>) {
         self.latency = latency;
     }
@@ -52,17 +91,335 @@ impl VpnUser {
     }
 }
 
+// Seconds of a RouterOS duration such as `1w2d3h4m5s` or `1d02:03:04`
+fn parse_uptime(uptime: &str) -> Option<u64> {
+    let split = uptime.rfind(|c: char| c.is_ascii_alphabetic()).map_or(0, |i| i + 1);
+    let (units, clock) = uptime.split_at(split);
+
+    let mut seconds = 0;
+    let mut value = String::new();
+    for c in units.chars() {
+        if c.is_ascii_digit() {
+            value.push(c);
+            continue;
+        }
+        let factor = match c {
+            'w' => 604_800,
+            'd' => 86_400,
+            'h' => 3_600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        seconds += value.parse::<u64>().ok()? * factor;
+        value.clear();
+    }
+
+    if !clock.is_empty() {
+        seconds += clock.split(':').try_fold(0, |acc, field| field.parse::<u64>().ok().map(|v| acc * 60 + v))?;
+    }
+    Some(seconds)
+}
+
+// Identifies a connected user. The same account may be connected to several
+// routers at once, each connection is then a user of its own
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UserKey {
+    pub router: Option<String>,
+    pub name: String,
+}
+
+impl UserKey {
+    pub fn new(router: Option<&str>, name: &str) -> Self {
+        Self {
+            router: router.map(str::to_string),
+            name: name.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for UserKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.router {
+            Some(router) => write!(f, "{} on {}", self.name, router),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// Identifies one session. An account may also hold several sessions on the
+// same router, told apart by their RouterOS `.id`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    pub router: Option<String>,
+    pub session_id: Option<String>,
+    pub name: String,
+}
+
+impl SessionKey {
+    pub fn new(router: Option<&str>, session_id: Option<&str>, name: &str) -> Self {
+        Self {
+            router: router.map(str::to_string),
+            session_id: session_id.map(str::to_string),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn user(&self) -> UserKey {
+        UserKey::new(self.router.as_deref(), &self.name)
+    }
+}
+
+impl std::fmt::Display for SessionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.session_id {
+            Some(session_id) => write!(f, "{} (session {})", self.user(), session_id),
+            None => write!(f, "{}", self.user()),
+        }
+    }
+}
+
+// One observed connection of a VPN user, from first sighting until it vanished
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VpnSession {
+    pub name: String,
+    pub router: Option<String>,
+    pub session_id: Option<String>,
+    pub service: Option<String>,
+    pub caller_id: Option<String>,
+    pub address: String,
+    pub comment: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    pub disconnected_at: Option<DateTime<Utc>>,
+    pub final_uptime: Option<String>,
+}
+
+impl VpnSession {
+    pub fn open(user: &VpnUser, connected_at: DateTime<Utc>) -> Self {
+        Self {
+            name: user.name.clone(),
+            router: user.router.clone(),
+            session_id: user.session_id.clone(),
+            service: user.service.clone(),
+            caller_id: user.caller_id.clone(),
+            address: user.address.clone(),
+            comment: user.comment.clone(),
+            connected_at,
+            disconnected_at: None,
+            final_uptime: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PppSecret {
+    pub id: String,
+    pub name: String,
+    pub service: Option<String>,
+    pub profile: Option<String>,
+    pub remote_address: Option<String>,
+    pub comment: Option<String>,
+    pub disabled: bool,
+    pub router: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewPppSecret {
+    pub name: String,
+    pub password: String,
+    pub service: Option<String>,
+    pub profile: Option<String>,
+    pub remote_address: Option<String>,
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PppSecretUpdate {
+    pub password: Option<String>,
+    pub profile: Option<String>,
+    pub remote_address: Option<String>,
+    pub comment: Option<String>,
+}
+
+const PPP_SERVICES: &[&str] = &["any", "async", "l2tp", "ovpn", "pppoe", "pptp", "sstp"];
+
+fn validate_text(field: &str, value: &str, max_len: usize) -> Result<(), DomainError> {
+    if value.trim().is_empty() {
+        return Err(DomainError::InvalidInput(format!("{} must not be empty", field)));
+    }
+    if value.len() > max_len {
+        return Err(DomainError::InvalidInput(format!("{} must be at most {} characters", field, max_len)));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(DomainError::InvalidInput(format!("{} must not contain control characters", field)));
+    }
+    Ok(())
+}
+
+fn validate_remote_address(value: &str) -> Result<(), DomainError> {
+    IpAddr::from_str(value)
+        .map(|_| ())
+        .map_err(|_| DomainError::InvalidIpAddress(value.to_string()))
+}
+
+pub fn validate_secret_name(name: &str) -> Result<(), DomainError> {
+    validate_text("name", name, 64)?;
+    
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '@')) {
+        return Err(DomainError::InvalidInput(
+            "name may only contain letters, digits, '.', '_', '-' and '@'".to_string()
+        ));
+    }
+    Ok(())
+}
+
+impl NewPppSecret {
+    pub fn validate(&self) -> Result<(), DomainError> {
+        validate_secret_name(&self.name)?;
+        validate_text("password", &self.password, 128)?;
+        
+        if let Some(service) = &self.service
+            && !PPP_SERVICES.contains(&service.as_str())
+        {
+            return Err(DomainError::InvalidInput(format!(
+                "service must be one of {}", PPP_SERVICES.join(", ")
+            )));
+        }
+        if let Some(profile) = &self.profile {
+            validate_text("profile", profile, 64)?;
+        }
+        if let Some(remote_address) = &self.remote_address {
+            validate_remote_address(remote_address)?;
+        }
+        if let Some(comment) = &self.comment {
+            validate_text("comment", comment, 255)?;
+        }
+        Ok(())
+    }
+}
+
+impl PppSecretUpdate {
+    pub fn validate(&self) -> Result<(), DomainError> {
+        if self.password.is_none() && self.profile.is_none()
+            && self.remote_address.is_none() && self.comment.is_none()
+        {
+            return Err(DomainError::InvalidInput("update contains no changes".to_string()));
+        }
+        
+        if let Some(password) = &self.password {
+            validate_text("password", password, 128)?;
+        }
+        if let Some(profile) = &self.profile {
+            validate_text("profile", profile, 64)?;
+        }
+        if let Some(remote_address) = &self.remote_address {
+            validate_remote_address(remote_address)?;
+        }
+        if let Some(comment) = &self.comment {
+            validate_text("comment", comment, 255)?;
+        }
+        Ok(())
+    }
+}
+
+// Dashboard roles, each one includes the permissions of the roles before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl Role {
+    pub fn allows(self, required: Role) -> bool {
+        self >= required
+    }
+}
+
+impl FromStr for Role {
+    type Err = DomainError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "viewer" => Ok(Self::Viewer),
+            "operator" => Ok(Self::Operator),
+            "admin" => Ok(Self::Admin),
+            other => Err(DomainError::InvalidInput(format!(
+                "unknown role '{}', expected viewer, operator or admin", other
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Viewer => "viewer",
+            Self::Operator => "operator",
+            Self::Admin => "admin",
+        })
+    }
+}
+
+// What failed login attempts are counted against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockoutScope {
+    Ip,
+    Username,
+}
+
+impl FromStr for LockoutScope {
+    type Err = DomainError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ip" => Ok(Self::Ip),
+            "username" => Ok(Self::Username),
+            other => Err(DomainError::InvalidInput(format!(
+                "unknown lockout scope '{}', expected ip or username", other
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for LockoutScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Ip => "ip",
+            Self::Username => "username",
+        })
+    }
+}
+
+// Recent failed logins of one client IP or username. `blocked_until` is set
+// while the next attempt has to wait, `locked` once the failure limit is hit
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginLockout {
+    pub scope: LockoutScope,
+    pub subject: String,
+    pub failures: u32,
+    pub last_failure_at: DateTime<Utc>,
+    pub blocked_until: Option<DateTime<Utc>>,
+    pub locked: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthUser {
     pub username: String,
+    pub role: Role,
     pub is_authenticated: bool,
 }
 
 #[allow(dead_code)]
 impl AuthUser {
-    pub fn new(username: String) -> Self {
+    pub fn new(username: String, role: Role) -> Self {
         Self {
             username,
+            role,
             is_authenticated: true,
         }
     }
@@ -70,15 +427,542 @@ impl AuthUser {
     pub fn anonymous() -> Self {
         Self {
             username: "anonymous".to_string(),
+            role: Role::Viewer,
             is_authenticated: false,
         }
     }
 }
 
+// Dashboard account kept in `.htpasswd`
+#[derive(Debug, Clone, Serialize)]
+pub struct LocalUser {
+    pub username: String,
+    pub role: Role,
+}
+
+// `role` is written to the roles file; without it the user gets default_role
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewLocalUser {
+    pub username: String,
+    pub password: String,
+    pub role: Option<Role>,
+}
+
+impl NewLocalUser {
+    pub fn validate(&self) -> Result<(), DomainError> {
+        validate_username(&self.username)?;
+        validate_password(&self.password)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PasswordReset {
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+// Kept to the characters that are safe in `.htpasswd` and `.roles` lines
+pub fn validate_username(username: &str) -> Result<(), DomainError> {
+    validate_text("username", username, 64)?;
+
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '@')) {
+        return Err(DomainError::InvalidInput(
+            "username may only contain letters, digits, '.', '_', '-' and '@'".to_string()
+        ));
+    }
+    Ok(())
+}
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+// bcrypt only uses the first 72 bytes, longer passwords are refused rather
+// than silently cut
+pub fn validate_password(password: &str) -> Result<(), DomainError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(DomainError::InvalidInput(format!(
+            "password must be at least {} characters", MIN_PASSWORD_LENGTH
+        )));
+    }
+    if password.len() > 72 {
+        return Err(DomainError::InvalidInput("password must be at most 72 bytes".to_string()));
+    }
+    Ok(())
+}
+
+// What an API token may do: read maps to the viewer role, write to operator
+// and admin, which has to be asked for explicitly, to admin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl TokenScope {
+    pub fn role(self) -> Role {
+        match self {
+            Self::Read => Role::Viewer,
+            Self::Write => Role::Operator,
+            Self::Admin => Role::Admin,
+        }
+    }
+}
+
+impl std::fmt::Display for TokenScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Admin => "admin",
+        })
+    }
+}
+
+// Issued API token; only the hash of the secret value is ever stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub scope: TokenScope,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewApiToken {
+    pub name: String,
+    pub scope: TokenScope,
+}
+
+impl NewApiToken {
+    pub fn validate(&self) -> Result<(), DomainError> {
+        validate_text("name", &self.name, 64)
+    }
+}
+
+// Second factor of a login user. The secret has to stay readable to check
+// codes, recovery codes are kept as hashes and removed once used
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpRecord {
+    pub username: String,
+    pub secret: String,
+    pub enabled: bool,
+    pub recovery_code_hashes: Vec<String>,
+    pub last_used_step: Option<u64>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Shown once when enrollment starts, before the first code confirms it
+#[derive(Debug, Clone, Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+    pub qr_svg: String,
+    pub recovery_codes: Vec<String>,
+}
+
+// Logins and administrative actions recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    ServiceStarted,
+    ConfigReloaded,
+    Login,
+    LoginFailed,
+    Logout,
+    UpdateTriggered,
+    UserDisconnected,
+    SecretCreated,
+    SecretUpdated,
+    SecretEnabled,
+    SecretDisabled,
+    SecretDeleted,
+    TokenIssued,
+    TokenRevoked,
+    TotpEnabled,
+    TotpDisabled,
+    TotpReset,
+    LockoutCleared,
+    AccountCreated,
+    AccountDeleted,
+    PasswordReset,
+    PasswordChanged,
+    DeadLettersRedelivered,
+    DeadLettersCleared,
+    AlertSilenced,
+    AlertSilenceRemoved,
+}
+
+// An action as reported by the code that performed it, before it is given
+// its place in the log
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub actor: String,
+    pub ip: Option<IpAddr>,
+    pub action: AuditAction,
+    pub target: Option<String>,
+    pub success: bool,
+    pub detail: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(actor: impl Into<String>, ip: Option<IpAddr>, action: AuditAction) -> Self {
+        Self {
+            actor: actor.into(),
+            ip,
+            action,
+            target: None,
+            success: true,
+            detail: None,
+        }
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    // Adds to the detail given so far, separated by "; "
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        let detail = detail.into();
+        self.detail = Some(match self.detail.take() {
+            Some(previous) => format!("{}; {}", previous, detail),
+            None => detail,
+        });
+        self
+    }
+
+    pub fn failed(mut self) -> Self {
+        self.success = false;
+        self
+    }
+
+    // Marks the record failed with the error message when the action failed
+    pub fn result<T>(self, result: &Result<T, DomainError>) -> Self {
+        match result {
+            Ok(_) => self,
+            Err(e) => self.failed().detail(e.to_string()),
+        }
+    }
+}
+
+// One line of the audit log. `hash` is the SHA-256 of the line without its
+// `hash` member and covers `prev_hash`, the hash of the line before, so
+// editing or removing a line breaks the chain from there on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub actor: String,
+    pub ip: Option<IpAddr>,
+    pub action: AuditAction,
+    pub target: Option<String>,
+    pub success: bool,
+    pub detail: Option<String>,
+    pub prev_hash: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+// Filters of GET /api/audit; every given field has to match
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<AuditAction>,
+    pub target: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub success: Option<bool>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.actor.as_ref().is_none_or(|actor| &entry.actor == actor)
+            && self.action.is_none_or(|action| entry.action == action)
+            && self.target.as_ref().is_none_or(|target| entry.target.as_ref() == Some(target))
+            && self.ip.is_none_or(|ip| entry.ip == Some(ip))
+            && self.success.is_none_or(|success| entry.success == success)
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp < to)
+    }
+}
+
+// Result of re-checking the hash chain of the whole log
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditVerification {
+    pub valid: bool,
+    pub entries: u64,
+    pub last_hash: String,
+    // Number of the first line (from 1) that does not match, and why
+    pub broken_at_line: Option<u64>,
+    pub error: Option<String>,
+}
+
+// Why a session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    // Gone from the router's active connections
+    SessionEnded,
+    // Disconnected through the dashboard or API
+    ManualDisconnect,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SessionEnded => "session_ended",
+            Self::ManualDisconnect => "manual_disconnect",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserConnected {
+    pub user: VpnUser,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl UserConnected {
+    pub fn new(user: VpnUser) -> Self {
+        Self {
+            user,
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+// `user` as last seen; `final_uptime` is its uptime at the last poll
+#[derive(Debug, Clone, Serialize)]
+pub struct UserDisconnected {
+    pub final_uptime: String,
+    pub user: VpnUser,
+    pub reason: DisconnectReason,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl UserDisconnected {
+    pub fn new(user: VpnUser, reason: DisconnectReason) -> Self {
+        Self {
+            final_uptime: user.uptime.clone(),
+            user,
+            reason,
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserFieldChange {
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+// A session that stayed connected but got another address or caller ID
+#[derive(Debug, Clone, Serialize)]
+pub struct UserChanged {
+    pub user: VpnUser,
+    pub changes: Vec<UserFieldChange>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl UserChanged {
+    pub fn between(previous: &VpnUser, current: &VpnUser) -> Option<Self> {
+        let fields = [
+            ("address", Some(&previous.address), Some(&current.address)),
+            ("caller_id", previous.caller_id.as_ref(), current.caller_id.as_ref()),
+        ];
+        let changes: Vec<UserFieldChange> = fields
+            .into_iter()
+            .filter(|(_, old, new)| old != new)
+            .map(|(field, old, new)| UserFieldChange {
+                field: field.to_string(),
+                old: old.cloned(),
+                new: new.cloned(),
+            })
+            .collect();
+
+        (!changes.is_empty()).then(|| Self {
+            user: current.clone(),
+            changes,
+            timestamp: Utc::now(),
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LatencyUpdate {
     pub user_name: String,
+    pub router: Option<String>,
+    pub session_id: Option<String>,
     pub latency: Option<f64>,
+    pub stats: LatencyStats,
+}
+
+impl LatencyUpdate {
+    pub fn key(&self) -> UserKey {
+        UserKey::new(self.router.as_deref(), &self.user_name)
+    }
+
+    pub fn session_key(&self) -> SessionKey {
+        SessionKey::new(self.router.as_deref(), self.session_id.as_deref(), &self.user_name)
+    }
+}
+
+// Round-trip statistics over the sliding window of recent probes
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LatencyStats {
+    pub min: Option<f64>,
+    pub avg: Option<f64>,
+    pub max: Option<f64>,
+    pub jitter: Option<f64>,
+    pub loss_percent: f64,
+    pub samples: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct LatencyWindow {
+    capacity: usize,
+    samples: VecDeque<Option<f64>>,
+    jitter: Option<f64>,
+    last_rtt: Option<f64>,
+}
+
+impl LatencyWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            samples: VecDeque::new(),
+            jitter: None,
+            last_rtt: None,
+        }
+    }
+
+    pub fn push(&mut self, latency: Option<f64>) -> LatencyStats {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+
+        // Interarrival jitter estimator from RFC 3550, section 6.4.1,
+        // applied to consecutive answered probes
+        if let Some(rtt) = latency {
+            if let Some(previous) = self.last_rtt {
+                let jitter = self.jitter.unwrap_or(0.0);
+                self.jitter = Some(jitter + ((rtt - previous).abs() - jitter) / 16.0);
+            }
+            self.last_rtt = Some(rtt);
+        }
+
+        self.stats()
+    }
+
+    pub fn stats(&self) -> LatencyStats {
+        let answered: Vec<f64> = self.samples.iter().flatten().copied().collect();
+        let lost = self.samples.len() - answered.len();
+
+        LatencyStats {
+            min: answered.iter().copied().reduce(f64::min),
+            avg: (!answered.is_empty()).then(|| answered.iter().sum::<f64>() / answered.len() as f64),
+            max: answered.iter().copied().reduce(f64::max),
+            jitter: self.jitter,
+            loss_percent: if self.samples.is_empty() {
+                0.0
+            } else {
+                lost as f64 * 100.0 / self.samples.len() as f64
+            },
+            samples: self.samples.len(),
+        }
+    }
+}
+
+// How a user's reachability is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProbeMethod {
+    // Raw-socket ICMP echo, needs CAP_NET_RAW
+    Icmp,
+    // ICMP echo over an unprivileged datagram socket (net.ipv4.ping_group_range)
+    IcmpDgram,
+    // TCP connect, a refused connection still proves the host is up
+    Tcp,
+}
+
+impl FromStr for ProbeMethod {
+    type Err = DomainError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "icmp" => Ok(Self::Icmp),
+            "icmp-dgram" => Ok(Self::IcmpDgram),
+            "tcp" => Ok(Self::Tcp),
+            other => Err(DomainError::InvalidInput(format!(
+                "unknown probe method '{}', expected icmp, icmp-dgram or tcp", other
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeTarget {
+    pub method: ProbeMethod,
+    pub port: u16,
+}
+
+impl ProbeTarget {
+    // Accepts "icmp", "icmp-dgram", "tcp" or "tcp:<port>"
+    pub fn parse(value: &str, default_tcp_port: u16) -> Result<Self, DomainError> {
+        let (method, port) = match value.split_once(':') {
+            Some((method, port)) => (method, Some(port)),
+            None => (value, None),
+        };
+        let method = ProbeMethod::from_str(method)?;
+
+        let port = match (method, port) {
+            (ProbeMethod::Tcp, Some(port)) => port
+                .parse::<u16>()
+                .ok()
+                .filter(|port| *port != 0)
+                .ok_or_else(|| DomainError::InvalidInput(format!("invalid probe port '{}'", port)))?,
+            (ProbeMethod::Tcp, None) => default_tcp_port,
+            (_, Some(_)) => {
+                return Err(DomainError::InvalidInput(format!(
+                    "probe method '{}' does not take a port", value
+                )));
+            }
+            (_, None) => 0,
+        };
+
+        Ok(Self { method, port })
+    }
+}
+
+// Aggregated latency over one bucket of a time series
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyPoint {
+    pub timestamp: DateTime<Utc>,
+    pub min: Option<f64>,
+    pub avg: Option<f64>,
+    pub max: Option<f64>,
+    pub loss_percent: f64,
+    pub samples: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct LatencyRetention {
+    pub raw_seconds: u64,
+    pub minute_seconds: u64,
+    pub hour_seconds: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -95,19 +979,378 @@ impl WebSocketMessage {
         }
     }
 
-    pub fn latency_update(user_name: String, latency: Option<f64>) -> Self {
+    pub fn user_connected(event: &UserConnected) -> Self {
+        Self {
+            message_type: "user_connected".to_string(),
+            data: serde_json::to_value(event).unwrap_or_default(),
+        }
+    }
+
+    pub fn user_disconnected(event: &UserDisconnected) -> Self {
+        Self {
+            message_type: "user_disconnected".to_string(),
+            data: serde_json::to_value(event).unwrap_or_default(),
+        }
+    }
+
+    pub fn user_changed(event: &UserChanged) -> Self {
+        Self {
+            message_type: "user_changed".to_string(),
+            data: serde_json::to_value(event).unwrap_or_default(),
+        }
+    }
+
+    pub fn latency_update(update: LatencyUpdate) -> Self {
         Self {
             message_type: "latency".to_string(),
             data: serde_json::json!({
-                "name": user_name,
-                "latency": latency
+                "name": update.user_name,
+                "router": update.router,
+                "session_id": update.session_id,
+                "latency": update.latency,
+                "stats": update.stats
             }),
         }
     }
 }
 
+// Where login passwords are checked; `auth_backends` lists them in the order
+// they are tried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthBackend {
+    Htpasswd,
+    Ldap,
+}
+
+impl FromStr for AuthBackend {
+    type Err = DomainError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "htpasswd" => Ok(Self::Htpasswd),
+            "ldap" => Ok(Self::Ldap),
+            other => Err(DomainError::InvalidInput(format!(
+                "unknown auth backend '{}', expected htpasswd or ldap", other
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for AuthBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Htpasswd => "htpasswd",
+            Self::Ldap => "ldap",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LdapGroupRole {
+    pub group: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+    pub url: String,
+    pub starttls: bool,
+    pub tls_ca_file: String,
+    pub tls_insecure: bool,
+    pub bind_dn: String,
+    pub bind_password: String,
+    pub base_dn: String,
+    pub user_filter: String,
+    pub group_attribute: String,
+    pub group_roles: Vec<LdapGroupRole>,
+    pub default_role: Option<Role>,
+    pub timeout_seconds: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcClaimRole {
+    pub value: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    pub username_claim: String,
+    pub role_claim: String,
+    pub claim_roles: Vec<OidcClaimRole>,
+    pub default_role: Option<Role>,
+    pub display_name: String,
+    pub timeout_seconds: u64,
+}
+
+// An API client allowed in by a client certificate with this subject common name
+#[derive(Debug, Clone)]
+pub struct TlsClientRole {
+    pub common_name: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub port: u16,
+    pub cert_file: String,
+    pub key_file: String,
+    pub reload_interval_seconds: u64,
+    pub redirect_http: bool,
+    pub client_ca_file: String,
+    pub client_roles: Vec<TlsClientRole>,
+}
+
+// Events a webhook endpoint can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WebhookEventKind {
+    #[serde(rename = "user.connected")]
+    UserConnected,
+    #[serde(rename = "user.disconnected")]
+    UserDisconnected,
+    #[serde(rename = "user.changed")]
+    UserChanged,
+    #[serde(rename = "latency")]
+    Latency,
+    #[serde(rename = "alert")]
+    Alert,
+}
+
+impl WebhookEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UserConnected => "user.connected",
+            Self::UserDisconnected => "user.disconnected",
+            Self::UserChanged => "user.changed",
+            Self::Latency => "latency",
+            Self::Alert => "alert",
+        }
+    }
+}
+
+impl FromStr for WebhookEventKind {
+    type Err = DomainError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "user.connected" => Ok(Self::UserConnected),
+            "user.disconnected" => Ok(Self::UserDisconnected),
+            "user.changed" => Ok(Self::UserChanged),
+            "latency" => Ok(Self::Latency),
+            "alert" => Ok(Self::Alert),
+            other => Err(DomainError::InvalidInput(format!(
+                "unknown webhook event '{}', expected user.connected, user.disconnected, user.changed, latency or alert", other
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub name: String,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<WebhookEventKind>,
+    pub timeout_seconds: u64,
+    pub max_attempts: u32,
+    pub retry_base_seconds: u64,
+}
+
+// Body POSTed to webhook endpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: WebhookEventKind,
+    pub timestamp: DateTime<Utc>,
+    pub data: serde_json::Value,
+}
+
+impl WebhookEvent {
+    pub fn new(kind: WebhookEventKind, data: serde_json::Value) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            kind,
+            timestamp: Utc::now(),
+            data,
+        }
+    }
+}
+
+// A webhook delivery that failed every attempt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub webhook: String,
+    pub failed_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub error: String,
+    pub event: WebhookEvent,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RedeliveryResult {
+    pub delivered: usize,
+    pub failed: usize,
+}
+
+// What an alert rule compares against its threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertRuleKind {
+    // Round-trip time of the last answered probe, in milliseconds
+    Latency,
+    // Unanswered probes over the ping window, in percent
+    PacketLoss,
+    // A user is no longer connected, no value
+    Disconnect,
+    // How far the session count is below its recent peak, in percent
+    SessionDrop,
+}
+
+impl FromStr for AlertRuleKind {
+    type Err = DomainError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "latency" => Ok(Self::Latency),
+            "packet_loss" => Ok(Self::PacketLoss),
+            "disconnect" => Ok(Self::Disconnect),
+            "session_drop" => Ok(Self::SessionDrop),
+            other => Err(DomainError::InvalidInput(format!(
+                "unknown alert type '{}', expected latency, packet_loss, disconnect or session_drop", other
+            ))),
+        }
+    }
+}
+
+// An `[[alerts]]` rule. It fires once its value has stayed at or above
+// `threshold` for `for_seconds`, and resolves when the value falls below
+// `clear`, which may be lower to keep a value around the threshold from flapping
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertRule {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: AlertRuleKind,
+    pub severity: String,
+    pub users: Vec<String>,
+    pub comment: Option<String>,
+    pub threshold: f64,
+    pub clear: f64,
+    pub for_seconds: u64,
+    pub window_seconds: u64,
+}
+
+impl AlertRule {
+    // Users named in `users` or whose PPP comment contains `comment`; every
+    // user when neither is set
+    pub fn matches(&self, user: &VpnUser) -> bool {
+        if self.users.is_empty() && self.comment.is_none() {
+            return true;
+        }
+        self.users.contains(&user.name)
+            || self.comment.as_deref().is_some_and(|comment| {
+                user.comment.as_deref().is_some_and(|user_comment| user_comment.contains(comment))
+            })
+    }
+}
+
+// Ordered by urgency, firing alerts are listed first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Firing,
+    Pending,
+    Resolved,
+}
+
+// An alert of a rule, per user except for session_drop rules
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub rule: String,
+    #[serde(rename = "type")]
+    pub kind: AlertRuleKind,
+    pub severity: String,
+    pub user: Option<String>,
+    pub router: Option<String>,
+    pub state: AlertState,
+    pub value: Option<f64>,
+    pub threshold: f64,
+    pub detail: Option<String>,
+    // When the condition started to hold
+    pub since: DateTime<Utc>,
+    pub fired_at: Option<DateTime<Utc>>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub silenced: bool,
+}
+
+// Keeps matching alerts from being notified until it expires. Without a
+// rule or user it matches every rule or user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertSilence {
+    pub id: String,
+    pub rule: Option<String>,
+    pub user: Option<String>,
+    pub comment: String,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl AlertSilence {
+    pub fn matches(&self, alert: &Alert, now: DateTime<Utc>) -> bool {
+        self.expires_at > now
+            && self.rule.as_ref().is_none_or(|rule| *rule == alert.rule)
+            && self.user.as_ref().is_none_or(|user| alert.user.as_ref() == Some(user))
+    }
+}
+
+// Longest silence, a forgotten one should not hide alerts forever
+pub const MAX_SILENCE_MINUTES: u64 = 30 * 24 * 60;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewAlertSilence {
+    pub rule: Option<String>,
+    pub user: Option<String>,
+    pub duration_minutes: u64,
+    pub comment: String,
+}
+
+impl NewAlertSilence {
+    pub fn validate(&self) -> Result<(), DomainError> {
+        if let Some(rule) = &self.rule {
+            validate_text("rule", rule, 64)?;
+        }
+        if let Some(user) = &self.user {
+            validate_text("user", user, 64)?;
+        }
+        validate_text("comment", &self.comment, 256)?;
+        if !(1..=MAX_SILENCE_MINUTES).contains(&self.duration_minutes) {
+            return Err(DomainError::InvalidInput(format!(
+                "duration_minutes must be between 1 and {}", MAX_SILENCE_MINUTES
+            )));
+        }
+        Ok(())
+    }
+}
+
+// An authorization request sent to the identity provider, kept in the
+// session until the browser comes back to the callback
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcAuthorization {
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone)]
 pub struct MikrotikConfig {
+    pub name: String,
     pub protocol: String,
     pub address: String,
     pub port: u16,
@@ -122,6 +1365,66 @@ impl MikrotikConfig {
     }
 }
 
+// Health of one component of /readyz, and of the service as the worst of
+// them. Only `Down` makes the service unready
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Degraded,
+    Down,
+}
+
+// Outcome of the last poll of one router. `reachable` and `authenticated`
+// stay None until they are known; a login refused by the router means it was
+// reachable
+#[derive(Debug, Clone, Serialize)]
+pub struct RouterStatus {
+    pub name: String,
+    pub status: HealthStatus,
+    pub reachable: Option<bool>,
+    pub authenticated: Option<bool>,
+    pub checked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeHealth {
+    pub method: ProbeMethod,
+    pub available: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SchedulerHealth {
+    pub status: HealthStatus,
+    pub last_cycle_at: Option<DateTime<Utc>>,
+    pub last_success_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserListHealth {
+    pub status: HealthStatus,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub age_seconds: Option<i64>,
+    pub max_age_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PingerHealth {
+    pub status: HealthStatus,
+    pub probes: Vec<ProbeHealth>,
+}
+
+// Body of /readyz
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadinessReport {
+    pub status: HealthStatus,
+    pub checked_at: DateTime<Utc>,
+    pub scheduler: SchedulerHealth,
+    pub user_list: UserListHealth,
+    pub pinger: PingerHealth,
+    pub routers: Vec<RouterStatus>,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct AppConfig {
@@ -131,6 +1434,57 @@ pub struct AppConfig {
     pub static_files_path: String,
     pub session_secret: String,
     pub ping_interval_seconds: u64,
+    pub storage_backend: String,
+    pub database_path: String,
+    pub latency_raw_retention_minutes: u64,
+    pub latency_minute_retention_hours: u64,
+    pub latency_hour_retention_days: u64,
+    pub ping_timeout_ms: u64,
+    pub ping_concurrency: usize,
+    pub ping_window_size: usize,
+    pub probe_method: ProbeMethod,
+    pub probe_tcp_port: u16,
+    pub htpasswd_path: String,
+    pub roles_file: String,
+    pub default_role: Role,
+    pub api_tokens_file: String,
+    pub session_key_file: String,
+    pub cookie_secure: bool,
+    pub cookie_same_site: String,
+    pub trusted_origins: Vec<String>,
+    pub session_max_age_minutes: u64,
+    pub session_idle_timeout_minutes: u64,
+    pub totp_file: String,
+    pub totp_issuer: String,
+    pub totp_required_roles: Vec<Role>,
+    pub login_max_failures: u32,
+    pub login_max_failures_per_ip: u32,
+    pub login_lockout_minutes: u64,
+    pub login_backoff_seconds: u64,
+    pub auth_backends: Vec<AuthBackend>,
+    pub audit_log_file: String,
+    pub webhook_dead_letter_file: String,
+    pub metrics_user_series_limit: usize,
+    pub readiness_max_user_list_age_seconds: u64,
+    pub alert_silences_file: String,
+    pub alert_resolved_retention_minutes: u64,
+}
+
+impl AppConfig {
+    pub fn default_probe(&self) -> ProbeTarget {
+        ProbeTarget {
+            method: self.probe_method,
+            port: self.probe_tcp_port,
+        }
+    }
+
+    pub fn latency_retention(&self) -> LatencyRetention {
+        LatencyRetention {
+            raw_seconds: self.latency_raw_retention_minutes * 60,
+            minute_seconds: self.latency_minute_retention_hours * 3600,
+            hour_seconds: self.latency_hour_retention_days * 86400,
+        }
+    }
 }
 
 impl Default for AppConfig {
@@ -142,6 +1496,40 @@ impl Default for AppConfig {
             static_files_path: "./asset".to_string(),
             session_secret: "change-me-in-production".to_string(),
             ping_interval_seconds: 2,
+            storage_backend: "memory".to_string(),
+            database_path: "mikriting.db".to_string(),
+            latency_raw_retention_minutes: 60,
+            latency_minute_retention_hours: 24,
+            latency_hour_retention_days: 30,
+            ping_timeout_ms: 1000,
+            ping_concurrency: 32,
+            ping_window_size: 20,
+            probe_method: ProbeMethod::Icmp,
+            probe_tcp_port: 80,
+            htpasswd_path: ".htpasswd".to_string(),
+            roles_file: ".roles".to_string(),
+            default_role: Role::Viewer,
+            api_tokens_file: ".api_tokens.json".to_string(),
+            session_key_file: String::new(),
+            cookie_secure: false,
+            cookie_same_site: "lax".to_string(),
+            trusted_origins: Vec::new(),
+            session_max_age_minutes: 720,
+            session_idle_timeout_minutes: 60,
+            totp_file: ".totp.json".to_string(),
+            totp_issuer: "mikriting-tool".to_string(),
+            totp_required_roles: Vec::new(),
+            login_max_failures: 5,
+            login_max_failures_per_ip: 20,
+            login_lockout_minutes: 15,
+            login_backoff_seconds: 1,
+            auth_backends: vec![AuthBackend::Htpasswd],
+            audit_log_file: "audit.jsonl".to_string(),
+            webhook_dead_letter_file: "webhook_dead_letters.json".to_string(),
+            metrics_user_series_limit: 500,
+            readiness_max_user_list_age_seconds: 60,
+            alert_silences_file: "alert_silences.json".to_string(),
+            alert_resolved_retention_minutes: 60,
         }
     }
 }
@@ -165,4 +1553,31 @@ pub enum DomainError {
     
     #[error("Serialization error: {0}")]
     SerializationError(String),
+    
+    #[error("PPP secret not found: {0}")]
+    SecretNotFound(String),
+    
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    
+    #[error("Router rejected the request: {0}")]
+    RouterRejected(String),
+    
+    #[error("Not found on the router: {0}")]
+    RouterItemNotFound(String),
+    
+    #[error("Storage error: {0}")]
+    StorageError(String),
+    
+    #[error("API token not found: {0}")]
+    TokenNotFound(String),
+    
+    #[error("Too many login attempts, retry in {0} seconds")]
+    TooManyAttempts(u64),
+    
+    #[error("Already exists: {0}")]
+    AlreadyExists(String),
+    
+    #[error("Alert silence not found: {0}")]
+    SilenceNotFound(String),
 }
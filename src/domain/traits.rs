@@ -1,5 +1,10 @@
 use async_trait::async_trait;
-use crate::domain::models::{VpnUser, AuthUser, LatencyUpdate, DomainError};
+use chrono::{DateTime, Utc};
+use std::net::IpAddr;
+use std::time::Duration;
+use crate::domain::models::{
+    VpnUser, VpnSession, UserKey, SessionKey, AuthUser, LocalUser, Role, ApiToken, TotpRecord, AuditRecord, AuditEntry, AuditQuery, AuditVerification, DeadLetter, RedeliveryResult, LoginLockout, LockoutScope, OidcAuthorization, LatencyUpdate, LatencyPoint, ProbeHealth, RouterStatus, PppSecret, NewPppSecret, PppSecretUpdate, UserConnected, UserDisconnected, UserChanged, Alert, AlertRule, AlertSilence, DomainError,
+};
 
 // Repository traits for data persistence
 #[allow(dead_code)]
@@ -8,8 +13,10 @@ pub trait VpnUserRepository {
     async fn find_all(&self) -> Result<Vec<VpnUser>, DomainError>;
     async fn find_by_name(&self, name: &str) -> Result<Option<VpnUser>, DomainError>;
     async fn save(&self, user: &VpnUser) -> Result<(), DomainError>;
-    async fn delete(&self, name: &str) -> Result<(), DomainError>;
+    // Ends the open session `user` was last seen in
+    async fn delete(&self, user: &VpnUser) -> Result<(), DomainError>;
     async fn update_latency(&self, name: &str, latency: Option<f64>) -> Result<(), DomainError>;
+    async fn find_sessions(&self, name: &str, limit: usize) -> Result<Vec<VpnSession>, DomainError>;
 }
 
 #[allow(dead_code)]
@@ -19,12 +26,91 @@ pub trait AuthRepository {
     async fn find_by_username(&self, username: &str) -> Result<Option<AuthUser>, DomainError>;
 }
 
+// Dashboard accounts in `.htpasswd`, with their roles in the roles file.
+// Password hashes are made by the caller
+#[async_trait]
+pub trait LocalUserRepository {
+    async fn list(&self) -> Result<Vec<LocalUser>, DomainError>;
+    async fn create(&self, username: &str, password_hash: &str, role: Option<Role>) -> Result<(), DomainError>;
+    async fn set_password(&self, username: &str, password_hash: &str) -> Result<(), DomainError>;
+    async fn delete(&self, username: &str) -> Result<(), DomainError>;
+    async fn check_password(&self, username: &str, password: &str) -> Result<bool, DomainError>;
+    // Changes whenever the password of `username` does, None when there is no
+    // such account. Kept in sessions to end them once it no longer matches
+    async fn credential_stamp(&self, username: &str) -> Result<Option<String>, DomainError>;
+}
+
+// Failed login bookkeeping per client IP and per username
+#[async_trait]
+pub trait LoginThrottle {
+    // Seconds the next attempt from this IP or for this username has to wait
+    async fn retry_after(&self, ip: IpAddr, username: &str) -> Result<Option<u64>, DomainError>;
+    async fn record_failure(&self, ip: IpAddr, username: &str) -> Result<(), DomainError>;
+    async fn record_success(&self, username: &str) -> Result<(), DomainError>;
+    async fn list(&self) -> Result<Vec<LoginLockout>, DomainError>;
+    async fn clear(&self, scope: LockoutScope, subject: &str) -> Result<bool, DomainError>;
+}
+
+// Single sign-on through an OpenID Connect identity provider
+#[async_trait]
+pub trait OidcProvider {
+    fn display_name(&self) -> &str;
+    // URL to send the browser to, and the request to check the callback against
+    async fn authorization_url(&self) -> Result<(String, OidcAuthorization), DomainError>;
+    async fn exchange_code(&self, code: &str, request: &OidcAuthorization) -> Result<AuthUser, DomainError>;
+}
+
+// API tokens are looked up by the SHA-256 hash of their secret value
+#[async_trait]
+pub trait ApiTokenRepository {
+    async fn list(&self) -> Result<Vec<ApiToken>, DomainError>;
+    async fn create(&self, token: &ApiToken, token_hash: &str) -> Result<(), DomainError>;
+    async fn revoke(&self, id: &str) -> Result<(), DomainError>;
+    async fn find_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>, DomainError>;
+    async fn touch(&self, id: &str, used_at: DateTime<Utc>) -> Result<(), DomainError>;
+}
+
+#[async_trait]
+pub trait TotpRepository {
+    async fn find(&self, username: &str) -> Result<Option<TotpRecord>, DomainError>;
+    async fn save(&self, record: &TotpRecord) -> Result<(), DomainError>;
+    async fn delete(&self, username: &str) -> Result<bool, DomainError>;
+    // Compare-and-set of the last used time step: records `step` unless it is
+    // not later than the one recorded, so a code is accepted only once
+    async fn use_step(&self, username: &str, step: u64) -> Result<bool, DomainError>;
+    // Removes the recovery code with this hash, returning how many are left
+    // when it was there
+    async fn use_recovery_code(&self, username: &str, hash: &str) -> Result<Option<usize>, DomainError>;
+}
+
+// Append-only, hash-chained record of logins and administrative actions
+#[async_trait]
+pub trait AuditLog {
+    async fn append(&self, record: AuditRecord) -> Result<AuditEntry, DomainError>;
+    // Matching entries, newest first
+    async fn query(&self, query: &AuditQuery, limit: usize) -> Result<Vec<AuditEntry>, DomainError>;
+    // The log file as written, for archiving
+    async fn export(&self) -> Result<Vec<u8>, DomainError>;
+    async fn verify(&self) -> Result<AuditVerification, DomainError>;
+}
+
 // External service interfaces
 #[allow(dead_code)]
 #[async_trait]
 pub trait MikrotikService {
     async fn fetch_active_connections(&self) -> Result<Vec<VpnUser>, DomainError>;
-    async fn disconnect_user(&self, user_name: &str) -> Result<(), DomainError>;
+    // Without `router` the user must be connected to exactly one router
+    async fn disconnect_user(&self, router: Option<&str>, user_name: &str) -> Result<(), DomainError>;
+}
+
+// PPP secret management; `router` selects the target when several routers are configured
+#[async_trait]
+pub trait PppSecretService {
+    async fn list_secrets(&self, router: Option<&str>) -> Result<Vec<PppSecret>, DomainError>;
+    async fn create_secret(&self, router: Option<&str>, secret: &NewPppSecret) -> Result<PppSecret, DomainError>;
+    async fn update_secret(&self, router: Option<&str>, name: &str, update: &PppSecretUpdate) -> Result<PppSecret, DomainError>;
+    async fn set_secret_disabled(&self, router: Option<&str>, name: &str, disabled: bool) -> Result<PppSecret, DomainError>;
+    async fn delete_secret(&self, router: Option<&str>, name: &str) -> Result<(), DomainError>;
 }
 
 #[allow(dead_code)]
@@ -32,7 +118,43 @@ pub trait MikrotikService {
 pub trait PingService {
     async fn ping_user(&self, user: &VpnUser) -> Result<Option<f64>, DomainError>;
     async fn start_monitoring(&self, user: &VpnUser) -> Result<(), DomainError>;
-    async fn stop_monitoring(&self, user_name: &str) -> Result<(), DomainError>;
+    async fn stop_monitoring(&self, session: &SessionKey) -> Result<(), DomainError>;
+    // Whether the probes of the default method and of monitored users can run
+    async fn probe_health(&self) -> Vec<ProbeHealth>;
+}
+
+// Single reachability check behind PingService; Ok(None) means no answer within the timeout
+#[async_trait]
+pub trait Probe {
+    async fn probe(&self, addr: IpAddr, port: u16, timeout: Duration) -> Result<Option<f64>, DomainError>;
+
+    // Fails when the probe cannot run on this host at all, such as without
+    // the socket permissions it needs
+    async fn check(&self) -> Result<(), DomainError> {
+        Ok(())
+    }
+}
+
+// Per-router outcome of the last poll, reported by /readyz
+#[async_trait]
+pub trait RouterStatusService {
+    async fn router_statuses(&self) -> Vec<RouterStatus>;
+}
+
+// Latency history per user and router. A query without a router reads the
+// only router that has history of the user
+#[async_trait]
+pub trait LatencyStore {
+    async fn record(&self, user: &UserKey, timestamp: DateTime<Utc>, latency: Option<f64>) -> Result<(), DomainError>;
+    async fn query(
+        &self,
+        router: Option<&str>,
+        user_name: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        step_seconds: u64,
+    ) -> Result<Vec<LatencyPoint>, DomainError>;
+    async fn prune(&self, now: DateTime<Utc>) -> Result<(), DomainError>;
 }
 
 // Event handling
@@ -40,6 +162,48 @@ pub trait PingService {
 pub trait EventPublisher {
     async fn publish_vpn_users_update(&self, users: Vec<VpnUser>) -> Result<(), DomainError>;
     async fn publish_latency_update(&self, update: LatencyUpdate) -> Result<(), DomainError>;
+    async fn publish_user_connected(&self, event: UserConnected) -> Result<(), DomainError>;
+    async fn publish_user_disconnected(&self, event: UserDisconnected) -> Result<(), DomainError>;
+    async fn publish_user_changed(&self, event: UserChanged) -> Result<(), DomainError>;
+}
+
+// Webhook deliveries that failed every attempt, kept until redelivered or cleared
+#[async_trait]
+pub trait DeadLetterQueue {
+    async fn list(&self) -> Result<Vec<DeadLetter>, DomainError>;
+    // Sends every entry once more and keeps those that fail again
+    async fn redeliver(&self) -> Result<RedeliveryResult, DomainError>;
+    async fn clear(&self) -> Result<usize, DomainError>;
+}
+
+// Delivers alerts that started firing or resolved, e.g. to the log or webhooks
+#[async_trait]
+pub trait AlertNotifier {
+    async fn notify(&self, alert: &Alert) -> Result<(), DomainError>;
+}
+
+// Alert rules evaluated against the published events, their current alerts
+// and the silences that keep alerts from being notified
+#[async_trait]
+pub trait AlertService {
+    async fn alerts(&self) -> Result<Vec<Alert>, DomainError>;
+    fn rules(&self) -> Vec<AlertRule>;
+    // Silences that have not expired yet
+    async fn silences(&self) -> Result<Vec<AlertSilence>, DomainError>;
+    async fn add_silence(&self, silence: AlertSilence) -> Result<(), DomainError>;
+    async fn remove_silence(&self, id: &str) -> Result<(), DomainError>;
+}
+
+// Operational metrics scraped from /metrics. Recording never fails and is
+// cheap enough for every router request; session and latency gauges are
+// taken from `users` when rendering
+pub trait MetricsRecorder {
+    // `error` is the kind of failure, None for a successful request
+    fn router_request(&self, router: &str, operation: &str, duration: Duration, error: Option<&str>);
+    fn scheduler_cycle(&self, duration: Duration, success: bool);
+    fn websocket_connections(&self, count: usize);
+    fn login(&self, method: &str, success: bool);
+    fn render(&self, users: &[VpnUser]) -> Result<String, DomainError>;
 }
 
 // Cache interface
@@ -48,13 +212,20 @@ pub trait EventPublisher {
 pub trait CacheService {
     async fn get_vpn_users(&self) -> Result<Option<Vec<VpnUser>>, DomainError>;
     async fn set_vpn_users(&self, users: Vec<VpnUser>) -> Result<(), DomainError>;
-    async fn get_user_latency(&self, user_name: &str) -> Result<Option<f64>, DomainError>;
-    async fn set_user_latency(&self, user_name: &str, latency: Option<f64>) -> Result<(), DomainError>;
-    async fn clear_user(&self, user_name: &str) -> Result<(), DomainError>;
+    // When the user list was last replaced by a poll, None before the first
+    async fn vpn_users_updated_at(&self) -> Result<Option<DateTime<Utc>>, DomainError>;
+    async fn get_user_latency(&self, session: &SessionKey) -> Result<Option<f64>, DomainError>;
+    async fn set_user_latency(&self, update: &LatencyUpdate) -> Result<(), DomainError>;
+    async fn clear_user(&self, session: &SessionKey) -> Result<(), DomainError>;
 }
 
 // Configuration interface
 pub trait ConfigService {
-    fn get_mikrotik_config(&self) -> Result<crate::domain::models::MikrotikConfig, DomainError>;
+    fn get_router_configs(&self) -> Result<Vec<crate::domain::models::MikrotikConfig>, DomainError>;
     fn get_app_config(&self) -> Result<crate::domain::models::AppConfig, DomainError>;
+    fn get_ldap_config(&self) -> Result<Option<crate::domain::models::LdapConfig>, DomainError>;
+    fn get_oidc_config(&self) -> Result<Option<crate::domain::models::OidcConfig>, DomainError>;
+    fn get_tls_config(&self) -> Result<Option<crate::domain::models::TlsConfig>, DomainError>;
+    fn get_webhook_configs(&self) -> Result<Vec<crate::domain::models::WebhookConfig>, DomainError>;
+    fn get_alert_rules(&self) -> Result<Vec<AlertRule>, DomainError>;
 }
@@ -0,0 +1,390 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use log::{debug, error, info, warn};
+
+use crate::domain::{
+    models::{
+        Alert, AlertRule, AlertRuleKind, AlertSilence, AlertState, AppConfig, LatencyUpdate, UserChanged, UserConnected,
+        UserDisconnected, UserKey, VpnUser, DomainError,
+    },
+    traits::{AlertNotifier, AlertService, EventPublisher},
+};
+use crate::infrastructure::json_file::{read_json_file, write_json_file};
+
+// Writes alerts to the application log
+pub struct LogAlertNotifier;
+
+#[async_trait]
+impl AlertNotifier for LogAlertNotifier {
+    async fn notify(&self, alert: &Alert) -> Result<(), DomainError> {
+        let user = alert.user.as_deref().map(|user| format!(" for {}", UserKey::new(alert.router.as_deref(), user))).unwrap_or_default();
+        let value = alert.value
+            .map(|value| format!(": {:.1} (threshold {})", value, alert.threshold))
+            .unwrap_or_default();
+        let detail = alert.detail.as_deref().map(|detail| format!(", {}", detail)).unwrap_or_default();
+
+        match alert.state {
+            AlertState::Firing => warn!("Alert {} ({}) firing{}{}{}", alert.rule, alert.severity, user, value, detail),
+            _ => info!("Alert {} resolved{}", alert.rule, user),
+        }
+        Ok(())
+    }
+}
+
+// Rule name and user
+type AlertKey = (String, Option<UserKey>);
+
+struct TrackedAlert {
+    alert: Alert,
+    // Session count a session_drop alert measures the drop from, fixed once
+    // it is pending so a lasting drop does not age out of the window
+    baseline: Option<usize>,
+}
+
+impl TrackedAlert {
+    fn is_active(&self) -> bool {
+        self.alert.state != AlertState::Resolved
+    }
+}
+
+fn fire_if_due(alert: &mut Alert, rule: &AlertRule, now: DateTime<Utc>, changed: &mut Vec<Alert>) {
+    if alert.state == AlertState::Pending && now - alert.since >= Duration::seconds(rule.for_seconds as i64) {
+        alert.state = AlertState::Firing;
+        alert.fired_at = Some(now);
+        changed.push(alert.clone());
+    }
+}
+
+#[derive(Default)]
+struct Evaluation {
+    alerts: HashMap<AlertKey, TrackedAlert>,
+    // Connected users, to match latency updates against the rules' users
+    users: HashMap<UserKey, VpnUser>,
+    // Session count of each poll within the longest session_drop window
+    session_counts: VecDeque<(DateTime<Utc>, usize)>,
+}
+
+impl Evaluation {
+    // Starting an alert takes the threshold, keeping it only `clear`
+    fn holds(&self, rule: &AlertRule, user: Option<&UserKey>, value: f64) -> bool {
+        let key = (rule.name.clone(), user.cloned());
+        let active = self.alerts.get(&key).is_some_and(TrackedAlert::is_active);
+        value >= if active { rule.clear } else { rule.threshold }
+    }
+
+    // Moves the alert of `rule` for `user` on, given whether its condition
+    // holds now. Alerts that started firing or resolved are added to `changed`
+    #[allow(clippy::too_many_arguments)]
+    fn update(
+        &mut self,
+        rule: &AlertRule,
+        user: Option<&UserKey>,
+        holds: bool,
+        value: Option<f64>,
+        detail: Option<String>,
+        now: DateTime<Utc>,
+        changed: &mut Vec<Alert>,
+    ) {
+        let key = (rule.name.clone(), user.cloned());
+        let active = self.alerts.get(&key).is_some_and(TrackedAlert::is_active);
+
+        match (active, holds) {
+            (false, true) => {
+                let mut alert = Alert {
+                    rule: rule.name.clone(),
+                    kind: rule.kind,
+                    severity: rule.severity.clone(),
+                    user: user.map(|user| user.name.clone()),
+                    router: user.and_then(|user| user.router.clone()),
+                    state: AlertState::Pending,
+                    value,
+                    threshold: rule.threshold,
+                    detail,
+                    since: now,
+                    fired_at: None,
+                    resolved_at: None,
+                    silenced: false,
+                };
+                fire_if_due(&mut alert, rule, now, changed);
+                self.alerts.insert(key, TrackedAlert { alert, baseline: None });
+            }
+            (true, true) => {
+                let Some(tracked) = self.alerts.get_mut(&key) else {
+                    return;
+                };
+                tracked.alert.value = value.or(tracked.alert.value);
+                tracked.alert.detail = detail.or(tracked.alert.detail.take());
+                fire_if_due(&mut tracked.alert, rule, now, changed);
+            }
+            (true, false) => {
+                let Some(tracked) = self.alerts.get_mut(&key) else {
+                    return;
+                };
+                // Pending alerts that stop holding never fired, nothing to resolve
+                if tracked.alert.state == AlertState::Pending {
+                    self.alerts.remove(&key);
+                    return;
+                }
+                tracked.alert.state = AlertState::Resolved;
+                tracked.alert.resolved_at = Some(now);
+                tracked.alert.value = value.or(tracked.alert.value);
+                changed.push(tracked.alert.clone());
+            }
+            (false, false) => {}
+        }
+    }
+
+    fn session_drop(&mut self, rule: &AlertRule, now: DateTime<Utc>, changed: &mut Vec<Alert>) {
+        let Some(&(_, count)) = self.session_counts.back() else {
+            return;
+        };
+        let key = (rule.name.clone(), None);
+        let window = Duration::seconds(rule.window_seconds as i64);
+
+        let baseline = match self.alerts.get(&key).filter(|tracked| tracked.is_active()) {
+            Some(tracked) => tracked.baseline.unwrap_or(count),
+            None => self.session_counts.iter()
+                .filter(|(at, _)| now - *at <= window)
+                .map(|(_, count)| *count)
+                .max()
+                .unwrap_or(count),
+        };
+        let drop = match baseline {
+            0 => 0.0,
+            baseline => baseline.saturating_sub(count) as f64 * 100.0 / baseline as f64,
+        };
+
+        let holds = self.holds(rule, None, drop);
+        let detail = format!("{} sessions, down from {}", count, baseline);
+        self.update(rule, None, holds, Some(drop), Some(detail), now, changed);
+        if let Some(tracked) = self.alerts.get_mut(&key).filter(|tracked| tracked.is_active()) {
+            tracked.baseline.get_or_insert(baseline);
+        }
+    }
+
+    // Fires pending alerts whose for_seconds have passed and forgets alerts
+    // resolved longer ago than `retention`
+    fn advance(&mut self, rules: &[AlertRule], now: DateTime<Utc>, retention: Duration, changed: &mut Vec<Alert>) {
+        for tracked in self.alerts.values_mut() {
+            if let Some(rule) = rules.iter().find(|rule| rule.name == tracked.alert.rule) {
+                fire_if_due(&mut tracked.alert, rule, now, changed);
+            }
+        }
+        self.alerts.retain(|_, tracked| tracked.alert.resolved_at.is_none_or(|resolved_at| now - resolved_at < retention));
+    }
+}
+
+// Evaluates the `[[alerts]]` rules against the events VpnUserUseCase
+// publishes: latency updates, user transitions and the user list of every
+// poll. Pending alerts are promoted on the next event after their
+// for_seconds, at the latest on the next poll
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    notifiers: Vec<Arc<dyn AlertNotifier + Send + Sync>>,
+    resolved_retention: Duration,
+    silences_path: PathBuf,
+    silences: Mutex<Vec<AlertSilence>>,
+    evaluation: Mutex<Evaluation>,
+}
+
+impl AlertEngine {
+    pub fn new(
+        rules: Vec<AlertRule>,
+        config: &AppConfig,
+        notifiers: Vec<Arc<dyn AlertNotifier + Send + Sync>>,
+    ) -> Result<Self, DomainError> {
+        let silences_path = PathBuf::from(&config.alert_silences_file);
+        let silences: Vec<AlertSilence> = read_json_file(&silences_path)?;
+
+        info!("Evaluating {} alert rules", rules.len());
+        Ok(Self {
+            rules,
+            notifiers,
+            resolved_retention: Duration::minutes(config.alert_resolved_retention_minutes as i64),
+            silences_path,
+            silences: Mutex::new(silences),
+            evaluation: Mutex::new(Evaluation::default()),
+        })
+    }
+
+    fn rules_of(&self, kind: AlertRuleKind) -> impl Iterator<Item = &AlertRule> {
+        self.rules.iter().filter(move |rule| rule.kind == kind)
+    }
+
+    // Runs `step` on the evaluation state, then notifies the alerts that
+    // started firing or resolved
+    async fn evaluate(&self, step: impl FnOnce(&mut Evaluation, DateTime<Utc>, &mut Vec<Alert>)) {
+        if self.rules.is_empty() {
+            return;
+        }
+
+        let now = Utc::now();
+        let mut changed = Vec::new();
+        {
+            let mut evaluation = self.evaluation.lock().await;
+            step(&mut evaluation, now, &mut changed);
+            evaluation.advance(&self.rules, now, self.resolved_retention, &mut changed);
+        }
+        self.notify(changed, now).await;
+    }
+
+    async fn notify(&self, changed: Vec<Alert>, now: DateTime<Utc>) {
+        if changed.is_empty() {
+            return;
+        }
+
+        let silences = self.silences.lock().await.clone();
+        for alert in changed {
+            if silences.iter().any(|silence| silence.matches(&alert, now)) {
+                debug!("Alert {} is silenced, not notified", alert.rule);
+                continue;
+            }
+            for notifier in &self.notifiers {
+                if let Err(e) = notifier.notify(&alert).await {
+                    error!("Failed to notify alert {}: {}", alert.rule, e);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EventPublisher for AlertEngine {
+    async fn publish_vpn_users_update(&self, users: Vec<VpnUser>) -> Result<(), DomainError> {
+        let keep = self.rules_of(AlertRuleKind::SessionDrop)
+            .map(|rule| Duration::seconds(rule.window_seconds as i64))
+            .max()
+            .unwrap_or_default();
+
+        self.evaluate(|evaluation, now, changed| {
+            evaluation.session_counts.push_back((now, users.len()));
+            while evaluation.session_counts.front().is_some_and(|(at, _)| now - *at > keep) {
+                evaluation.session_counts.pop_front();
+            }
+            evaluation.users = users.into_iter().map(|user| (user.key(), user)).collect();
+
+            for rule in self.rules_of(AlertRuleKind::SessionDrop) {
+                evaluation.session_drop(rule, now, changed);
+            }
+        }).await;
+        Ok(())
+    }
+
+    async fn publish_latency_update(&self, update: LatencyUpdate) -> Result<(), DomainError> {
+        self.evaluate(|evaluation, now, changed| {
+            let Some(user) = evaluation.users.get(&update.key()).cloned() else {
+                return;
+            };
+
+            for rule in self.rules.iter().filter(|rule| rule.matches(&user)) {
+                let value = match rule.kind {
+                    // A lost probe has no round-trip time and leaves the alert as it is
+                    AlertRuleKind::Latency => update.latency,
+                    AlertRuleKind::PacketLoss => Some(update.stats.loss_percent),
+                    AlertRuleKind::Disconnect | AlertRuleKind::SessionDrop => None,
+                };
+                if let Some(value) = value {
+                    let holds = evaluation.holds(rule, Some(&user.key()), value);
+                    evaluation.update(rule, Some(&user.key()), holds, Some(value), None, now, changed);
+                }
+            }
+        }).await;
+        Ok(())
+    }
+
+    async fn publish_user_connected(&self, event: UserConnected) -> Result<(), DomainError> {
+        self.evaluate(|evaluation, now, changed| {
+            for rule in self.rules_of(AlertRuleKind::Disconnect) {
+                evaluation.update(rule, Some(&event.user.key()), false, None, None, now, changed);
+            }
+            evaluation.users.insert(event.user.key(), event.user);
+        }).await;
+        Ok(())
+    }
+
+    async fn publish_user_disconnected(&self, event: UserDisconnected) -> Result<(), DomainError> {
+        self.evaluate(|evaluation, now, changed| {
+            let user = &event.user;
+            let key = user.key();
+            evaluation.users.remove(&key);
+
+            for rule in &self.rules {
+                match rule.kind {
+                    AlertRuleKind::Disconnect if rule.matches(user) => {
+                        let detail = format!("{}, uptime {}", event.reason.as_str(), event.final_uptime);
+                        evaluation.update(rule, Some(&key), true, None, Some(detail), now, changed);
+                    }
+                    // Without probes there is nothing left to alert on
+                    AlertRuleKind::Latency | AlertRuleKind::PacketLoss => {
+                        evaluation.update(rule, Some(&key), false, None, None, now, changed);
+                    }
+                    _ => {}
+                }
+            }
+        }).await;
+        Ok(())
+    }
+
+    async fn publish_user_changed(&self, event: UserChanged) -> Result<(), DomainError> {
+        self.evaluate(|evaluation, _, _| {
+            evaluation.users.insert(event.user.key(), event.user);
+        }).await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AlertService for AlertEngine {
+    async fn alerts(&self) -> Result<Vec<Alert>, DomainError> {
+        let now = Utc::now();
+        let silences = self.silences.lock().await.clone();
+        let evaluation = self.evaluation.lock().await;
+
+        let mut alerts: Vec<Alert> = evaluation.alerts.values()
+            .map(|tracked| {
+                let mut alert = tracked.alert.clone();
+                alert.silenced = silences.iter().any(|silence| silence.matches(&alert, now));
+                alert
+            })
+            .collect();
+        alerts.sort_by(|a, b| (a.state, &a.rule, &a.user, &a.router).cmp(&(b.state, &b.rule, &b.user, &b.router)));
+        Ok(alerts)
+    }
+
+    fn rules(&self) -> Vec<AlertRule> {
+        self.rules.clone()
+    }
+
+    async fn silences(&self) -> Result<Vec<AlertSilence>, DomainError> {
+        let now = Utc::now();
+        Ok(self.silences.lock().await
+            .iter()
+            .filter(|silence| silence.expires_at > now)
+            .cloned()
+            .collect())
+    }
+
+    // Expired silences are dropped from the file whenever it is rewritten
+    async fn add_silence(&self, silence: AlertSilence) -> Result<(), DomainError> {
+        let now = Utc::now();
+        let mut silences = self.silences.lock().await;
+        silences.retain(|silence| silence.expires_at > now);
+        silences.push(silence);
+        write_json_file(&self.silences_path, &*silences).await
+    }
+
+    async fn remove_silence(&self, id: &str) -> Result<(), DomainError> {
+        let now = Utc::now();
+        let mut silences = self.silences.lock().await;
+        let Some(index) = silences.iter().position(|silence| silence.id == id && silence.expires_at > now) else {
+            return Err(DomainError::SilenceNotFound(id.to_string()));
+        };
+        silences.remove(index);
+        silences.retain(|silence| silence.expires_at > now);
+        write_json_file(&self.silences_path, &*silences).await
+    }
+}
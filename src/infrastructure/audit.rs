@@ -0,0 +1,205 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+use tokio::task;
+use log::{debug, error, info};
+
+use crate::domain::{
+    models::{AuditEntry, AuditQuery, AuditRecord, AuditVerification, DomainError},
+    traits::AuditLog,
+};
+
+// `prev_hash` of the first line
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+struct ChainHead {
+    seq: u64,
+    hash: String,
+    // Set when the file ends in a partly written line, so the next entry
+    // starts on a line of its own
+    needs_newline: bool,
+}
+
+fn line_hash(body: &str) -> String {
+    hex::encode(Sha256::digest(body.as_bytes()))
+}
+
+// Serializes the entry without its hash, hashes exactly those bytes and
+// appends the hash as the last member. Checking a line only needs that
+// member cut off again, not a re-serialization
+fn seal(entry: &mut AuditEntry) -> Result<String, DomainError> {
+    entry.hash.clear();
+    let body = serde_json::to_string(entry)
+        .map_err(|e| DomainError::SerializationError(e.to_string()))?;
+    entry.hash = line_hash(&body);
+    Ok(format!("{},\"hash\":\"{}\"}}", &body[..body.len() - 1], entry.hash))
+}
+
+fn check_line(line: &str, prev_hash: &str, seq: u64) -> Result<AuditEntry, String> {
+    let entry: AuditEntry = serde_json::from_str(line).map_err(|e| format!("invalid entry: {}", e))?;
+    let Some(body) = line.strip_suffix(&format!(",\"hash\":\"{}\"}}", entry.hash)) else {
+        return Err("hash is missing or not the last member".to_string());
+    };
+
+    if line_hash(&format!("{}}}", body)) != entry.hash {
+        return Err("hash does not match the line".to_string());
+    }
+    if entry.prev_hash != prev_hash {
+        return Err("prev_hash does not match the line before".to_string());
+    }
+    if entry.seq != seq {
+        return Err(format!("expected seq {}, found {}", seq, entry.seq));
+    }
+    Ok(entry)
+}
+
+fn verify_lines(content: &str) -> AuditVerification {
+    let mut last_hash = GENESIS_HASH.to_string();
+    let mut entries = 0;
+
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index as u64 + 1;
+        match check_line(line, &last_hash, line_number) {
+            Ok(entry) => {
+                last_hash = entry.hash;
+                entries += 1;
+            }
+            Err(e) => {
+                return AuditVerification {
+                    valid: false,
+                    entries,
+                    last_hash,
+                    broken_at_line: Some(line_number),
+                    error: Some(e),
+                };
+            }
+        }
+    }
+
+    AuditVerification {
+        valid: true,
+        entries,
+        last_hash,
+        broken_at_line: None,
+        error: None,
+    }
+}
+
+fn read_log(path: &Path) -> Result<String, DomainError> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(DomainError::StorageError(format!("Failed to read {}: {}", path.display(), e))),
+    }
+}
+
+async fn read_log_async(path: &Path) -> Result<String, DomainError> {
+    let path = path.to_path_buf();
+    task::spawn_blocking(move || read_log(&path))
+        .await
+        .map_err(|e| DomainError::StorageError(format!("Audit log read task failed: {}", e)))?
+}
+
+// Audit trail as JSON lines, one entry per line, each chained to the one
+// before by its hash. Entries are only ever appended; queries read the file
+pub struct FileAuditLog {
+    path: PathBuf,
+    head: Mutex<ChainHead>,
+}
+
+impl FileAuditLog {
+    pub fn open(path: &str) -> Result<Self, DomainError> {
+        let path = PathBuf::from(path);
+        let content = read_log(&path)?;
+
+        // A broken chain does not stop the service, new entries continue
+        // after the last readable line and verification keeps reporting it
+        let verification = verify_lines(&content);
+        if let (Some(line), Some(e)) = (verification.broken_at_line, &verification.error) {
+            error!("Audit log {} fails verification at line {}: {}", path.display(), line, e);
+        }
+
+        let last = content.lines().rev().find_map(|line| serde_json::from_str::<AuditEntry>(line).ok());
+        let head = ChainHead {
+            seq: last.as_ref().map_or(0, |entry| entry.seq),
+            hash: last.map_or_else(|| GENESIS_HASH.to_string(), |entry| entry.hash),
+            needs_newline: !content.is_empty() && !content.ends_with('\n'),
+        };
+
+        info!("Audit log {} holds {} entries", path.display(), head.seq);
+        Ok(Self {
+            path,
+            head: Mutex::new(head),
+        })
+    }
+}
+
+#[async_trait]
+impl AuditLog for FileAuditLog {
+    async fn append(&self, record: AuditRecord) -> Result<AuditEntry, DomainError> {
+        let mut head = self.head.lock().await;
+        let mut entry = AuditEntry {
+            seq: head.seq + 1,
+            timestamp: Utc::now(),
+            actor: record.actor,
+            ip: record.ip,
+            action: record.action,
+            target: record.target,
+            success: record.success,
+            detail: record.detail,
+            prev_hash: head.hash.clone(),
+            hash: String::new(),
+        };
+
+        let mut line = seal(&mut entry)?;
+        line.push('\n');
+        if head.needs_newline {
+            line.insert(0, '\n');
+        }
+
+        let path = self.path.clone();
+        task::spawn_blocking(move || {
+            let mut options = fs::OpenOptions::new();
+            options.append(true).create(true);
+
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+            let mut file = options.open(&path)?;
+            file.write_all(line.as_bytes())?;
+            file.sync_data()
+        })
+        .await
+        .map_err(|e| DomainError::StorageError(format!("Audit log write task failed: {}", e)))?
+        .map_err(|e| DomainError::StorageError(format!("Failed to write {}: {}", self.path.display(), e)))?;
+
+        head.seq = entry.seq;
+        head.hash = entry.hash.clone();
+        head.needs_newline = false;
+        debug!("Audit log entry {}: {:?} by {}", entry.seq, entry.action, entry.actor);
+        Ok(entry)
+    }
+
+    async fn query(&self, query: &AuditQuery, limit: usize) -> Result<Vec<AuditEntry>, DomainError> {
+        let content = read_log_async(&self.path).await?;
+        Ok(content.lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+            .filter(|entry| query.matches(entry))
+            .take(limit)
+            .collect())
+    }
+
+    async fn export(&self) -> Result<Vec<u8>, DomainError> {
+        Ok(read_log_async(&self.path).await?.into_bytes())
+    }
+
+    async fn verify(&self) -> Result<AuditVerification, DomainError> {
+        let content = read_log_async(&self.path).await?;
+        Ok(verify_lines(&content))
+    }
+}
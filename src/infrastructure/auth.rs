@@ -1,27 +1,154 @@
 use async_trait::async_trait;
 use htpasswd_verify::Htpasswd;
+use std::collections::HashMap;
 use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
 use tokio::task;
-use log::{debug, error};
+use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use log::{debug, error, info, warn};
 
 use crate::domain::{
-    models::{AuthUser, DomainError},
-    traits::AuthRepository,
+    models::{AuthUser, LocalUser, Role, DomainError},
+    traits::{AuthRepository, LocalUserRepository},
 };
+use crate::infrastructure::json_file::write_file_atomically;
+
+fn read_optional(path: &str) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        result => result,
+    }
+}
+
+// `name:value` lines of `.htpasswd` and the roles file, skipping blank
+// lines and comments
+fn user_lines(content: &str) -> impl Iterator<Item = (&str, &str)> {
+    content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+}
+
+// Sets the line of `username` to `username:value`, or removes it when
+// `value` is None. Comments and the lines of other users are kept as they
+// are. Returns whether the user had a line
+fn replace_user_line(path: &str, username: &str, value: Option<&str>) -> io::Result<bool> {
+    let content = read_optional(path)?;
+    let mut lines: Vec<String> = Vec::new();
+    let mut found = false;
+
+    for line in content.lines() {
+        let is_user = !line.trim_start().starts_with('#')
+            && line.split_once(':').is_some_and(|(name, _)| name.trim() == username);
+        if !is_user {
+            lines.push(line.to_string());
+            continue;
+        }
+        if let (false, Some(value)) = (found, value) {
+            lines.push(format!("{}:{}", username, value));
+        }
+        found = true;
+    }
+    match (found, value) {
+        (false, Some(value)) => lines.push(format!("{}:{}", username, value)),
+        (false, None) => return Ok(false),
+        _ => {}
+    }
+
+    let mut updated = lines.join("\n");
+    if !updated.is_empty() {
+        updated.push('\n');
+    }
+    write_file_atomically(Path::new(path), updated.as_bytes())?;
+    Ok(found)
+}
+
+// Changes to `.htpasswd` and the roles file hold an exclusive lock on
+// `.htpasswd.lock`, which also keeps the CLI and a running server from
+// overwriting each other's changes
+fn with_file_lock<T>(htpasswd_path: &str, change: impl FnOnce() -> Result<T, DomainError>) -> Result<T, DomainError> {
+    let lock_path = format!("{}.lock", htpasswd_path);
+    let lock_file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| DomainError::StorageError(format!("Failed to open {}: {}", lock_path, e)))?;
+    lock_file.lock()
+        .map_err(|e| DomainError::StorageError(format!("Failed to lock {}: {}", lock_path, e)))?;
+
+    // Released when `lock_file` is dropped
+    change()
+}
+
+fn storage_error(path: &str, e: io::Error) -> DomainError {
+    DomainError::StorageError(format!("Failed to update {}: {}", path, e))
+}
 
 pub struct HtpasswdAuthRepository {
     htpasswd_path: String,
+    roles_path: String,
+    default_role: Role,
 }
 
 impl HtpasswdAuthRepository {
-    pub fn new(htpasswd_path: String) -> Self {
-        Self { htpasswd_path }
+    pub fn new(htpasswd_path: String, roles_path: String, default_role: Role) -> Self {
+        Self {
+            htpasswd_path,
+            roles_path,
+            default_role,
+        }
+    }
+
+    // Looks the user up in the roles sidecar file, one `username:role` per
+    // line. The file is read on every login so role changes apply without a
+    // restart; users that are not listed get the default role
+    async fn resolve_role(&self, username: &str) -> Role {
+        let roles_path = self.roles_path.clone();
+        let username = username.to_string();
+        let default_role = self.default_role;
+        
+        task::spawn_blocking(move || {
+            let content = match read_optional(&roles_path) {
+                Ok(content) => content,
+                Err(e) => {
+                    error!("Failed to read roles file {}: {}", roles_path, e);
+                    return default_role;
+                }
+            };
+            
+            user_lines(&content)
+                .find(|(name, _)| *name == username)
+                .and_then(|(_, role)| {
+                    role.parse::<Role>()
+                        .inspect_err(|e| warn!("Ignoring role of user {} in {}: {}", username, roles_path, e))
+                        .ok()
+                })
+                .unwrap_or(default_role)
+        })
+        .await
+        .unwrap_or(default_role)
+    }
+
+    // Runs file work off the async runtime, with the htpasswd and roles paths
+    async fn blocking<T: Send + 'static>(
+        &self,
+        task: impl FnOnce(&str, &str) -> Result<T, DomainError> + Send + 'static,
+    ) -> Result<T, DomainError> {
+        let htpasswd_path = self.htpasswd_path.clone();
+        let roles_path = self.roles_path.clone();
+        task::spawn_blocking(move || task(&htpasswd_path, &roles_path))
+            .await
+            .map_err(|e| DomainError::StorageError(format!("User file task failed: {}", e)))?
     }
 }
 
 impl Default for HtpasswdAuthRepository {
     fn default() -> Self {
-        Self::new(".htpasswd".to_string())
+        Self::new(".htpasswd".to_string(), ".roles".to_string(), Role::Viewer)
     }
 }
 
@@ -49,8 +176,9 @@ impl AuthRepository for HtpasswdAuthRepository {
         })??;
         
         if is_valid {
-            debug!("Authentication successful for user: {}", username);
-            Ok(AuthUser::new(username.to_string()))
+            let role = self.resolve_role(username).await;
+            debug!("Authentication successful for user: {} ({})", username, role);
+            Ok(AuthUser::new(username.to_string(), role))
         } else {
             debug!("Authentication failed for user: {}", username);
             Err(DomainError::AuthenticationFailed)
@@ -73,9 +201,149 @@ impl AuthRepository for HtpasswdAuthRepository {
         .map_err(|_| DomainError::AuthenticationFailed)??;
         
         if user_exists {
-            Ok(Some(AuthUser::new(username.to_string())))
+            Ok(Some(AuthUser::new(username.to_string(), self.resolve_role(username).await)))
         } else {
             Ok(None)
         }
     }
 }
+
+#[async_trait]
+impl LocalUserRepository for HtpasswdAuthRepository {
+    async fn list(&self) -> Result<Vec<LocalUser>, DomainError> {
+        let default_role = self.default_role;
+        self.blocking(move |htpasswd_path, roles_path| {
+            let htpasswd = read_optional(htpasswd_path).map_err(|e| storage_error(htpasswd_path, e))?;
+            let roles_content = read_optional(roles_path).map_err(|e| storage_error(roles_path, e))?;
+            let roles: HashMap<&str, Role> = user_lines(&roles_content)
+                .filter_map(|(name, role)| Some((name, role.parse().ok()?)))
+                .collect();
+
+            Ok(user_lines(&htpasswd)
+                .map(|(name, _)| LocalUser {
+                    username: name.to_string(),
+                    role: roles.get(name).copied().unwrap_or(default_role),
+                })
+                .collect())
+        })
+        .await
+    }
+
+    async fn create(&self, username: &str, password_hash: &str, role: Option<Role>) -> Result<(), DomainError> {
+        let (username, password_hash) = (username.to_string(), password_hash.to_string());
+        self.blocking(move |htpasswd_path, roles_path| {
+            with_file_lock(htpasswd_path, || {
+                let htpasswd = read_optional(htpasswd_path).map_err(|e| storage_error(htpasswd_path, e))?;
+                if user_lines(&htpasswd).any(|(name, _)| name == username) {
+                    return Err(DomainError::AlreadyExists(format!("user {}", username)));
+                }
+
+                // The role is written first, so the user never logs in with a
+                // stale role left in the file by an earlier account
+                let role = role.map(|role| role.to_string());
+                replace_user_line(roles_path, &username, role.as_deref()).map_err(|e| storage_error(roles_path, e))?;
+                replace_user_line(htpasswd_path, &username, Some(&password_hash)).map_err(|e| storage_error(htpasswd_path, e))?;
+                info!("Created login user {}", username);
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn set_password(&self, username: &str, password_hash: &str) -> Result<(), DomainError> {
+        let (username, password_hash) = (username.to_string(), password_hash.to_string());
+        self.blocking(move |htpasswd_path, _| {
+            with_file_lock(htpasswd_path, || {
+                let htpasswd = read_optional(htpasswd_path).map_err(|e| storage_error(htpasswd_path, e))?;
+                if !user_lines(&htpasswd).any(|(name, _)| name == username) {
+                    return Err(DomainError::UserNotFound(username.clone()));
+                }
+
+                replace_user_line(htpasswd_path, &username, Some(&password_hash)).map_err(|e| storage_error(htpasswd_path, e))?;
+                info!("Changed password of login user {}", username);
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn delete(&self, username: &str) -> Result<(), DomainError> {
+        let username = username.to_string();
+        self.blocking(move |htpasswd_path, roles_path| {
+            with_file_lock(htpasswd_path, || {
+                if !replace_user_line(htpasswd_path, &username, None).map_err(|e| storage_error(htpasswd_path, e))? {
+                    return Err(DomainError::UserNotFound(username.clone()));
+                }
+
+                replace_user_line(roles_path, &username, None).map_err(|e| storage_error(roles_path, e))?;
+                info!("Deleted login user {}", username);
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn check_password(&self, username: &str, password: &str) -> Result<bool, DomainError> {
+        let (username, password) = (username.to_string(), password.to_string());
+        self.blocking(move |htpasswd_path, _| {
+            let htpasswd = read_optional(htpasswd_path).map_err(|e| storage_error(htpasswd_path, e))?;
+            Ok(Htpasswd::from(htpasswd.as_str()).check(&username, &password))
+        })
+        .await
+    }
+
+    // A digest of the password hash, which has a fresh salt after every change
+    async fn credential_stamp(&self, username: &str) -> Result<Option<String>, DomainError> {
+        let username = username.to_string();
+        self.blocking(move |htpasswd_path, _| {
+            let htpasswd = read_optional(htpasswd_path).map_err(|e| storage_error(htpasswd_path, e))?;
+            Ok(user_lines(&htpasswd)
+                .find(|(name, _)| *name == username)
+                .map(|(_, hash)| hex::encode(Sha256::digest(hash.as_bytes()))))
+        })
+        .await
+    }
+}
+
+// Tries several backends in order, e.g. LDAP with `.htpasswd` as a fallback
+// for local accounts. A backend that cannot be reached is skipped, so the
+// fallback keeps working while the directory is down
+pub struct ChainedAuthRepository {
+    backends: Vec<(String, Arc<dyn AuthRepository + Send + Sync>)>,
+}
+
+impl ChainedAuthRepository {
+    pub fn new(backends: Vec<(String, Arc<dyn AuthRepository + Send + Sync>)>) -> Self {
+        Self { backends }
+    }
+}
+
+#[async_trait]
+impl AuthRepository for ChainedAuthRepository {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<AuthUser, DomainError> {
+        for (name, backend) in &self.backends {
+            match backend.authenticate(username, password).await {
+                Ok(auth_user) if auth_user.is_authenticated => {
+                    debug!("User {} authenticated by {}", username, name);
+                    return Ok(auth_user);
+                }
+                Ok(_) | Err(DomainError::AuthenticationFailed) => {}
+                Err(e) => warn!("Auth backend {} failed, trying the next one: {}", name, e),
+            }
+        }
+        
+        Err(DomainError::AuthenticationFailed)
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<AuthUser>, DomainError> {
+        for (name, backend) in &self.backends {
+            match backend.find_by_username(username).await {
+                Ok(Some(auth_user)) => return Ok(Some(auth_user)),
+                Ok(None) => {}
+                Err(e) => warn!("Auth backend {} failed, trying the next one: {}", name, e),
+            }
+        }
+        
+        Ok(None)
+    }
+}
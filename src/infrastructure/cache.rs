@@ -1,16 +1,18 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
 use crate::domain::{
-    models::{DomainError, VpnUser},
+    models::{DomainError, LatencyUpdate, SessionKey, VpnUser},
     traits::CacheService,
 };
 
 pub struct InMemoryCache {
-    vpn_users: Arc<RwLock<HashMap<String, VpnUser>>>,
-    latencies: Arc<RwLock<HashMap<String, Option<f64>>>>,
+    vpn_users: Arc<RwLock<HashMap<SessionKey, VpnUser>>>,
+    latencies: Arc<RwLock<HashMap<SessionKey, LatencyUpdate>>>,
+    updated_at: Arc<RwLock<Option<DateTime<Utc>>>>,
 }
 
 impl InMemoryCache {
@@ -18,6 +20,7 @@ impl InMemoryCache {
         Self {
             vpn_users: Arc::new(RwLock::new(HashMap::new())),
             latencies: Arc::new(RwLock::new(HashMap::new())),
+            updated_at: Arc::new(RwLock::new(None)),
         }
     }
 }
@@ -42,40 +45,55 @@ impl CacheService for InMemoryCache {
     }
 
     async fn set_vpn_users(&self, users: Vec<VpnUser>) -> Result<(), DomainError> {
+        let latencies = self.latencies.read().await;
         let mut cache = self.vpn_users.write().await;
         cache.clear();
 
-        for user in users {
-            cache.insert(user.name.clone(), user);
+        // Freshly polled users carry no latency, keep the last measurement
+        for mut user in users {
+            let key = user.session_key();
+            if let Some(update) = latencies.get(&key) {
+                user.latency = update.latency;
+                user.latency_stats = Some(update.stats.clone());
+            }
+            cache.insert(key, user);
         }
+        *self.updated_at.write().await = Some(Utc::now());
 
         Ok(())
     }
 
-    async fn get_user_latency(&self, user_name: &str) -> Result<Option<f64>, DomainError> {
+    async fn vpn_users_updated_at(&self) -> Result<Option<DateTime<Utc>>, DomainError> {
+        Ok(*self.updated_at.read().await)
+    }
+
+    async fn get_user_latency(&self, session: &SessionKey) -> Result<Option<f64>, DomainError> {
         let latencies = self.latencies.read().await;
-        Ok(latencies.get(user_name).cloned().flatten())
+        Ok(latencies.get(session).and_then(|update| update.latency))
     }
 
-    async fn set_user_latency(&self, user_name: &str, latency: Option<f64>) -> Result<(), DomainError> {
+    async fn set_user_latency(&self, update: &LatencyUpdate) -> Result<(), DomainError> {
+        let key = update.session_key();
         let mut latencies = self.latencies.write().await;
-        latencies.insert(user_name.to_string(), latency);
+        latencies.insert(key.clone(), update.clone());
 
         // Also update the user's latency in the users cache
         let mut users = self.vpn_users.write().await;
-        if let Some(user) = users.get_mut(user_name) {
-            user.latency = latency;
+        if let Some(user) = users.get_mut(&key) {
+            user.latency = update.latency;
+            user.latency_stats = Some(update.stats.clone());
         }
 
         Ok(())
     }
 
-    async fn clear_user(&self, user_name: &str) -> Result<(), DomainError> {
-        let mut users = self.vpn_users.write().await;
-        users.remove(user_name);
-
+    // Locks are taken latencies first, like everywhere else in this cache
+    async fn clear_user(&self, session: &SessionKey) -> Result<(), DomainError> {
         let mut latencies = self.latencies.write().await;
-        latencies.remove(user_name);
+        latencies.remove(session);
+
+        let mut users = self.vpn_users.write().await;
+        users.remove(session);
 
         Ok(())
     }
@@ -3,14 +3,27 @@ use serde::Deserialize;
 use std::sync::LazyLock;
 
 use crate::domain::{
-    models::{MikrotikConfig, AppConfig, DomainError},
+    models::{
+        MikrotikConfig, AppConfig, LdapConfig, LdapGroupRole, OidcConfig, OidcClaimRole, Role, TlsClientRole, TlsConfig,
+        WebhookConfig, AlertRule, AlertRuleKind, DomainError,
+    },
     traits::ConfigService,
 };
 
 #[derive(Debug, Deserialize)]
 struct ConfigFile {
     app: AppConfigFile,
-    mikrotik: MikrotikConfigFile,
+    // Single-router setups keep using [mikrotik], fleets use [[routers]]
+    mikrotik: Option<MikrotikConfigFile>,
+    #[serde(default)]
+    routers: Vec<MikrotikConfigFile>,
+    ldap: Option<LdapConfigFile>,
+    oidc: Option<OidcConfigFile>,
+    tls: Option<TlsConfigFile>,
+    #[serde(default)]
+    webhooks: Vec<WebhookConfigFile>,
+    #[serde(default)]
+    alerts: Vec<AlertRuleFile>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -27,10 +40,80 @@ struct AppConfigFile {
     session_secret: String,
     #[serde(default = "default_ping_interval")]
     ping_interval_seconds: u64,
+    #[serde(default = "default_storage_backend")]
+    storage_backend: String,
+    #[serde(default = "default_database_path")]
+    database_path: String,
+    #[serde(default = "default_latency_raw_retention")]
+    latency_raw_retention_minutes: u64,
+    #[serde(default = "default_latency_minute_retention")]
+    latency_minute_retention_hours: u64,
+    #[serde(default = "default_latency_hour_retention")]
+    latency_hour_retention_days: u64,
+    #[serde(default = "default_ping_timeout")]
+    ping_timeout_ms: u64,
+    #[serde(default = "default_ping_concurrency")]
+    ping_concurrency: usize,
+    #[serde(default = "default_ping_window_size")]
+    ping_window_size: usize,
+    #[serde(default = "default_probe_method")]
+    probe_method: String,
+    #[serde(default = "default_probe_tcp_port")]
+    probe_tcp_port: u16,
+    #[serde(default = "default_htpasswd_path")]
+    htpasswd_path: String,
+    #[serde(default = "default_roles_file")]
+    roles_file: String,
+    #[serde(default = "default_role")]
+    default_role: String,
+    #[serde(default = "default_api_tokens_file")]
+    api_tokens_file: String,
+    #[serde(default)]
+    session_key_file: String,
+    #[serde(default)]
+    cookie_secure: bool,
+    #[serde(default = "default_cookie_same_site")]
+    cookie_same_site: String,
+    #[serde(default)]
+    trusted_origins: Vec<String>,
+    #[serde(default = "default_session_max_age")]
+    session_max_age_minutes: u64,
+    #[serde(default = "default_session_idle_timeout")]
+    session_idle_timeout_minutes: u64,
+    #[serde(default = "default_totp_file")]
+    totp_file: String,
+    #[serde(default = "default_totp_issuer")]
+    totp_issuer: String,
+    #[serde(default)]
+    totp_required_roles: Vec<String>,
+    #[serde(default = "default_login_max_failures")]
+    login_max_failures: u32,
+    #[serde(default = "default_login_max_failures_per_ip")]
+    login_max_failures_per_ip: u32,
+    #[serde(default = "default_login_lockout")]
+    login_lockout_minutes: u64,
+    #[serde(default = "default_login_backoff")]
+    login_backoff_seconds: u64,
+    #[serde(default = "default_auth_backends")]
+    auth_backends: Vec<String>,
+    #[serde(default = "default_audit_log_file")]
+    audit_log_file: String,
+    #[serde(default = "default_webhook_dead_letter_file")]
+    webhook_dead_letter_file: String,
+    #[serde(default = "default_metrics_user_series_limit")]
+    metrics_user_series_limit: usize,
+    #[serde(default = "default_readiness_max_user_list_age")]
+    readiness_max_user_list_age_seconds: u64,
+    #[serde(default = "default_alert_silences_file")]
+    alert_silences_file: String,
+    #[serde(default = "default_alert_resolved_retention")]
+    alert_resolved_retention_minutes: u64,
 }
 
 #[derive(Debug, Deserialize)]
 struct MikrotikConfigFile {
+    #[serde(default = "default_router_name")]
+    name: String,
     protocol: String,
     address: String,
     port: u16,
@@ -40,6 +123,120 @@ struct MikrotikConfigFile {
     timeout_seconds: u64,
 }
 
+#[derive(Debug, Deserialize)]
+struct WebhookConfigFile {
+    name: String,
+    url: String,
+    secret: String,
+    #[serde(default = "default_webhook_events")]
+    events: Vec<String>,
+    #[serde(default = "default_timeout")]
+    timeout_seconds: u64,
+    #[serde(default = "default_webhook_max_attempts")]
+    max_attempts: u32,
+    #[serde(default = "default_webhook_retry_base")]
+    retry_base_seconds: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct AlertRuleFile {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default = "default_alert_severity")]
+    severity: String,
+    #[serde(default)]
+    users: Vec<String>,
+    comment: Option<String>,
+    threshold: Option<f64>,
+    clear: Option<f64>,
+    #[serde(default)]
+    for_seconds: u64,
+    #[serde(default = "default_alert_window")]
+    window_seconds: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct LdapConfigFile {
+    url: String,
+    #[serde(default)]
+    starttls: bool,
+    #[serde(default)]
+    tls_ca_file: String,
+    #[serde(default)]
+    tls_insecure: bool,
+    #[serde(default)]
+    bind_dn: String,
+    #[serde(default)]
+    bind_password: String,
+    base_dn: String,
+    #[serde(default = "default_ldap_user_filter")]
+    user_filter: String,
+    #[serde(default = "default_ldap_group_attribute")]
+    group_attribute: String,
+    #[serde(default)]
+    group_roles: Vec<LdapGroupRoleFile>,
+    default_role: Option<String>,
+    #[serde(default = "default_timeout")]
+    timeout_seconds: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct LdapGroupRoleFile {
+    group: String,
+    role: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OidcConfigFile {
+    issuer_url: String,
+    client_id: String,
+    #[serde(default)]
+    client_secret: String,
+    redirect_url: String,
+    #[serde(default = "default_oidc_scopes")]
+    scopes: Vec<String>,
+    #[serde(default = "default_oidc_username_claim")]
+    username_claim: String,
+    #[serde(default = "default_oidc_role_claim")]
+    role_claim: String,
+    #[serde(default)]
+    claim_roles: Vec<OidcClaimRoleFile>,
+    default_role: Option<String>,
+    #[serde(default = "default_oidc_display_name")]
+    display_name: String,
+    #[serde(default = "default_timeout")]
+    timeout_seconds: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct OidcClaimRoleFile {
+    value: String,
+    role: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TlsConfigFile {
+    #[serde(default = "default_tls_port")]
+    port: u16,
+    cert_file: String,
+    key_file: String,
+    #[serde(default = "default_tls_reload_interval")]
+    reload_interval_seconds: u64,
+    #[serde(default)]
+    redirect_http: bool,
+    #[serde(default)]
+    client_ca_file: String,
+    #[serde(default)]
+    client_roles: Vec<TlsClientRoleFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TlsClientRoleFile {
+    common_name: String,
+    role: String,
+}
+
 // Default values
 fn default_log_level() -> String { "info".to_string() }
 fn default_bind_address() -> String { "127.0.0.1".to_string() }
@@ -47,7 +244,51 @@ fn default_bind_port() -> u16 { 3217 }
 fn default_static_files_path() -> String { "./asset".to_string() }
 fn default_session_secret() -> String { "change-me-in-production".to_string() }
 fn default_ping_interval() -> u64 { 2 }
+fn default_storage_backend() -> String { "memory".to_string() }
+fn default_database_path() -> String { "mikriting.db".to_string() }
+fn default_latency_raw_retention() -> u64 { 60 }
+fn default_latency_minute_retention() -> u64 { 24 }
+fn default_latency_hour_retention() -> u64 { 30 }
+fn default_ping_timeout() -> u64 { 1000 }
+fn default_ping_concurrency() -> usize { 32 }
+fn default_ping_window_size() -> usize { 20 }
+fn default_probe_method() -> String { "icmp".to_string() }
+fn default_probe_tcp_port() -> u16 { 80 }
+fn default_htpasswd_path() -> String { ".htpasswd".to_string() }
+fn default_roles_file() -> String { ".roles".to_string() }
+fn default_role() -> String { "viewer".to_string() }
+fn default_api_tokens_file() -> String { ".api_tokens.json".to_string() }
+fn default_cookie_same_site() -> String { "lax".to_string() }
+fn default_session_max_age() -> u64 { 720 }
+fn default_session_idle_timeout() -> u64 { 60 }
+fn default_totp_file() -> String { ".totp.json".to_string() }
+fn default_totp_issuer() -> String { "mikriting-tool".to_string() }
+fn default_login_max_failures() -> u32 { 5 }
+fn default_login_max_failures_per_ip() -> u32 { 20 }
+fn default_login_lockout() -> u64 { 15 }
+fn default_login_backoff() -> u64 { 1 }
+fn default_auth_backends() -> Vec<String> { vec!["htpasswd".to_string()] }
+fn default_audit_log_file() -> String { "audit.jsonl".to_string() }
+fn default_webhook_dead_letter_file() -> String { "webhook_dead_letters.json".to_string() }
+fn default_webhook_events() -> Vec<String> { vec!["user.connected".to_string(), "user.disconnected".to_string()] }
+fn default_webhook_max_attempts() -> u32 { 5 }
+fn default_webhook_retry_base() -> u64 { 2 }
+fn default_metrics_user_series_limit() -> usize { 500 }
+fn default_readiness_max_user_list_age() -> u64 { 60 }
+fn default_alert_silences_file() -> String { "alert_silences.json".to_string() }
+fn default_alert_resolved_retention() -> u64 { 60 }
+fn default_alert_severity() -> String { "warning".to_string() }
+fn default_alert_window() -> u64 { 600 }
+fn default_ldap_user_filter() -> String { "(uid={username})".to_string() }
+fn default_ldap_group_attribute() -> String { "memberOf".to_string() }
+fn default_oidc_scopes() -> Vec<String> { vec!["openid".to_string(), "profile".to_string(), "email".to_string()] }
+fn default_oidc_username_claim() -> String { "preferred_username".to_string() }
+fn default_oidc_role_claim() -> String { "groups".to_string() }
+fn default_oidc_display_name() -> String { "SSO".to_string() }
+fn default_tls_port() -> u16 { 3443 }
+fn default_tls_reload_interval() -> u64 { 60 }
 fn default_timeout() -> u64 { 10 }
+fn default_router_name() -> String { "default".to_string() }
 
 static CONFIG: LazyLock<ConfigFile> = LazyLock::new(|| {
     let builder = Config::builder()
@@ -76,22 +317,48 @@ impl Default for FileConfigService {
 }
 
 impl ConfigService for FileConfigService {
-    fn get_mikrotik_config(&self) -> Result<MikrotikConfig, DomainError> {
-        let config = &CONFIG.mikrotik;
+    fn get_router_configs(&self) -> Result<Vec<MikrotikConfig>, DomainError> {
+        let routers: Vec<&MikrotikConfigFile> = CONFIG.mikrotik.iter()
+            .chain(CONFIG.routers.iter())
+            .collect();
         
-        Ok(MikrotikConfig {
-            protocol: config.protocol.clone(),
-            address: config.address.clone(),
-            port: config.port,
-            username: config.username.clone(),
-            password: config.password.clone(),
-            timeout_seconds: config.timeout_seconds,
-        })
+        if routers.is_empty() {
+            return Err(DomainError::ConfigurationError(
+                "No router configured, add a [mikrotik] table or [[routers]] entries".to_string()
+            ));
+        }
+        
+        let mut names = std::collections::HashSet::new();
+        for router in &routers {
+            if !names.insert(router.name.as_str()) {
+                return Err(DomainError::ConfigurationError(
+                    format!("Duplicate router name: {}", router.name)
+                ));
+            }
+        }
+        
+        Ok(routers.into_iter()
+            .map(|config| MikrotikConfig {
+                name: config.name.clone(),
+                protocol: config.protocol.clone(),
+                address: config.address.clone(),
+                port: config.port,
+                username: config.username.clone(),
+                password: config.password.clone(),
+                timeout_seconds: config.timeout_seconds,
+            })
+            .collect())
     }
 
     fn get_app_config(&self) -> Result<AppConfig, DomainError> {
         let config = &CONFIG.app;
-        
+
+        if config.ping_interval_seconds == 0 {
+            return Err(DomainError::ConfigurationError(
+                "app.ping_interval_seconds must be at least 1".to_string()
+            ));
+        }
+
         Ok(AppConfig {
             log_level: config.log_level.clone(),
             bind_address: config.bind_address.clone(),
@@ -99,8 +366,239 @@ impl ConfigService for FileConfigService {
             static_files_path: config.static_files_path.clone(),
             session_secret: config.session_secret.clone(),
             ping_interval_seconds: config.ping_interval_seconds,
+            storage_backend: config.storage_backend.clone(),
+            database_path: config.database_path.clone(),
+            latency_raw_retention_minutes: config.latency_raw_retention_minutes,
+            latency_minute_retention_hours: config.latency_minute_retention_hours,
+            latency_hour_retention_days: config.latency_hour_retention_days,
+            ping_timeout_ms: config.ping_timeout_ms,
+            ping_concurrency: config.ping_concurrency,
+            ping_window_size: config.ping_window_size,
+            probe_method: config.probe_method.parse()
+                .map_err(|e| DomainError::ConfigurationError(format!("app.probe_method: {}", e)))?,
+            probe_tcp_port: config.probe_tcp_port,
+            htpasswd_path: config.htpasswd_path.clone(),
+            roles_file: config.roles_file.clone(),
+            default_role: config.default_role.parse()
+                .map_err(|e| DomainError::ConfigurationError(format!("app.default_role: {}", e)))?,
+            api_tokens_file: config.api_tokens_file.clone(),
+            session_key_file: config.session_key_file.clone(),
+            cookie_secure: config.cookie_secure,
+            cookie_same_site: config.cookie_same_site.clone(),
+            trusted_origins: config.trusted_origins.clone(),
+            session_max_age_minutes: config.session_max_age_minutes,
+            session_idle_timeout_minutes: config.session_idle_timeout_minutes,
+            totp_file: config.totp_file.clone(),
+            totp_issuer: config.totp_issuer.clone(),
+            totp_required_roles: config.totp_required_roles.iter()
+                .map(|role| role.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| DomainError::ConfigurationError(format!("app.totp_required_roles: {}", e)))?,
+            login_max_failures: config.login_max_failures,
+            login_max_failures_per_ip: config.login_max_failures_per_ip,
+            login_lockout_minutes: config.login_lockout_minutes,
+            login_backoff_seconds: config.login_backoff_seconds,
+            auth_backends: config.auth_backends.iter()
+                .map(|backend| backend.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| DomainError::ConfigurationError(format!("app.auth_backends: {}", e)))?,
+            audit_log_file: config.audit_log_file.clone(),
+            webhook_dead_letter_file: config.webhook_dead_letter_file.clone(),
+            metrics_user_series_limit: config.metrics_user_series_limit,
+            readiness_max_user_list_age_seconds: config.readiness_max_user_list_age_seconds,
+            alert_silences_file: config.alert_silences_file.clone(),
+            alert_resolved_retention_minutes: config.alert_resolved_retention_minutes,
         })
     }
+
+    fn get_ldap_config(&self) -> Result<Option<LdapConfig>, DomainError> {
+        let Some(config) = &CONFIG.ldap else {
+            return Ok(None);
+        };
+        
+        if !config.user_filter.contains("{username}") {
+            return Err(DomainError::ConfigurationError(
+                "ldap.user_filter must contain the {username} placeholder".to_string()
+            ));
+        }
+        
+        let role = |field: &str, value: &str| -> Result<Role, DomainError> {
+            value.parse()
+                .map_err(|e| DomainError::ConfigurationError(format!("ldap.{}: {}", field, e)))
+        };
+        
+        Ok(Some(LdapConfig {
+            url: config.url.clone(),
+            starttls: config.starttls,
+            tls_ca_file: config.tls_ca_file.clone(),
+            tls_insecure: config.tls_insecure,
+            bind_dn: config.bind_dn.clone(),
+            bind_password: config.bind_password.clone(),
+            base_dn: config.base_dn.clone(),
+            user_filter: config.user_filter.clone(),
+            group_attribute: config.group_attribute.clone(),
+            group_roles: config.group_roles.iter()
+                .map(|entry| Ok(LdapGroupRole {
+                    group: entry.group.clone(),
+                    role: role("group_roles", &entry.role)?,
+                }))
+                .collect::<Result<_, DomainError>>()?,
+            default_role: config.default_role.as_deref()
+                .map(|value| role("default_role", value))
+                .transpose()?,
+            timeout_seconds: config.timeout_seconds,
+        }))
+    }
+
+    fn get_oidc_config(&self) -> Result<Option<OidcConfig>, DomainError> {
+        let Some(config) = &CONFIG.oidc else {
+            return Ok(None);
+        };
+        
+        if !config.scopes.iter().any(|scope| scope == "openid") {
+            return Err(DomainError::ConfigurationError(
+                "oidc.scopes must include openid".to_string()
+            ));
+        }
+        
+        let role = |field: &str, value: &str| -> Result<Role, DomainError> {
+            value.parse()
+                .map_err(|e| DomainError::ConfigurationError(format!("oidc.{}: {}", field, e)))
+        };
+        
+        Ok(Some(OidcConfig {
+            issuer_url: config.issuer_url.trim_end_matches('/').to_string(),
+            client_id: config.client_id.clone(),
+            client_secret: config.client_secret.clone(),
+            redirect_url: config.redirect_url.clone(),
+            scopes: config.scopes.clone(),
+            username_claim: config.username_claim.clone(),
+            role_claim: config.role_claim.clone(),
+            claim_roles: config.claim_roles.iter()
+                .map(|entry| Ok(OidcClaimRole {
+                    value: entry.value.clone(),
+                    role: role("claim_roles", &entry.role)?,
+                }))
+                .collect::<Result<_, DomainError>>()?,
+            default_role: config.default_role.as_deref()
+                .map(|value| role("default_role", value))
+                .transpose()?,
+            display_name: config.display_name.clone(),
+            timeout_seconds: config.timeout_seconds,
+        }))
+    }
+
+    fn get_tls_config(&self) -> Result<Option<TlsConfig>, DomainError> {
+        let Some(config) = &CONFIG.tls else {
+            return Ok(None);
+        };
+        
+        if config.reload_interval_seconds == 0 {
+            return Err(DomainError::ConfigurationError(
+                "tls.reload_interval_seconds must be at least 1".to_string()
+            ));
+        }
+        if !config.client_roles.is_empty() && config.client_ca_file.is_empty() {
+            return Err(DomainError::ConfigurationError(
+                "tls.client_roles requires tls.client_ca_file".to_string()
+            ));
+        }
+        
+        Ok(Some(TlsConfig {
+            port: config.port,
+            cert_file: config.cert_file.clone(),
+            key_file: config.key_file.clone(),
+            reload_interval_seconds: config.reload_interval_seconds,
+            redirect_http: config.redirect_http,
+            client_ca_file: config.client_ca_file.clone(),
+            client_roles: config.client_roles.iter()
+                .map(|entry| Ok(TlsClientRole {
+                    common_name: entry.common_name.clone(),
+                    role: entry.role.parse()
+                        .map_err(|e| DomainError::ConfigurationError(format!("tls.client_roles: {}", e)))?,
+                }))
+                .collect::<Result<_, DomainError>>()?,
+        }))
+    }
+
+    fn get_webhook_configs(&self) -> Result<Vec<WebhookConfig>, DomainError> {
+        let mut names = std::collections::HashSet::new();
+        
+        CONFIG.webhooks.iter()
+            .map(|webhook| {
+                let invalid = |message: String| DomainError::ConfigurationError(format!("webhooks.{}: {}", webhook.name, message));
+                
+                if !names.insert(webhook.name.as_str()) {
+                    return Err(DomainError::ConfigurationError(format!("Duplicate webhook name: {}", webhook.name)));
+                }
+                if !webhook.url.starts_with("https://") && !webhook.url.starts_with("http://") {
+                    return Err(invalid("url must start with https:// or http://".to_string()));
+                }
+                if webhook.secret.is_empty() {
+                    return Err(invalid("secret is required to sign deliveries".to_string()));
+                }
+                if webhook.max_attempts == 0 {
+                    return Err(invalid("max_attempts must be at least 1".to_string()));
+                }
+                
+                Ok(WebhookConfig {
+                    name: webhook.name.clone(),
+                    url: webhook.url.clone(),
+                    secret: webhook.secret.clone(),
+                    events: webhook.events.iter()
+                        .map(|event| event.parse().map_err(|e: DomainError| invalid(e.to_string())))
+                        .collect::<Result<_, _>>()?,
+                    timeout_seconds: webhook.timeout_seconds,
+                    max_attempts: webhook.max_attempts,
+                    retry_base_seconds: webhook.retry_base_seconds,
+                })
+            })
+            .collect()
+    }
+
+    fn get_alert_rules(&self) -> Result<Vec<AlertRule>, DomainError> {
+        let mut names = std::collections::HashSet::new();
+        
+        CONFIG.alerts.iter()
+            .map(|rule| {
+                let invalid = |message: String| DomainError::ConfigurationError(format!("alerts.{}: {}", rule.name, message));
+                
+                if !names.insert(rule.name.as_str()) {
+                    return Err(DomainError::ConfigurationError(format!("Duplicate alert rule name: {}", rule.name)));
+                }
+                let kind: AlertRuleKind = rule.kind.parse().map_err(|e: DomainError| invalid(e.to_string()))?;
+                
+                // Disconnect rules have no value to compare
+                let threshold = match (kind, rule.threshold) {
+                    (AlertRuleKind::Disconnect, _) => 0.0,
+                    (_, Some(threshold)) if threshold > 0.0 => threshold,
+                    _ => return Err(invalid("threshold must be greater than 0".to_string())),
+                };
+                if matches!(kind, AlertRuleKind::PacketLoss | AlertRuleKind::SessionDrop) && threshold > 100.0 {
+                    return Err(invalid("threshold is a percentage, at most 100".to_string()));
+                }
+                let clear = rule.clear.unwrap_or(threshold);
+                if clear > threshold {
+                    return Err(invalid("clear must not be greater than threshold".to_string()));
+                }
+                if kind == AlertRuleKind::SessionDrop && rule.window_seconds == 0 {
+                    return Err(invalid("window_seconds must be at least 1".to_string()));
+                }
+                
+                Ok(AlertRule {
+                    name: rule.name.clone(),
+                    kind,
+                    severity: rule.severity.clone(),
+                    users: rule.users.clone(),
+                    comment: rule.comment.clone(),
+                    threshold,
+                    clear,
+                    for_seconds: rule.for_seconds,
+                    window_seconds: rule.window_seconds,
+                })
+            })
+            .collect()
+    }
 }
 
 impl From<ConfigError> for DomainError {
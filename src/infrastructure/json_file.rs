@@ -0,0 +1,46 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use tokio::task;
+
+use crate::domain::models::DomainError;
+
+// Small JSON documents kept next to `.htpasswd` (API tokens, TOTP secrets).
+// A missing file reads as the default value
+pub fn read_json_file<T: DeserializeOwned + Default>(path: &Path) -> Result<T, DomainError> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|e| DomainError::StorageError(format!("Invalid JSON in {}: {}", path.display(), e))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(DomainError::StorageError(format!("Failed to read {}: {}", path.display(), e))),
+    }
+}
+
+// Writes through a temp file and a rename so readers never see a partial
+// file. The file is only readable by the service user
+pub fn write_file_atomically(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&temp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+pub async fn write_json_file<T: Serialize>(path: &Path, value: &T) -> Result<(), DomainError> {
+    let content = serde_json::to_vec_pretty(value)
+        .map_err(|e| DomainError::SerializationError(e.to_string()))?;
+    let path = path.to_path_buf();
+
+    task::spawn_blocking(move || write_file_atomically(&path, &content))
+        .await
+        .map_err(|e| DomainError::StorageError(format!("File write task failed: {}", e)))?
+        .map_err(|e| DomainError::StorageError(format!("Failed to write file: {}", e)))
+}
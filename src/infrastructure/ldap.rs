@@ -0,0 +1,485 @@
+use async_trait::async_trait;
+use ldap3::{drive, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer},
+    ClientConfig, RootCertStore,
+};
+use log::{debug, info, warn};
+
+use crate::domain::{
+    models::{AuthUser, LdapConfig, Role, DomainError},
+    traits::AuthRepository,
+};
+
+// Result code of a bind with a wrong password or an unknown DN
+const LDAP_INVALID_CREDENTIALS: u32 = 49;
+
+fn ldap_error(e: LdapError) -> DomainError {
+    DomainError::NetworkError(format!("LDAP: {}", e))
+}
+
+// Checks passwords by binding as the user. The user's DN is looked up first
+// with `user_filter`, through the service account when `bind_dn` is set,
+// and its groups are mapped to a role
+pub struct LdapAuthRepository {
+    config: LdapConfig,
+    settings: LdapConnSettings,
+}
+
+impl LdapAuthRepository {
+    pub fn new(config: LdapConfig) -> Result<Self, DomainError> {
+        let mut settings = LdapConnSettings::new()
+            .set_conn_timeout(Duration::from_secs(config.timeout_seconds))
+            .set_starttls(config.starttls)
+            .set_no_tls_verify(config.tls_insecure);
+
+        if config.tls_insecure {
+            warn!("LDAP server certificate verification is disabled");
+        } else if !config.tls_ca_file.is_empty() {
+            settings = settings.set_config(Arc::new(Self::tls_config(&config.tls_ca_file)?));
+        }
+
+        info!("Using LDAP authentication against {}", config.url);
+        Ok(Self { config, settings })
+    }
+
+    // Trusts only the CA certificates in the given PEM file, for directories
+    // with an internal CA
+    fn tls_config(ca_file: &str) -> Result<ClientConfig, DomainError> {
+        let ca_error = |e: String| DomainError::ConfigurationError(format!("ldap.tls_ca_file {}: {}", ca_file, e));
+
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca_file).map_err(|e| ca_error(e.to_string()))? {
+            roots.add(cert.map_err(|e| ca_error(e.to_string()))?)
+                .map_err(|e| ca_error(e.to_string()))?;
+        }
+        if roots.is_empty() {
+            return Err(ca_error("no certificates found".to_string()));
+        }
+
+        Ok(ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| DomainError::ConfigurationError(format!("LDAP TLS: {}", e)))?
+            .with_root_certificates(roots)
+            .with_no_client_auth())
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.timeout_seconds)
+    }
+
+    // Opens a connection, bound as the service account if one is configured
+    async fn connect(&self) -> Result<Ldap, DomainError> {
+        let (conn, mut ldap) = LdapConnAsync::with_settings(self.settings.clone(), &self.config.url)
+            .await
+            .map_err(ldap_error)?;
+        drive!(conn);
+
+        if !self.config.bind_dn.is_empty() {
+            ldap.with_timeout(self.timeout())
+                .simple_bind(&self.config.bind_dn, &self.config.bind_password)
+                .await
+                .and_then(|result| result.success())
+                .map_err(|e| DomainError::NetworkError(format!("LDAP service bind failed: {}", e)))?;
+        }
+
+        Ok(ldap)
+    }
+
+    async fn find_entry(&self, ldap: &mut Ldap, username: &str) -> Result<Option<SearchEntry>, DomainError> {
+        let filter = self.config.user_filter.replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap.with_timeout(self.timeout())
+            .search(&self.config.base_dn, Scope::Subtree, &filter, vec![self.config.group_attribute.as_str()])
+            .await
+            .and_then(|result| result.success())
+            .map_err(ldap_error)?;
+
+        // More than one match would make the login ambiguous, refuse it
+        if entries.len() > 1 {
+            warn!("LDAP filter {} matches {} entries, refusing login", filter, entries.len());
+            return Ok(None);
+        }
+
+        Ok(entries.into_iter().next().map(SearchEntry::construct))
+    }
+
+    // Highest role of the user's mapped groups. Group DNs are compared
+    // case-insensitively, as directories return them in varying case
+    fn resolve_role(&self, entry: &SearchEntry) -> Option<Role> {
+        let groups: Vec<&String> = entry.attrs.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(&self.config.group_attribute))
+            .flat_map(|(_, values)| values)
+            .collect();
+
+        self.config.group_roles.iter()
+            .filter(|mapping| groups.iter().any(|group| group.trim().eq_ignore_ascii_case(mapping.group.trim())))
+            .map(|mapping| mapping.role)
+            .max()
+            .or(self.config.default_role)
+    }
+
+    async fn unbind(mut ldap: Ldap) {
+        if let Err(e) = ldap.unbind().await {
+            debug!("LDAP unbind failed: {}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl AuthRepository for LdapAuthRepository {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<AuthUser, DomainError> {
+        // An empty password would be an unauthenticated bind, which servers
+        // accept for any DN
+        if username.is_empty() || password.is_empty() {
+            return Err(DomainError::AuthenticationFailed);
+        }
+
+        let mut ldap = self.connect().await?;
+        let entry = match self.find_entry(&mut ldap, username).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                debug!("LDAP user not found: {}", username);
+                Self::unbind(ldap).await;
+                return Err(DomainError::AuthenticationFailed);
+            }
+            Err(e) => {
+                Self::unbind(ldap).await;
+                return Err(e);
+            }
+        };
+
+        let bind = ldap.with_timeout(self.timeout())
+            .simple_bind(&entry.dn, password)
+            .await;
+        Self::unbind(ldap).await;
+
+        let result = bind.map_err(ldap_error)?;
+        if result.rc == LDAP_INVALID_CREDENTIALS {
+            debug!("LDAP bind failed for user: {}", username);
+            return Err(DomainError::AuthenticationFailed);
+        }
+        result.success().map_err(ldap_error)?;
+
+        match self.resolve_role(&entry) {
+            Some(role) => {
+                debug!("LDAP authentication successful for user: {} ({})", username, role);
+                Ok(AuthUser::new(username.to_string(), role))
+            }
+            None => {
+                info!("LDAP user {} is in no mapped group, refusing login", username);
+                Err(DomainError::AuthenticationFailed)
+            }
+        }
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<AuthUser>, DomainError> {
+        let mut ldap = self.connect().await?;
+        let entry = self.find_entry(&mut ldap, username).await;
+        Self::unbind(ldap).await;
+
+        Ok(entry?
+            .and_then(|entry| self.resolve_role(&entry))
+            .map(|role| AuthUser::new(username.to_string(), role)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::LdapGroupRole;
+    use crate::infrastructure::ChainedAuthRepository;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const SERVICE_DN: &str = "cn=svc,dc=example,dc=org";
+
+    // DN, password, uid and groups of the stand-in directory
+    const DIRECTORY: &[(&str, &str, &str, &[&str])] = &[
+        (SERVICE_DN, "svcpw", "", &[]),
+        ("uid=carol,ou=people,dc=example,dc=org", "carolpw", "carol", &["CN=NetAdmins,OU=Groups,DC=example,DC=org"]),
+        ("uid=dan,ou=people,dc=example,dc=org", "danpw", "dan", &[
+            "cn=staff,ou=groups,dc=example,dc=org",
+            "cn=helpdesk,ou=groups,dc=example,dc=org",
+        ]),
+        ("uid=eve,ou=people,dc=example,dc=org", "evepw", "eve", &[]),
+        ("uid=twin,ou=people,dc=example,dc=org", "twinpw", "twin", &[]),
+        ("uid=twin,ou=contractors,dc=example,dc=org", "twinpw", "twin", &[]),
+    ];
+
+    // Just enough BER for the bind, search and unbind requests the backend sends
+
+    // Tag, value and total length of the first element, None while incomplete
+    fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], usize)> {
+        let (&tag, rest) = data.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+
+        let (length, header) = if first & 0x80 == 0 {
+            (first as usize, 2)
+        } else {
+            let count = (first & 0x7f) as usize;
+            let bytes = rest.get(..count)?;
+            (bytes.iter().fold(0, |acc, &b| acc << 8 | b as usize), 2 + count)
+        };
+
+        let value = data.get(header..header + length)?;
+        Some((tag, value, header + length))
+    }
+
+    fn children(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut elements = Vec::new();
+        while let Some((tag, value, length)) = read_tlv(data) {
+            elements.push((tag, value));
+            data = &data[length..];
+        }
+        elements
+    }
+
+    fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![tag];
+        if value.len() < 0x80 {
+            encoded.push(value.len() as u8);
+        } else {
+            let length = (value.len() as u32).to_be_bytes();
+            let skip = length.iter().take_while(|&&b| b == 0).count();
+            encoded.push(0x80 | (4 - skip) as u8);
+            encoded.extend_from_slice(&length[skip..]);
+        }
+        encoded.extend_from_slice(value);
+        encoded
+    }
+
+    fn octets(value: &str) -> Vec<u8> {
+        tlv(0x04, value.as_bytes())
+    }
+
+    // Result code, matched DN and diagnostic message
+    fn ldap_result(tag: u8, code: u8) -> Vec<u8> {
+        tlv(tag, &[tlv(0x0a, &[code]), octets(""), octets("")].concat())
+    }
+
+    fn message(id: &[u8], operation: Vec<u8>) -> Vec<u8> {
+        tlv(0x30, &[tlv(0x02, id), operation].concat())
+    }
+
+    // Value of a `(uid=...)` equality match, possibly inside an and/or filter
+    fn filter_uid(tag: u8, value: &[u8]) -> Option<String> {
+        match tag {
+            0xa3 => match children(value).as_slice() {
+                [(_, attribute), (_, uid)] if attribute.eq_ignore_ascii_case(b"uid") => {
+                    Some(String::from_utf8_lossy(uid).into_owned())
+                }
+                _ => None,
+            },
+            0xa0 | 0xa1 => children(value).into_iter().find_map(|(tag, value)| filter_uid(tag, value)),
+            _ => None,
+        }
+    }
+
+    struct StandIn {
+        url: String,
+        // DN of every bind request, in order
+        binds: Arc<Mutex<Vec<String>>>,
+    }
+
+    async fn start_directory() -> StandIn {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let binds = Arc::new(Mutex::new(Vec::new()));
+
+        let log = binds.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, log.clone()));
+            }
+        });
+
+        StandIn { url, binds }
+    }
+
+    async fn serve(mut stream: TcpStream, binds: Arc<Mutex<Vec<String>>>) {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 4096];
+        let mut bound = false;
+
+        loop {
+            while let Some((_, body, length)) = read_tlv(&buffer) {
+                let Some(replies) = answer(body, &mut bound, &binds) else {
+                    return;
+                };
+                buffer.drain(..length);
+                for reply in replies {
+                    stream.write_all(&reply).await.unwrap();
+                }
+            }
+
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            }
+        }
+    }
+
+    // Replies to one request, None once the client unbinds
+    fn answer(body: &[u8], bound: &mut bool, binds: &Mutex<Vec<String>>) -> Option<Vec<Vec<u8>>> {
+        let parts = children(body);
+        let (id, (tag, operation)) = (parts[0].1, parts[1]);
+
+        match tag {
+            // Bind: version, name and simple password
+            0x60 => {
+                let fields = children(operation);
+                let dn = String::from_utf8_lossy(fields[1].1).into_owned();
+                let password = String::from_utf8_lossy(fields[2].1).into_owned();
+                binds.lock().unwrap().push(dn.clone());
+
+                let known = DIRECTORY.iter()
+                    .any(|(entry, secret, _, _)| entry.eq_ignore_ascii_case(&dn) && *secret == password);
+                // Like real servers, an empty password is an unauthenticated bind
+                let code = if known || password.is_empty() { 0 } else { LDAP_INVALID_CREDENTIALS as u8 };
+                *bound = code == 0 && !password.is_empty();
+
+                Some(vec![message(id, ldap_result(0x61, code))])
+            }
+            // Search: base, scope, deref, size and time limit, types only, filter, attributes
+            0x63 => {
+                if !*bound {
+                    return Some(vec![message(id, ldap_result(0x65, 50))]);
+                }
+
+                let fields = children(operation);
+                let uid = filter_uid(fields[6].0, fields[6].1);
+                let mut replies: Vec<Vec<u8>> = DIRECTORY.iter()
+                    .filter(|(_, _, entry_uid, _)| !entry_uid.is_empty() && uid.as_deref() == Some(*entry_uid))
+                    .map(|(dn, _, _, groups)| {
+                        let values: Vec<u8> = groups.iter().flat_map(|group| octets(group)).collect();
+                        let attribute = tlv(0x30, &[octets("memberOf"), tlv(0x31, &values)].concat());
+                        message(id, tlv(0x64, &[octets(dn), tlv(0x30, &attribute)].concat()))
+                    })
+                    .collect();
+                replies.push(message(id, ldap_result(0x65, 0)));
+                Some(replies)
+            }
+            _ => None,
+        }
+    }
+
+    fn config(url: &str) -> LdapConfig {
+        LdapConfig {
+            url: url.to_string(),
+            starttls: false,
+            tls_ca_file: String::new(),
+            tls_insecure: false,
+            bind_dn: SERVICE_DN.to_string(),
+            bind_password: "svcpw".to_string(),
+            base_dn: "dc=example,dc=org".to_string(),
+            user_filter: "(&(objectClass=person)(uid={username}))".to_string(),
+            group_attribute: "memberof".to_string(),
+            group_roles: vec![
+                LdapGroupRole { group: "cn=netadmins,ou=groups,dc=example,dc=org".to_string(), role: Role::Admin },
+                LdapGroupRole { group: "CN=HelpDesk,OU=Groups,DC=Example,DC=Org".to_string(), role: Role::Operator },
+                LdapGroupRole { group: "cn=staff,ou=groups,dc=example,dc=org".to_string(), role: Role::Viewer },
+            ],
+            default_role: None,
+            timeout_seconds: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn binds_as_the_user_found_by_the_filter() {
+        let directory = start_directory().await;
+        let repository = LdapAuthRepository::new(config(&directory.url)).unwrap();
+
+        let user = repository.authenticate("carol", "carolpw").await.unwrap();
+
+        assert_eq!(user.username, "carol");
+        assert_eq!(user.role, Role::Admin);
+        assert_eq!(
+            *directory.binds.lock().unwrap(),
+            [SERVICE_DN, "uid=carol,ou=people,dc=example,dc=org"]
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_password_fails_authentication() {
+        let directory = start_directory().await;
+        let repository = LdapAuthRepository::new(config(&directory.url)).unwrap();
+
+        let result = repository.authenticate("carol", "wrong").await;
+
+        assert!(matches!(result, Err(DomainError::AuthenticationFailed)));
+    }
+
+    #[tokio::test]
+    async fn ambiguous_filter_refuses_login_without_binding() {
+        let directory = start_directory().await;
+        let repository = LdapAuthRepository::new(config(&directory.url)).unwrap();
+
+        let result = repository.authenticate("twin", "twinpw").await;
+
+        assert!(matches!(result, Err(DomainError::AuthenticationFailed)));
+        assert_eq!(*directory.binds.lock().unwrap(), [SERVICE_DN]);
+    }
+
+    #[tokio::test]
+    async fn group_dns_map_to_the_highest_role_ignoring_case() {
+        let directory = start_directory().await;
+        let repository = LdapAuthRepository::new(config(&directory.url)).unwrap();
+
+        let user = repository.authenticate("dan", "danpw").await.unwrap();
+
+        assert_eq!(user.role, Role::Operator);
+    }
+
+    #[tokio::test]
+    async fn user_without_mapped_group_gets_the_default_role() {
+        let directory = start_directory().await;
+        let refusing = LdapAuthRepository::new(config(&directory.url)).unwrap();
+        let defaulting = LdapAuthRepository::new(LdapConfig {
+            default_role: Some(Role::Viewer),
+            ..config(&directory.url)
+        }).unwrap();
+
+        assert!(matches!(refusing.authenticate("eve", "evepw").await, Err(DomainError::AuthenticationFailed)));
+        assert_eq!(defaulting.authenticate("eve", "evepw").await.unwrap().role, Role::Viewer);
+    }
+
+    struct LocalUser;
+
+    #[async_trait]
+    impl AuthRepository for LocalUser {
+        async fn authenticate(&self, username: &str, password: &str) -> Result<AuthUser, DomainError> {
+            match (username, password) {
+                ("carol", "localpw") => Ok(AuthUser::new(username.to_string(), Role::Viewer)),
+                _ => Err(DomainError::AuthenticationFailed),
+            }
+        }
+
+        async fn find_by_username(&self, _username: &str) -> Result<Option<AuthUser>, DomainError> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_directory_falls_back_to_the_next_backend() {
+        // A port nothing listens on
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let ldap = Arc::new(LdapAuthRepository::new(config(&url)).unwrap());
+        assert!(matches!(ldap.authenticate("carol", "localpw").await, Err(DomainError::NetworkError(_))));
+
+        let chain = ChainedAuthRepository::new(vec![
+            ("ldap".to_string(), ldap as Arc<dyn AuthRepository + Send + Sync>),
+            ("htpasswd".to_string(), Arc::new(LocalUser) as Arc<dyn AuthRepository + Send + Sync>),
+        ]);
+        let user = chain.authenticate("carol", "localpw").await.unwrap();
+
+        assert_eq!(user.username, "carol");
+        assert_eq!(user.role, Role::Viewer);
+    }
+}
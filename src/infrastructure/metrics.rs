@@ -0,0 +1,171 @@
+use prometheus::{
+    core::Collector, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::domain::{
+    models::{AppConfig, DomainError, VpnUser},
+    traits::MetricsRecorder,
+};
+
+const ROUTER_REQUEST_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const SCHEDULER_CYCLE_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+fn metrics_error(e: prometheus::Error) -> DomainError {
+    DomainError::ConfigurationError(format!("Metrics: {}", e))
+}
+
+fn register<T: Collector + Clone + 'static>(registry: &Registry, metric: prometheus::Result<T>) -> Result<T, DomainError> {
+    let metric = metric.map_err(metrics_error)?;
+    registry.register(Box::new(metric.clone())).map_err(metrics_error)?;
+    Ok(metric)
+}
+
+// Prometheus metrics in their own registry, every name prefixed with
+// `mikriting_`. Per-user series are limited to `metrics_user_series_limit`
+// users, taken in name order; 0 leaves them out
+pub struct PrometheusMetrics {
+    registry: Registry,
+    user_series_limit: usize,
+    active_sessions: IntGaugeVec,
+    user_latency: GaugeVec,
+    user_latency_avg: GaugeVec,
+    user_jitter: GaugeVec,
+    user_packet_loss: GaugeVec,
+    user_series_dropped: IntGauge,
+    router_request_duration: HistogramVec,
+    router_request_errors: IntCounterVec,
+    scheduler_cycle_duration: Histogram,
+    scheduler_cycle_failures: IntCounter,
+    websocket_connections: IntGauge,
+    logins: IntCounterVec,
+    // Gauges taken from the user list are reset and refilled on every scrape
+    render_lock: Mutex<()>,
+}
+
+impl PrometheusMetrics {
+    pub fn new(config: &AppConfig) -> Result<Self, DomainError> {
+        let registry = Registry::new_custom(Some("mikriting".to_string()), None).map_err(metrics_error)?;
+        let user_gauge = |name: &str, help: &str| {
+            register(&registry, GaugeVec::new(Opts::new(name, help), &["user", "router"]))
+        };
+
+        Ok(Self {
+            user_series_limit: config.metrics_user_series_limit,
+            active_sessions: register(&registry, IntGaugeVec::new(
+                Opts::new("active_sessions", "Active VPN sessions"),
+                &["router", "service"],
+            ))?,
+            user_latency: user_gauge("user_latency_seconds", "Round-trip time of the last probe that was answered")?,
+            user_latency_avg: user_gauge("user_latency_avg_seconds", "Average round-trip time over the ping window")?,
+            user_jitter: user_gauge("user_jitter_seconds", "Round-trip time jitter over the ping window")?,
+            user_packet_loss: user_gauge("user_packet_loss_ratio", "Share of unanswered probes over the ping window")?,
+            user_series_dropped: register(&registry, IntGauge::new(
+                "user_series_dropped",
+                "Users left out of the per-user series by metrics_user_series_limit",
+            ))?,
+            router_request_duration: register(&registry, HistogramVec::new(
+                HistogramOpts::new("router_request_duration_seconds", "Duration of requests to MikroTik routers")
+                    .buckets(ROUTER_REQUEST_BUCKETS.to_vec()),
+                &["router", "operation"],
+            ))?,
+            router_request_errors: register(&registry, IntCounterVec::new(
+                Opts::new("router_request_errors_total", "Failed requests to MikroTik routers"),
+                &["router", "operation", "kind"],
+            ))?,
+            scheduler_cycle_duration: register(&registry, Histogram::with_opts(
+                HistogramOpts::new("scheduler_cycle_duration_seconds", "Duration of scheduled user list updates")
+                    .buckets(SCHEDULER_CYCLE_BUCKETS.to_vec()),
+            ))?,
+            scheduler_cycle_failures: register(&registry, IntCounter::new(
+                "scheduler_cycle_failures_total",
+                "Scheduled user list updates that failed",
+            ))?,
+            websocket_connections: register(&registry, IntGauge::new(
+                "websocket_connections",
+                "Open dashboard WebSocket connections",
+            ))?,
+            logins: register(&registry, IntCounterVec::new(
+                Opts::new("logins_total", "Login attempts by the login step they ended in"),
+                &["method", "result"],
+            ))?,
+            render_lock: Mutex::new(()),
+            registry,
+        })
+    }
+
+    fn set_user_gauges(&self, users: &[VpnUser]) {
+        self.user_latency.reset();
+        self.user_latency_avg.reset();
+        self.user_jitter.reset();
+        self.user_packet_loss.reset();
+
+        let mut users = users.iter().collect::<Vec<_>>();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        let shown = users.len().min(self.user_series_limit);
+        self.user_series_dropped.set((users.len() - shown) as i64);
+
+        for user in &users[..shown] {
+            let labels = [user.name.as_str(), user.router.as_deref().unwrap_or_default()];
+            if let Some(latency) = user.latency {
+                self.user_latency.with_label_values(&labels).set(latency / 1000.0);
+            }
+
+            let Some(stats) = &user.latency_stats else {
+                continue;
+            };
+            if let Some(avg) = stats.avg {
+                self.user_latency_avg.with_label_values(&labels).set(avg / 1000.0);
+            }
+            if let Some(jitter) = stats.jitter {
+                self.user_jitter.with_label_values(&labels).set(jitter / 1000.0);
+            }
+            self.user_packet_loss.with_label_values(&labels).set(stats.loss_percent / 100.0);
+        }
+    }
+}
+
+impl MetricsRecorder for PrometheusMetrics {
+    fn router_request(&self, router: &str, operation: &str, duration: Duration, error: Option<&str>) {
+        self.router_request_duration
+            .with_label_values(&[router, operation])
+            .observe(duration.as_secs_f64());
+        if let Some(kind) = error {
+            self.router_request_errors.with_label_values(&[router, operation, kind]).inc();
+        }
+    }
+
+    fn scheduler_cycle(&self, duration: Duration, success: bool) {
+        self.scheduler_cycle_duration.observe(duration.as_secs_f64());
+        if !success {
+            self.scheduler_cycle_failures.inc();
+        }
+    }
+
+    fn websocket_connections(&self, count: usize) {
+        self.websocket_connections.set(count as i64);
+    }
+
+    fn login(&self, method: &str, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        self.logins.with_label_values(&[method, result]).inc();
+    }
+
+    fn render(&self, users: &[VpnUser]) -> Result<String, DomainError> {
+        let _render = self.render_lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+        self.active_sessions.reset();
+        for user in users {
+            let router = user.router.as_deref().unwrap_or_default();
+            let service = user.service.as_deref().unwrap_or_default();
+            self.active_sessions.with_label_values(&[router, service]).inc();
+        }
+        self.set_user_gauges(users);
+
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .map_err(|e| DomainError::SerializationError(e.to_string()))
+    }
+}
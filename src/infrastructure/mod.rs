@@ -2,12 +2,37 @@ pub mod cache;
 pub mod scheduler;
 pub mod config;
 pub mod auth;
+pub mod ldap;
+pub mod oidc;
 pub mod ping;
+pub mod probe;
 pub mod repository;
+pub mod sqlite;
+pub mod timeseries;
+pub mod json_file;
+pub mod token;
+pub mod totp;
+pub mod throttle;
+pub mod audit;
+pub mod metrics;
+pub mod webhook;
+pub mod alert;
 
 pub use cache::*;
 pub use scheduler::*;
 pub use config::*;
 pub use auth::*;
+pub use ldap::*;
+pub use oidc::*;
 pub use ping::*;
-pub use repository::*;
\ No newline at end of file
+pub use probe::*;
+pub use repository::*;
+pub use sqlite::*;
+pub use timeseries::*;
+pub use token::*;
+pub use totp::*;
+pub use throttle::*;
+pub use audit::*;
+pub use metrics::*;
+pub use webhook::*;
+pub use alert::*;
\ No newline at end of file
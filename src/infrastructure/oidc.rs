@@ -0,0 +1,527 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use log::{debug, info, warn};
+
+use crate::domain::{
+    models::{AuthUser, OidcAuthorization, OidcConfig, Role, DomainError},
+    traits::OidcProvider,
+};
+
+// An unknown key id triggers a JWKS refresh at most this often, so the IdP
+// can rotate keys without letting bad tokens hammer it
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+// Allowed clock difference to the identity provider
+const CLOCK_LEEWAY_SECONDS: u64 = 60;
+
+#[derive(Debug, Clone, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+fn random_token() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+fn network_error(context: &str, e: reqwest::Error) -> DomainError {
+    DomainError::NetworkError(format!("OIDC {}: {}", context, e))
+}
+
+// Authorization code flow with PKCE (RFC 7636). The provider's endpoints come
+// from its discovery document and ID tokens are checked against its JWKS;
+// both are fetched on first use and cached
+pub struct OidcClient {
+    config: OidcConfig,
+    http: Client,
+    discovery: RwLock<Option<Discovery>>,
+    jwks: RwLock<Option<(JwkSet, Instant)>>,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Result<Self, DomainError> {
+        let http = Client::builder()
+            .timeout(Duration::from_secs(config.timeout_seconds))
+            .build()
+            .map_err(|e| DomainError::ConfigurationError(format!("Failed to create OIDC HTTP client: {}", e)))?;
+
+        info!("Using OpenID Connect login with {}", config.issuer_url);
+        Ok(Self {
+            config,
+            http,
+            discovery: RwLock::new(None),
+            jwks: RwLock::new(None),
+        })
+    }
+
+    async fn discovery(&self) -> Result<Discovery, DomainError> {
+        if let Some(discovery) = self.discovery.read().await.as_ref() {
+            return Ok(discovery.clone());
+        }
+
+        let url = format!("{}/.well-known/openid-configuration", self.config.issuer_url);
+        let discovery: Discovery = self.http.get(&url).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| network_error("discovery", e))?
+            .json().await
+            .map_err(|e| network_error("discovery", e))?;
+
+        if discovery.issuer.trim_end_matches('/') != self.config.issuer_url {
+            return Err(DomainError::ConfigurationError(format!(
+                "OIDC discovery returned issuer {}, expected {}", discovery.issuer, self.config.issuer_url
+            )));
+        }
+
+        debug!("Loaded OIDC discovery document from {}", url);
+        *self.discovery.write().await = Some(discovery.clone());
+        Ok(discovery)
+    }
+
+    async fn fetch_jwks(&self, jwks_uri: &str) -> Result<JwkSet, DomainError> {
+        let jwks: JwkSet = self.http.get(jwks_uri).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| network_error("JWKS", e))?
+            .json().await
+            .map_err(|e| network_error("JWKS", e))?;
+
+        debug!("Loaded {} signing keys from {}", jwks.keys.len(), jwks_uri);
+        *self.jwks.write().await = Some((jwks.clone(), Instant::now()));
+        Ok(jwks)
+    }
+
+    async fn decoding_key(&self, kid: Option<&str>, jwks_uri: &str) -> Result<DecodingKey, DomainError> {
+        let cached = self.jwks.read().await.clone();
+        let find = |jwks: &JwkSet| match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            // Without a key id the token can only be checked against a single key
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+
+        let jwk = match cached {
+            Some((jwks, _)) if find(&jwks).is_some() => find(&jwks),
+            Some((_, fetched_at)) if fetched_at.elapsed() < JWKS_REFRESH_INTERVAL => None,
+            _ => find(&self.fetch_jwks(jwks_uri).await?),
+        };
+
+        let jwk = jwk.ok_or_else(|| {
+            warn!("No OIDC signing key found for key id {:?}", kid);
+            DomainError::AuthenticationFailed
+        })?;
+        DecodingKey::from_jwk(&jwk).map_err(|e| {
+            warn!("Unusable OIDC signing key: {}", e);
+            DomainError::AuthenticationFailed
+        })
+    }
+
+    async fn validate_id_token(&self, id_token: &str, request: &OidcAuthorization) -> Result<HashMap<String, Value>, DomainError> {
+        let rejected = |reason: String| {
+            warn!("Rejected OIDC ID token: {}", reason);
+            DomainError::AuthenticationFailed
+        };
+
+        let header = decode_header(id_token).map_err(|e| rejected(e.to_string()))?;
+        // Only signatures made with the provider's published keys are accepted
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(rejected(format!("algorithm {:?} not allowed", header.alg)));
+        }
+
+        let discovery = self.discovery().await?;
+        let key = self.decoding_key(header.kid.as_deref(), &discovery.jwks_uri).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&discovery.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.leeway = CLOCK_LEEWAY_SECONDS;
+
+        let claims = decode::<HashMap<String, Value>>(id_token, &key, &validation)
+            .map_err(|e| rejected(e.to_string()))?
+            .claims;
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(request.nonce.as_str()) {
+            return Err(rejected("nonce mismatch".to_string()));
+        }
+        Ok(claims)
+    }
+
+    // Highest role of the values of `role_claim`, which may be a string or a
+    // list of strings such as group names
+    fn resolve_role(&self, claims: &HashMap<String, Value>) -> Option<Role> {
+        let values: Vec<&str> = match claims.get(&self.config.role_claim) {
+            Some(Value::String(value)) => vec![value.as_str()],
+            Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+
+        self.config.claim_roles.iter()
+            .filter(|mapping| values.contains(&mapping.value.as_str()))
+            .map(|mapping| mapping.role)
+            .max()
+            .or(self.config.default_role)
+    }
+}
+
+#[async_trait]
+impl OidcProvider for OidcClient {
+    fn display_name(&self) -> &str {
+        &self.config.display_name
+    }
+
+    async fn authorization_url(&self) -> Result<(String, OidcAuthorization), DomainError> {
+        let discovery = self.discovery().await?;
+        let request = OidcAuthorization {
+            state: random_token(),
+            nonce: random_token(),
+            code_verifier: random_token(),
+            created_at: Utc::now(),
+        };
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(request.code_verifier.as_bytes()));
+
+        let url = Url::parse_with_params(&discovery.authorization_endpoint, &[
+            ("response_type", "code"),
+            ("client_id", self.config.client_id.as_str()),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("scope", self.config.scopes.join(" ").as_str()),
+            ("state", request.state.as_str()),
+            ("nonce", request.nonce.as_str()),
+            ("code_challenge", code_challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ])
+        .map_err(|e| DomainError::ConfigurationError(format!("Invalid OIDC authorization endpoint: {}", e)))?;
+
+        Ok((url.to_string(), request))
+    }
+
+    async fn exchange_code(&self, code: &str, request: &OidcAuthorization) -> Result<AuthUser, DomainError> {
+        let discovery = self.discovery().await?;
+        let form = [
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+            ("code_verifier", request.code_verifier.as_str()),
+        ];
+
+        // Confidential clients authenticate with HTTP basic auth
+        // (client_secret_basic), public clients only send their id
+        let mut token_request = self.http.post(&discovery.token_endpoint).form(&form);
+        if !self.config.client_secret.is_empty() {
+            token_request = token_request.basic_auth(&self.config.client_id, Some(&self.config.client_secret));
+        }
+
+        let response = token_request.send().await.map_err(|e| network_error("token request", e))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            warn!("OIDC token request failed with {}: {}", status, body);
+            return Err(DomainError::AuthenticationFailed);
+        }
+        let tokens: TokenResponse = response.json().await.map_err(|e| network_error("token response", e))?;
+
+        let claims = self.validate_id_token(&tokens.id_token, request).await?;
+        let Some(username) = claims.get(&self.config.username_claim).and_then(Value::as_str) else {
+            warn!("OIDC ID token has no {} claim", self.config.username_claim);
+            return Err(DomainError::AuthenticationFailed);
+        };
+
+        match self.resolve_role(&claims) {
+            Some(role) => {
+                debug!("OIDC authentication successful for user: {} ({})", username, role);
+                Ok(AuthUser::new(username.to_string(), role))
+            }
+            None => {
+                info!("OIDC user {} has no mapped {} value, refusing login", username, self.config.role_claim);
+                Err(DomainError::AuthenticationFailed)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::OidcClaimRole;
+    use base64::engine::general_purpose::STANDARD;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // P-256 keys made for these tests only
+    struct SigningKey {
+        kid: &'static str,
+        pkcs8: &'static str,
+        x: &'static str,
+        y: &'static str,
+    }
+
+    const KEY_1: SigningKey = SigningKey {
+        kid: "k1",
+        pkcs8: "MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQgM8NY5iUZCGw/ItDhwq9ublsq2fiRLGYf++0p6oYz7NihRANCAAQhV/T8XaFwGPNV2bPN5x/hh1VjHYBUrrJQyNOBQYZOUDKYz4A3+1WsRq/zK4lEC7QjQPAlhcEN0nA/5WFBYDxs",
+        x: "IVf0_F2hcBjzVdmzzecf4YdVYx2AVK6yUMjTgUGGTlA",
+        y: "MpjPgDf7VaxGr_MriUQLtCNA8CWFwQ3ScD_lYUFgPGw",
+    };
+
+    const KEY_2: SigningKey = SigningKey {
+        kid: "k2",
+        pkcs8: "MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQg2jJy/fAjIqgkTj6yN/MpBM6hiHTBZ1X0oMMUpf5gJ5GhRANCAAQ9nv1/4/aUA7JJdppY2FozxaHcNyeXU3lvzgRRq7bCaYj4KE0kUY3WgT3wwySt0gCdQj0SMIYrJdCrIdWFPDUk",
+        x: "PZ79f-P2lAOySXaaWNhaM8Wh3Dcnl1N5b84EUau2wmk",
+        y: "iPgoTSRRjdaBPfDDJK3SAJ1CPRIwhisl0Ksh1YU8NSQ",
+    };
+
+    impl SigningKey {
+        fn jwk(&self) -> Value {
+            json!({"kty": "EC", "crv": "P-256", "use": "sig", "alg": "ES256", "kid": self.kid, "x": self.x, "y": self.y})
+        }
+
+        fn sign(&self, claims: &Value) -> String {
+            let mut header = Header::new(Algorithm::ES256);
+            header.kid = Some(self.kid.to_string());
+            let key = EncodingKey::from_ec_der(&STANDARD.decode(self.pkcs8).unwrap());
+            encode(&header, claims, &key).unwrap()
+        }
+    }
+
+    #[derive(Default)]
+    struct IdpState {
+        jwks: Value,
+        id_token: String,
+        jwks_fetches: usize,
+    }
+
+    // Identity provider serving discovery, JWKS and a token endpoint that
+    // answers with whatever ID token the test put in place
+    struct MockIdp {
+        issuer: String,
+        state: Arc<Mutex<IdpState>>,
+    }
+
+    impl MockIdp {
+        async fn start(keys: &[&SigningKey]) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let state = Arc::new(Mutex::new(IdpState::default()));
+
+            let (base, shared) = (issuer.clone(), state.clone());
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(respond(stream, base.clone(), shared.clone()));
+                }
+            });
+
+            let idp = Self { issuer, state };
+            idp.publish(keys);
+            idp
+        }
+
+        fn publish(&self, keys: &[&SigningKey]) {
+            self.state.lock().unwrap().jwks = json!({"keys": keys.iter().map(|key| key.jwk()).collect::<Vec<_>>()});
+        }
+
+        fn issue(&self, id_token: String) {
+            self.state.lock().unwrap().id_token = id_token;
+        }
+
+        fn jwks_fetches(&self) -> usize {
+            self.state.lock().unwrap().jwks_fetches
+        }
+
+        fn claims(&self, request: &OidcAuthorization) -> Value {
+            let now = Utc::now().timestamp();
+            json!({
+                "iss": self.issuer,
+                "aud": "mikriting",
+                "sub": "frank",
+                "preferred_username": "frank",
+                "groups": ["staff", "noc"],
+                "nonce": request.nonce,
+                "iat": now,
+                "exp": now + 300,
+            })
+        }
+    }
+
+    // Whether the head and the Content-Length bytes of body have arrived
+    fn request_complete(request: &[u8]) -> bool {
+        let Some(head_end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+            return false;
+        };
+        let content_length = String::from_utf8_lossy(&request[..head_end])
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length").then(|| value.trim().parse::<usize>().ok())?
+            })
+            .unwrap_or(0);
+        request.len() >= head_end + 4 + content_length
+    }
+
+    async fn respond(mut stream: TcpStream, issuer: String, state: Arc<Mutex<IdpState>>) {
+        let mut request = Vec::new();
+        let mut chunk = [0u8; 4096];
+        while !request_complete(&request) {
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => request.extend_from_slice(&chunk[..read]),
+            }
+        }
+
+        let head = String::from_utf8_lossy(&request).into_owned();
+        let path = head.split_whitespace().nth(1).unwrap_or_default();
+        let (status, body) = match path {
+            "/.well-known/openid-configuration" => ("200 OK", json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer),
+            })),
+            "/jwks" => {
+                let mut state = state.lock().unwrap();
+                state.jwks_fetches += 1;
+                ("200 OK", state.jwks.clone())
+            }
+            "/token" => ("200 OK", json!({
+                "access_token": "opaque",
+                "token_type": "Bearer",
+                "id_token": state.lock().unwrap().id_token,
+            })),
+            _ => ("404 Not Found", json!({})),
+        };
+
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, body.len(), body
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+    }
+
+    fn config(issuer: &str) -> OidcConfig {
+        OidcConfig {
+            issuer_url: issuer.to_string(),
+            client_id: "mikriting".to_string(),
+            client_secret: String::new(),
+            redirect_url: "http://localhost:3217/login/oidc/callback".to_string(),
+            scopes: vec!["openid".to_string()],
+            username_claim: "preferred_username".to_string(),
+            role_claim: "groups".to_string(),
+            claim_roles: vec![
+                OidcClaimRole { value: "staff".to_string(), role: Role::Viewer },
+                OidcClaimRole { value: "noc".to_string(), role: Role::Operator },
+                OidcClaimRole { value: "netadmins".to_string(), role: Role::Admin },
+            ],
+            default_role: None,
+            display_name: "Mock IdP".to_string(),
+            timeout_seconds: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn maps_claim_values_to_the_highest_role() {
+        let idp = MockIdp::start(&[&KEY_1]).await;
+        let client = OidcClient::new(config(&idp.issuer)).unwrap();
+        let (_, request) = client.authorization_url().await.unwrap();
+
+        idp.issue(KEY_1.sign(&idp.claims(&request)));
+        let user = client.exchange_code("code", &request).await.unwrap();
+        assert_eq!(user.username, "frank");
+        assert_eq!(user.role, Role::Operator);
+
+        // A single string works like a list
+        let mut claims = idp.claims(&request);
+        claims["groups"] = json!("netadmins");
+        idp.issue(KEY_1.sign(&claims));
+        assert_eq!(client.exchange_code("code", &request).await.unwrap().role, Role::Admin);
+
+        claims["groups"] = json!(["guests"]);
+        idp.issue(KEY_1.sign(&claims));
+        assert!(matches!(client.exchange_code("code", &request).await, Err(DomainError::AuthenticationFailed)));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_nonce_mismatch() {
+        let idp = MockIdp::start(&[&KEY_1]).await;
+        let client = OidcClient::new(config(&idp.issuer)).unwrap();
+        let (_, request) = client.authorization_url().await.unwrap();
+
+        let mut claims = idp.claims(&request);
+        claims["nonce"] = json!("replayed");
+        idp.issue(KEY_1.sign(&claims));
+
+        assert!(matches!(client.exchange_code("code", &request).await, Err(DomainError::AuthenticationFailed)));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_audience_or_issuer() {
+        let idp = MockIdp::start(&[&KEY_1]).await;
+        let client = OidcClient::new(config(&idp.issuer)).unwrap();
+        let (_, request) = client.authorization_url().await.unwrap();
+
+        let mut claims = idp.claims(&request);
+        claims["aud"] = json!("another-client");
+        idp.issue(KEY_1.sign(&claims));
+        assert!(matches!(client.exchange_code("code", &request).await, Err(DomainError::AuthenticationFailed)));
+
+        let mut claims = idp.claims(&request);
+        claims["iss"] = json!("https://evil.example.com");
+        idp.issue(KEY_1.sign(&claims));
+        assert!(matches!(client.exchange_code("code", &request).await, Err(DomainError::AuthenticationFailed)));
+    }
+
+    #[tokio::test]
+    async fn rejects_hmac_signed_tokens() {
+        let idp = MockIdp::start(&[&KEY_1]).await;
+        let client = OidcClient::new(config(&idp.issuer)).unwrap();
+        let (_, request) = client.authorization_url().await.unwrap();
+
+        // Signed with a secret an attacker may know, such as the client ID
+        for algorithm in [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512] {
+            let token = encode(&Header::new(algorithm), &idp.claims(&request), &EncodingKey::from_secret(b"mikriting")).unwrap();
+            idp.issue(token);
+            assert!(matches!(client.exchange_code("code", &request).await, Err(DomainError::AuthenticationFailed)));
+        }
+        assert_eq!(idp.jwks_fetches(), 0);
+    }
+
+    #[tokio::test]
+    async fn refreshes_the_jwks_for_an_unknown_key_id() {
+        let idp = MockIdp::start(&[&KEY_1]).await;
+        let client = OidcClient::new(config(&idp.issuer)).unwrap();
+        let (_, request) = client.authorization_url().await.unwrap();
+
+        idp.issue(KEY_1.sign(&idp.claims(&request)));
+        client.exchange_code("code", &request).await.unwrap();
+        assert_eq!(idp.jwks_fetches(), 1);
+
+        // The provider rotates its key; right after a fetch the unknown key
+        // id is refused without asking again
+        idp.publish(&[&KEY_2]);
+        idp.issue(KEY_2.sign(&idp.claims(&request)));
+        assert!(matches!(client.exchange_code("code", &request).await, Err(DomainError::AuthenticationFailed)));
+        assert_eq!(idp.jwks_fetches(), 1);
+
+        // Once the refresh interval has passed the new key is fetched
+        if let Some((_, fetched_at)) = client.jwks.write().await.as_mut() {
+            *fetched_at = Instant::now() - JWKS_REFRESH_INTERVAL;
+        }
+        let user = client.exchange_code("code", &request).await.unwrap();
+        assert_eq!(user.username, "frank");
+        assert_eq!(idp.jwks_fetches(), 2);
+    }
+}
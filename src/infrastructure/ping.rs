@@ -1,105 +1,187 @@
 use async_trait::async_trait;
-use std::collections::HashMap;
+use futures::stream::{self, StreamExt};
+use std::collections::{BTreeSet, HashMap};
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, watch};
+use tokio::task::JoinHandle;
 use tokio::time::interval;
-use tokio_icmp_echo::Pinger;
 use log::{debug, error, info, warn};
 
 use crate::domain::{
-    models::{VpnUser, LatencyUpdate, DomainError},
-    traits::PingService,
+    models::{VpnUser, SessionKey, LatencyUpdate, LatencyWindow, AppConfig, ProbeHealth, ProbeMethod, ProbeTarget, DomainError},
+    traits::{PingService, Probe},
 };
+use crate::infrastructure::{DatagramIcmpProbe, IcmpProbe, TcpProbe};
 use crate::usecase::VpnUserUseCase;
 
 pub struct PingMonitor {
-    pinger: Pinger,
-    monitored_users: Arc<RwLock<HashMap<String, VpnUser>>>,
-    use_case: Arc<VpnUserUseCase>,
+    probes: HashMap<ProbeMethod, Arc<dyn Probe + Send + Sync>>,
+    default_probe: ProbeTarget,
+    monitored_users: Arc<RwLock<HashMap<SessionKey, (VpnUser, ProbeTarget)>>>,
+    windows: Arc<RwLock<HashMap<SessionKey, LatencyWindow>>>,
     ping_interval: Duration,
+    ping_timeout: Duration,
+    concurrency: usize,
+    window_size: usize,
+}
+
+// Running monitoring loop, stopped through `stop`
+pub struct PingMonitorHandle {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl PingMonitorHandle {
+    pub async fn stop(self) {
+        info!("Stopping ping monitor");
+        let _ = self.shutdown.send(true);
+        
+        if let Err(e) = self.task.await {
+            error!("Ping monitor task failed: {}", e);
+        }
+    }
 }
 
 impl PingMonitor {
-    pub async fn new(
-        use_case: Arc<VpnUserUseCase>,
-        ping_interval_seconds: u64,
-    ) -> Result<Self, DomainError> {
-        let pinger = Pinger::new()
-            .await
-            .map_err(|e| DomainError::NetworkError(format!("Failed to create pinger: {}", e)))?;
-        
-        Ok(Self {
-            pinger,
+    pub fn new(config: &AppConfig) -> Self {
+        let probes: HashMap<ProbeMethod, Arc<dyn Probe + Send + Sync>> = HashMap::from([
+            (ProbeMethod::Icmp, Arc::new(IcmpProbe::new()) as Arc<dyn Probe + Send + Sync>),
+            (ProbeMethod::IcmpDgram, Arc::new(DatagramIcmpProbe) as Arc<dyn Probe + Send + Sync>),
+            (ProbeMethod::Tcp, Arc::new(TcpProbe) as Arc<dyn Probe + Send + Sync>),
+        ]);
+        
+        Self {
+            probes,
+            default_probe: config.default_probe(),
             monitored_users: Arc::new(RwLock::new(HashMap::new())),
-            use_case,
-            ping_interval: Duration::from_secs(ping_interval_seconds),
-        })
+            windows: Arc::new(RwLock::new(HashMap::new())),
+            ping_interval: Duration::from_secs(config.ping_interval_seconds),
+            ping_timeout: Duration::from_millis(config.ping_timeout_ms),
+            concurrency: config.ping_concurrency.max(1),
+            window_size: config.ping_window_size,
+        }
     }
 
-    #[allow(dead_code)]
-    pub async fn start_monitoring_loop(&self) {
+    // The use case is passed in here rather than at construction because it
+    // owns this monitor as its PingService
+    pub fn start(self: &Arc<Self>, use_case: Arc<VpnUserUseCase>) -> PingMonitorHandle {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let monitor = self.clone();
+        
+        let task = tokio::spawn(async move {
+            monitor.start_monitoring_loop(use_case, shutdown_rx).await;
+        });
+        
+        info!(
+            "Ping monitor started (interval: {:?}, timeout: {:?}, concurrency: {}, default probe: {:?})",
+            self.ping_interval, self.ping_timeout, self.concurrency, self.default_probe
+        );
+        PingMonitorHandle { shutdown, task }
+    }
+
+    async fn start_monitoring_loop(&self, use_case: Arc<VpnUserUseCase>, mut shutdown: watch::Receiver<bool>) {
         let mut interval = interval(self.ping_interval);
         
         loop {
-            interval.tick().await;
-            
-            let users = {
-                let monitored = self.monitored_users.read().await;
-                monitored.values().cloned().collect::<Vec<_>>()
-            };
-            
-            if users.is_empty() {
-                continue;
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = interval.tick() => {}
             }
             
-            debug!("Pinging {} users", users.len());
-            
-            for user in users {
-                if let Some(ip_addr) = user.get_ip_address() {
-                    let latency = self.ping_single_user(&ip_addr).await;
-                    
-                    let update = LatencyUpdate {
-                        user_name: user.name.clone(),
-                        latency,
-                    };
-                    
-                    if let Err(e) = self.use_case.update_user_latency(update).await {
-                        error!("Failed to update latency for user {}: {}", user.name, e);
-                    }
-                }
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = self.ping_cycle(&use_case) => {}
             }
         }
+        
+        info!("Ping monitor stopped");
     }
 
-    async fn ping_single_user(&self, ip_addr: &IpAddr) -> Option<f64> {
-        let ident = rand::random();
-        let seq = rand::random();
-        let timeout = Duration::from_secs(1);
-        
-        match self.pinger.ping(*ip_addr, ident, seq, timeout).await {
-            Ok(Some(duration)) => {
-                let latency_ms = duration.as_micros() as f64 / 1000.0;
-                Some(latency_ms)
-            }
+    async fn ping_cycle(&self, use_case: &VpnUserUseCase) {
+        let users = {
+            let monitored = self.monitored_users.read().await;
+            monitored.values().cloned().collect::<Vec<_>>()
+        };
+        
+        if users.is_empty() {
+            return;
+        }
+        
+        debug!("Pinging {} users", users.len());
+        
+        // Probe up to `concurrency` users at once so a cycle takes roughly
+        // users / concurrency timeouts instead of one timeout per user
+        stream::iter(users)
+            .for_each_concurrent(self.concurrency, |(user, target)| async move {
+                let Some(ip_addr) = user.get_ip_address() else {
+                    return;
+                };
+                
+                let latency = self.probe(ip_addr, target).await;
+                let Some(update) = self.record_sample(&user, latency).await else {
+                    debug!("Dropping probe result of {}, no longer monitored", user.session_key());
+                    return;
+                };
+                
+                if let Err(e) = use_case.update_user_latency(update).await {
+                    error!("Failed to update latency for user {}: {}", user.name, e);
+                }
+            })
+            .await;
+    }
+
+    // Windows exist from start_monitoring until stop_monitoring, a probe
+    // that outlived its user has nothing to record into
+    async fn record_sample(&self, user: &VpnUser, latency: Option<f64>) -> Option<LatencyUpdate> {
+        let mut windows = self.windows.write().await;
+        let stats = windows.get_mut(&user.session_key())?.push(latency);
+        
+        Some(LatencyUpdate {
+            user_name: user.name.clone(),
+            router: user.router.clone(),
+            session_id: user.session_id.clone(),
+            latency,
+            stats,
+        })
+    }
+
+    async fn probe(&self, ip_addr: IpAddr, target: ProbeTarget) -> Option<f64> {
+        let probe = self.probes.get(&target.method)?;
+        
+        match probe.probe(ip_addr, target.port, self.ping_timeout).await {
+            Ok(Some(latency_ms)) => Some(latency_ms),
             Ok(None) => {
-                debug!("Ping timeout for {}", ip_addr);
+                debug!("Probe timeout for {} ({:?})", ip_addr, target.method);
                 None
             }
             Err(e) => {
-                debug!("Ping error for {}: {}", ip_addr, e);
+                debug!("Probe error for {} ({:?}): {}", ip_addr, target.method, e);
                 None
             }
         }
     }
+
+    // The comment tag wins over the global default; a malformed tag is
+    // reported once here and the default is used instead
+    fn probe_target(&self, user: &VpnUser) -> ProbeTarget {
+        match user.probe_tag().map(|tag| ProbeTarget::parse(tag, self.default_probe.port)) {
+            Some(Ok(target)) => target,
+            Some(Err(e)) => {
+                warn!("Ignoring probe tag for user {}: {}", user.name, e);
+                self.default_probe
+            }
+            None => self.default_probe,
+        }
+    }
 }
 
 #[async_trait]
 impl PingService for PingMonitor {
     async fn ping_user(&self, user: &VpnUser) -> Result<Option<f64>, DomainError> {
         if let Some(ip_addr) = user.get_ip_address() {
-            Ok(self.ping_single_user(&ip_addr).await)
+            Ok(self.probe(ip_addr, self.probe_target(user)).await)
         } else {
             Err(DomainError::InvalidIpAddress(user.address.clone()))
         }
@@ -110,22 +192,44 @@ impl PingService for PingMonitor {
             return Err(DomainError::InvalidIpAddress(user.address.clone()));
         }
         
+        let target = self.probe_target(user);
         let mut monitored = self.monitored_users.write().await;
-        monitored.insert(user.name.clone(), user.clone());
+        monitored.insert(user.session_key(), (user.clone(), target));
+        self.windows.write().await
+            .entry(user.session_key())
+            .or_insert_with(|| LatencyWindow::new(self.window_size));
         
-        info!("Started monitoring user: {} ({})", user.name, user.address);
+        info!("Started monitoring user: {} ({}, {:?})", user.session_key(), user.address, target.method);
         Ok(())
     }
 
-    async fn stop_monitoring(&self, user_name: &str) -> Result<(), DomainError> {
+    async fn stop_monitoring(&self, session: &SessionKey) -> Result<(), DomainError> {
         let mut monitored = self.monitored_users.write().await;
         
-        if monitored.remove(user_name).is_some() {
-            info!("Stopped monitoring user: {}", user_name);
+        if monitored.remove(session).is_some() {
+            self.windows.write().await.remove(session);
+            info!("Stopped monitoring user: {}", session);
         } else {
-            warn!("Attempted to stop monitoring non-existent user: {}", user_name);
+            warn!("Attempted to stop monitoring non-existent user: {}", session);
         }
         
         Ok(())
     }
+
+    async fn probe_health(&self) -> Vec<ProbeHealth> {
+        let mut methods = BTreeSet::from([self.default_probe.method]);
+        methods.extend(self.monitored_users.read().await.values().map(|(_, target)| target.method));
+        
+        let mut health = Vec::new();
+        for method in methods {
+            let available = match self.probes.get(&method) {
+                Some(probe) => probe.check().await
+                    .inspect_err(|e| debug!("Probe {:?} unavailable: {}", method, e))
+                    .is_ok(),
+                None => false,
+            };
+            health.push(ProbeHealth { method, available });
+        }
+        health
+    }
 }
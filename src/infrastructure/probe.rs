@@ -0,0 +1,181 @@
+use async_trait::async_trait;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::OnceCell;
+use tokio::time::timeout;
+use tokio_icmp_echo::Pinger;
+use log::{debug, warn};
+
+use crate::domain::{
+    models::DomainError,
+    traits::Probe,
+};
+
+const ECHO_PAYLOAD: &[u8] = b"mikriting-probe!";
+
+fn elapsed_ms(started: Instant) -> f64 {
+    started.elapsed().as_micros() as f64 / 1000.0
+}
+
+fn network_error(context: &str, err: io::Error) -> DomainError {
+    DomainError::NetworkError(format!("{}: {}", context, err))
+}
+
+// Raw-socket ICMP echo. The pinger is created on first use so the service
+// still starts without CAP_NET_RAW when only other probes are configured
+pub struct IcmpProbe {
+    pinger: OnceCell<Option<Pinger>>,
+}
+
+impl IcmpProbe {
+    pub fn new() -> Self {
+        Self {
+            pinger: OnceCell::new(),
+        }
+    }
+
+    async fn pinger(&self) -> Result<&Pinger, DomainError> {
+        self.pinger
+            .get_or_init(|| async {
+                Pinger::new()
+                    .await
+                    .inspect_err(|e| warn!("ICMP probe unavailable, raw sockets need CAP_NET_RAW: {}", e))
+                    .ok()
+            })
+            .await
+            .as_ref()
+            .ok_or_else(|| DomainError::NetworkError("ICMP pinger unavailable".to_string()))
+    }
+}
+
+impl Default for IcmpProbe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Probe for IcmpProbe {
+    async fn probe(&self, addr: IpAddr, _port: u16, timeout: Duration) -> Result<Option<f64>, DomainError> {
+        let pinger = self.pinger().await?;
+
+        match pinger.ping(addr, rand::random(), rand::random(), timeout).await {
+            Ok(Some(duration)) => Ok(Some(duration.as_micros() as f64 / 1000.0)),
+            Ok(None) => Ok(None),
+            Err(e) => Err(DomainError::NetworkError(format!("Ping error for {}: {}", addr, e))),
+        }
+    }
+
+    async fn check(&self) -> Result<(), DomainError> {
+        self.pinger().await.map(|_| ())
+    }
+}
+
+// ICMP echo over an unprivileged datagram socket. The kernel fills in the
+// identifier and only delivers replies for this socket to it
+pub struct DatagramIcmpProbe;
+
+impl DatagramIcmpProbe {
+    fn open_socket(addr: &IpAddr) -> io::Result<UdpSocket> {
+        let (domain, protocol) = match addr {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+
+        let socket = Socket::new(domain, Type::DGRAM, Some(protocol))?;
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into())
+    }
+}
+
+fn echo_request(request_type: u8, sequence: u16) -> Vec<u8> {
+    let mut packet = vec![request_type, 0, 0, 0, 0, 0];
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(ECHO_PAYLOAD);
+
+    let checksum = icmp_checksum(&packet);
+    packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+fn icmp_checksum(packet: &[u8]) -> u16 {
+    let mut sum: u32 = packet
+        .chunks(2)
+        .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)])))
+        .sum();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[async_trait]
+impl Probe for DatagramIcmpProbe {
+    async fn probe(&self, addr: IpAddr, _port: u16, timeout_duration: Duration) -> Result<Option<f64>, DomainError> {
+        let (request_type, reply_type) = match addr {
+            IpAddr::V4(_) => (8, 0),
+            IpAddr::V6(_) => (128, 129),
+        };
+
+        let socket = Self::open_socket(&addr)
+            .map_err(|e| network_error("Failed to open ICMP datagram socket (see net.ipv4.ping_group_range)", e))?;
+        let sequence: u16 = rand::random();
+
+        let started = Instant::now();
+        socket.send_to(&echo_request(request_type, sequence), SocketAddr::new(addr, 0))
+            .await
+            .map_err(|e| network_error("Failed to send ICMP echo", e))?;
+
+        // Replies arrive without the IP header on Linux ping sockets
+        let wait_reply = async {
+            let mut buffer = [0u8; 1500];
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer).await?;
+                if from.ip() == addr
+                    && len >= 8
+                    && buffer[0] == reply_type
+                    && u16::from_be_bytes([buffer[6], buffer[7]]) == sequence
+                {
+                    return Ok::<_, io::Error>(());
+                }
+            }
+        };
+
+        match timeout(timeout_duration, wait_reply).await {
+            Ok(Ok(())) => Ok(Some(elapsed_ms(started))),
+            Ok(Err(e)) => Err(network_error("Failed to receive ICMP echo reply", e)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn check(&self) -> Result<(), DomainError> {
+        Self::open_socket(&IpAddr::from([127, 0, 0, 1]))
+            .map(|_| ())
+            .map_err(|e| network_error("Failed to open ICMP datagram socket (see net.ipv4.ping_group_range)", e))
+    }
+}
+
+// TCP connect probe. A refused connection means the host answered with a
+// RST, so it counts as reachable just like an accepted one
+pub struct TcpProbe;
+
+#[async_trait]
+impl Probe for TcpProbe {
+    async fn probe(&self, addr: IpAddr, port: u16, timeout_duration: Duration) -> Result<Option<f64>, DomainError> {
+        let started = Instant::now();
+
+        match timeout(timeout_duration, TcpStream::connect((addr, port))).await {
+            Ok(Ok(_)) => Ok(Some(elapsed_ms(started))),
+            Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(Some(elapsed_ms(started))),
+            Ok(Err(e)) => {
+                debug!("TCP probe to {}:{} failed: {}", addr, port, e);
+                Ok(None)
+            }
+            Err(_) => Ok(None),
+        }
+    }
+}
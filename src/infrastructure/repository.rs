@@ -1,21 +1,30 @@
 use async_trait::async_trait;
-use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
 use crate::domain::{
-    models::{VpnUser, DomainError},
+    models::{VpnUser, VpnSession, SessionKey, DomainError},
     traits::VpnUserRepository,
 };
 
+// Upper bound for the in-memory session history
+const MAX_SESSIONS: usize = 10_000;
+
+// Open sessions with the time of the poll they were last seen in
+type OpenSessions = HashMap<SessionKey, (VpnUser, DateTime<Utc>)>;
+
 pub struct InMemoryVpnUserRepository {
-    users: Arc<RwLock<HashMap<String, VpnUser>>>,
+    users: Arc<RwLock<OpenSessions>>,
+    sessions: Arc<RwLock<VecDeque<VpnSession>>>,
 }
 
 impl InMemoryVpnUserRepository {
     pub fn new() -> Self {
         Self {
             users: Arc::new(RwLock::new(HashMap::new())),
+            sessions: Arc::new(RwLock::new(VecDeque::new())),
         }
     }
 }
@@ -26,41 +35,91 @@ impl Default for InMemoryVpnUserRepository {
     }
 }
 
+fn close_session(sessions: &mut VecDeque<VpnSession>, user: &VpnUser, last_seen_at: DateTime<Utc>) {
+    if let Some(session) = sessions.iter_mut()
+        .rev()
+        .find(|s| s.disconnected_at.is_none() && s.router == user.router && s.session_id == user.session_id && s.name == user.name)
+    {
+        session.disconnected_at = Some(last_seen_at);
+        session.final_uptime = Some(user.uptime.clone());
+    }
+}
+
 #[async_trait]
 impl VpnUserRepository for InMemoryVpnUserRepository {
     async fn find_all(&self) -> Result<Vec<VpnUser>, DomainError> {
         let users = self.users.read().await;
-        Ok(users.values().cloned().collect())
+        Ok(users.values().map(|(user, _)| user.clone()).collect())
     }
 
     async fn find_by_name(&self, name: &str) -> Result<Option<VpnUser>, DomainError> {
         let users = self.users.read().await;
-        Ok(users.get(name).cloned())
+        Ok(users.values().map(|(user, _)| user).find(|user| user.name == name).cloned())
     }
 
     async fn save(&self, user: &VpnUser) -> Result<(), DomainError> {
+        let now = Utc::now();
         let mut users = self.users.write().await;
-        users.insert(user.name.clone(), user.clone());
+        let mut sessions = self.sessions.write().await;
+
+        let started = match users.get(&user.session_key()) {
+            Some((stored, _)) if user.continues(stored) => false,
+            // Reconnected between two polls
+            Some((stored, last_seen_at)) => {
+                close_session(&mut sessions, stored, *last_seen_at);
+                true
+            }
+            None => true,
+        };
+
+        if started {
+            sessions.push_back(VpnSession::open(user, now));
+
+            if sessions.len() > MAX_SESSIONS {
+                sessions.pop_front();
+            }
+        }
+        users.insert(user.session_key(), (user.clone(), now));
+
         Ok(())
     }
 
-    async fn delete(&self, name: &str) -> Result<(), DomainError> {
+    async fn delete(&self, user: &VpnUser) -> Result<(), DomainError> {
         let mut users = self.users.write().await;
-        if users.remove(name).is_some() {
-            Ok(())
-        } else {
-            Err(DomainError::UserNotFound(name.to_string()))
-        }
+        let Some((stored, last_seen_at)) = users.remove(&user.session_key()) else {
+            return Err(DomainError::UserNotFound(user.name.clone()));
+        };
+
+        let mut sessions = self.sessions.write().await;
+        close_session(&mut sessions, &stored, last_seen_at);
+
+        Ok(())
     }
 
     async fn update_latency(&self, name: &str, latency: Option<f64>) -> Result<(), DomainError> {
         let mut users = self.users.write().await;
-        
-        if let Some(user) = users.get_mut(name) {
+        let mut found = false;
+
+        for (user, _) in users.values_mut().filter(|(user, _)| user.name == name) {
             user.latency = latency;
+            found = true;
+        }
+
+        if found {
             Ok(())
         } else {
             Err(DomainError::UserNotFound(name.to_string()))
         }
     }
+
+    async fn find_sessions(&self, name: &str, limit: usize) -> Result<Vec<VpnSession>, DomainError> {
+        let sessions = self.sessions.read().await;
+
+        Ok(sessions.iter()
+            .rev()
+            .filter(|s| s.name == name)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
 }
@@ -1,19 +1,27 @@
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::time::{interval, Interval};
 use log::{info, error, debug};
 
-use crate::usecase::VpnUserUseCase;
+use crate::domain::traits::MetricsRecorder;
+use crate::usecase::{HealthUseCase, VpnUserUseCase};
 
 pub struct VpnUserScheduler {
     use_case: Arc<VpnUserUseCase>,
     interval: Interval,
+    metrics: Arc<dyn MetricsRecorder + Send + Sync>,
+    health: Arc<HealthUseCase>,
 }
 
 impl VpnUserScheduler {
-    pub fn new(use_case: Arc<VpnUserUseCase>, interval_seconds: u64) -> Self {
+    pub fn new(
+        use_case: Arc<VpnUserUseCase>,
+        interval_seconds: u64,
+        metrics: Arc<dyn MetricsRecorder + Send + Sync>,
+        health: Arc<HealthUseCase>,
+    ) -> Self {
         let interval = interval(Duration::from_secs(interval_seconds));
-        Self { use_case, interval }
+        Self { use_case, interval, metrics, health }
     }
 
     pub async fn start(&mut self) {
@@ -22,7 +30,12 @@ impl VpnUserScheduler {
         loop {
             self.interval.tick().await;
             
-            match self.use_case.fetch_and_update_users().await {
+            let started = Instant::now();
+            let result = self.use_case.fetch_and_update_users().await;
+            self.metrics.scheduler_cycle(started.elapsed(), result.is_ok());
+            self.health.record_cycle(result.is_ok()).await;
+            
+            match result {
                 Ok(users) => {
                     debug!("Scheduled update completed: {} users", users.len());
                 }
@@ -30,6 +43,10 @@ impl VpnUserScheduler {
                     error!("Scheduled update failed: {}", e);
                 }
             }
+            
+            if let Err(e) = self.use_case.prune_latency_history().await {
+                error!("Failed to prune latency history: {}", e);
+            }
         }
     }
 }
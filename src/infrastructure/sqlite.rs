@@ -0,0 +1,261 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use std::sync::{Arc, Mutex};
+use tokio::task;
+use log::{debug, info};
+
+use crate::domain::{
+    models::{VpnUser, VpnSession, DomainError},
+    traits::VpnUserRepository,
+};
+
+// Schema migrations, applied in order and tracked through PRAGMA user_version
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE vpn_sessions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        router TEXT,
+        service TEXT,
+        caller_id TEXT,
+        address TEXT NOT NULL,
+        comment TEXT,
+        uptime TEXT NOT NULL,
+        latency REAL,
+        connected_at TEXT NOT NULL,
+        last_seen_at TEXT NOT NULL,
+        disconnected_at TEXT
+    );
+    CREATE INDEX idx_vpn_sessions_name ON vpn_sessions (name, connected_at DESC);
+    CREATE INDEX idx_vpn_sessions_open ON vpn_sessions (name) WHERE disconnected_at IS NULL;",
+    // Sessions are told apart by their RouterOS ID, the same name may be
+    // connected to several routers or reconnect between two polls
+    "ALTER TABLE vpn_sessions ADD COLUMN session_id TEXT;
+    DROP INDEX idx_vpn_sessions_open;
+    CREATE INDEX idx_vpn_sessions_open ON vpn_sessions (router, name) WHERE disconnected_at IS NULL;",
+];
+
+const USER_COLUMNS: &str = "name, router, session_id, service, caller_id, address, comment, uptime, latency";
+
+// Open row of a session: same router, RouterOS ID and name
+const OPEN_SESSION: &str = "router IS ?1 AND session_id IS ?2 AND name = ?3 AND disconnected_at IS NULL";
+
+pub struct SqliteVpnUserRepository {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteVpnUserRepository {
+    pub fn open(path: &str) -> Result<Self, DomainError> {
+        let mut connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+
+        Self::migrate(&mut connection)?;
+        info!("Opened SQLite database at {}", path);
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    fn migrate(connection: &mut Connection) -> Result<(), DomainError> {
+        let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = connection.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", index + 1)?;
+            tx.commit()?;
+            info!("Applied database migration {}", index + 1);
+        }
+
+        Ok(())
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> Result<T, DomainError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, DomainError> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+
+        task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| DomainError::StorageError("Database connection poisoned".to_string()))?;
+            f(&mut connection)
+        })
+        .await
+        .map_err(|e| DomainError::StorageError(format!("Database task failed: {}", e)))?
+    }
+}
+
+fn user_from_row(row: &Row<'_>) -> rusqlite::Result<VpnUser> {
+    let mut user = VpnUser::new(
+        row.get("name")?,
+        row.get("service")?,
+        row.get("caller_id")?,
+        row.get("address")?,
+        row.get("uptime")?,
+        row.get("comment")?,
+    );
+    user.router = row.get("router")?;
+    user.session_id = row.get("session_id")?;
+    user.latency = row.get("latency")?;
+    Ok(user)
+}
+
+fn session_from_row(row: &Row<'_>) -> rusqlite::Result<VpnSession> {
+    let disconnected_at: Option<DateTime<Utc>> = row.get("disconnected_at")?;
+    let uptime: String = row.get("uptime")?;
+
+    Ok(VpnSession {
+        name: row.get("name")?,
+        router: row.get("router")?,
+        session_id: row.get("session_id")?,
+        service: row.get("service")?,
+        caller_id: row.get("caller_id")?,
+        address: row.get("address")?,
+        comment: row.get("comment")?,
+        connected_at: row.get("connected_at")?,
+        final_uptime: disconnected_at.map(|_| uptime),
+        disconnected_at,
+    })
+}
+
+#[async_trait]
+impl VpnUserRepository for SqliteVpnUserRepository {
+    async fn find_all(&self) -> Result<Vec<VpnUser>, DomainError> {
+        self.with_connection(|conn| {
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT {} FROM vpn_sessions WHERE disconnected_at IS NULL", USER_COLUMNS
+            ))?;
+            let users = stmt.query_map([], user_from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(users)
+        }).await
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<Option<VpnUser>, DomainError> {
+        let name = name.to_string();
+
+        self.with_connection(move |conn| {
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT {} FROM vpn_sessions WHERE name = ?1 AND disconnected_at IS NULL", USER_COLUMNS
+            ))?;
+            Ok(stmt.query_row(params![name], user_from_row).optional()?)
+        }).await
+    }
+
+    // A poll whose uptime is below the stored one is a reconnect between two
+    // polls, the old row is closed and a new one started
+    async fn save(&self, user: &VpnUser) -> Result<(), DomainError> {
+        let user = user.clone();
+
+        self.with_connection(move |conn| {
+            let now = Utc::now();
+            let tx = conn.transaction()?;
+
+            let open = tx.prepare_cached(&format!(
+                "SELECT id, {} FROM vpn_sessions WHERE {}", USER_COLUMNS, OPEN_SESSION
+            ))?
+                .query_row(params![user.router, user.session_id, user.name], |row| {
+                    Ok((row.get::<_, i64>("id")?, user_from_row(row)?))
+                })
+                .optional()?;
+
+            match open {
+                Some((id, stored)) if user.continues(&stored) => {
+                    tx.execute(
+                        "UPDATE vpn_sessions
+                         SET service = ?2, caller_id = ?3, address = ?4, comment = ?5,
+                             uptime = ?6, last_seen_at = ?7
+                         WHERE id = ?1",
+                        params![id, user.service, user.caller_id, user.address,
+                                user.comment, user.uptime, now],
+                    )?;
+                }
+                open => {
+                    if let Some((id, _)) = open {
+                        tx.execute(
+                            "UPDATE vpn_sessions SET disconnected_at = last_seen_at WHERE id = ?1",
+                            params![id],
+                        )?;
+                        debug!("User {} reconnected since the last poll", user.name);
+                    }
+
+                    tx.execute(
+                        "INSERT INTO vpn_sessions
+                            (name, router, session_id, service, caller_id, address, comment, uptime,
+                             latency, connected_at, last_seen_at)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?10)",
+                        params![user.name, user.router, user.session_id, user.service, user.caller_id,
+                                user.address, user.comment, user.uptime, user.latency, now],
+                    )?;
+                    debug!("Recorded new session for user {}", user.name);
+                }
+            }
+
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    // The session ended after the poll it was last seen in, which for those
+    // that ended while the application was down may be long ago
+    async fn delete(&self, user: &VpnUser) -> Result<(), DomainError> {
+        let user = user.clone();
+
+        self.with_connection(move |conn| {
+            let closed = conn.execute(
+                &format!("UPDATE vpn_sessions SET disconnected_at = last_seen_at WHERE {}", OPEN_SESSION),
+                params![user.router, user.session_id, user.name],
+            )?;
+
+            if closed == 0 {
+                return Err(DomainError::UserNotFound(user.name));
+            }
+
+            debug!("Closed session for user {}", user.name);
+            Ok(())
+        }).await
+    }
+
+    async fn update_latency(&self, name: &str, latency: Option<f64>) -> Result<(), DomainError> {
+        let name = name.to_string();
+
+        self.with_connection(move |conn| {
+            let updated = conn.execute(
+                "UPDATE vpn_sessions SET latency = ?2 WHERE name = ?1 AND disconnected_at IS NULL",
+                params![name, latency],
+            )?;
+
+            if updated == 0 {
+                return Err(DomainError::UserNotFound(name));
+            }
+            Ok(())
+        }).await
+    }
+
+    async fn find_sessions(&self, name: &str, limit: usize) -> Result<Vec<VpnSession>, DomainError> {
+        let name = name.to_string();
+
+        self.with_connection(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT name, router, session_id, service, caller_id, address, comment, uptime,
+                        connected_at, disconnected_at
+                 FROM vpn_sessions WHERE name = ?1
+                 ORDER BY connected_at DESC LIMIT ?2",
+            )?;
+            let sessions = stmt.query_map(params![name, limit as i64], session_from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(sessions)
+        }).await
+    }
+}
+
+impl From<rusqlite::Error> for DomainError {
+    fn from(err: rusqlite::Error) -> Self {
+        DomainError::StorageError(err.to_string())
+    }
+}
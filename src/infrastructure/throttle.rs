@@ -0,0 +1,149 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use log::warn;
+
+use crate::domain::{
+    models::{AppConfig, DomainError, LockoutScope, LoginLockout},
+    traits::LoginThrottle,
+};
+
+#[derive(Debug, Clone)]
+struct Attempts {
+    failures: u32,
+    last_failure_at: DateTime<Utc>,
+    blocked_until: Option<DateTime<Utc>>,
+}
+
+// Counts failed logins in memory, so a restart forgets them. Every failure
+// doubles the wait before the next attempt; reaching the limit locks the IP
+// or username for `login_lockout_minutes`. Counters are forgotten once no
+// failure happened for that long
+pub struct InMemoryLoginThrottle {
+    attempts: Arc<RwLock<HashMap<(LockoutScope, String), Attempts>>>,
+    max_failures: u32,
+    max_failures_per_ip: u32,
+    lockout: Duration,
+    backoff_seconds: u64,
+}
+
+impl InMemoryLoginThrottle {
+    pub fn new(config: &AppConfig) -> Self {
+        Self {
+            attempts: Arc::new(RwLock::new(HashMap::new())),
+            max_failures: config.login_max_failures,
+            max_failures_per_ip: config.login_max_failures_per_ip,
+            lockout: Duration::minutes(config.login_lockout_minutes as i64),
+            backoff_seconds: config.login_backoff_seconds,
+        }
+    }
+
+    fn limit(&self, scope: LockoutScope) -> u32 {
+        match scope {
+            LockoutScope::Ip => self.max_failures_per_ip,
+            LockoutScope::Username => self.max_failures,
+        }
+    }
+
+    fn is_stale(&self, attempts: &Attempts, now: DateTime<Utc>) -> bool {
+        now - attempts.last_failure_at > self.lockout
+            && attempts.blocked_until.is_none_or(|until| until <= now)
+    }
+
+    fn backoff(&self, failures: u32) -> Duration {
+        let seconds = self.backoff_seconds.saturating_mul(1 << (failures - 1).min(20));
+        Duration::seconds(seconds as i64).min(self.lockout)
+    }
+
+    fn register_failure(&self, entry: &mut Attempts, scope: LockoutScope, subject: &str, now: DateTime<Utc>) {
+        entry.failures += 1;
+        entry.last_failure_at = now;
+
+        let limit = self.limit(scope);
+        entry.blocked_until = if limit > 0 && entry.failures >= limit {
+            warn!("Locking out {} {} after {} failed logins", scope, subject, entry.failures);
+            Some(now + self.lockout)
+        } else if self.backoff_seconds > 0 {
+            Some(now + self.backoff(entry.failures))
+        } else {
+            None
+        };
+    }
+
+    fn to_lockout(&self, (scope, subject): &(LockoutScope, String), attempts: &Attempts) -> LoginLockout {
+        let limit = self.limit(*scope);
+        LoginLockout {
+            scope: *scope,
+            subject: subject.clone(),
+            failures: attempts.failures,
+            last_failure_at: attempts.last_failure_at,
+            blocked_until: attempts.blocked_until,
+            locked: limit > 0 && attempts.failures >= limit,
+        }
+    }
+}
+
+#[async_trait]
+impl LoginThrottle for InMemoryLoginThrottle {
+    async fn retry_after(&self, ip: IpAddr, username: &str) -> Result<Option<u64>, DomainError> {
+        let now = Utc::now();
+        let attempts = self.attempts.read().await;
+
+        let wait = [(LockoutScope::Ip, ip.to_string()), (LockoutScope::Username, username.to_string())]
+            .iter()
+            .filter_map(|key| attempts.get(key)?.blocked_until)
+            .filter(|until| *until > now)
+            .max()
+            .map(|until| (until - now).num_seconds().max(1) as u64);
+
+        Ok(wait)
+    }
+
+    async fn record_failure(&self, ip: IpAddr, username: &str) -> Result<(), DomainError> {
+        let now = Utc::now();
+        let mut attempts = self.attempts.write().await;
+
+        // Drop expired counters here so guessed usernames cannot grow the map forever
+        attempts.retain(|_, entry| !self.is_stale(entry, now));
+
+        for (scope, subject) in [(LockoutScope::Ip, ip.to_string()), (LockoutScope::Username, username.to_string())] {
+            let entry = attempts.entry((scope, subject.clone())).or_insert(Attempts {
+                failures: 0,
+                last_failure_at: now,
+                blocked_until: None,
+            });
+            self.register_failure(entry, scope, &subject, now);
+        }
+
+        Ok(())
+    }
+
+    // Only the username counter is reset; the IP keeps counting so one known
+    // password cannot be used to clear failures against other accounts
+    async fn record_success(&self, username: &str) -> Result<(), DomainError> {
+        let mut attempts = self.attempts.write().await;
+        attempts.remove(&(LockoutScope::Username, username.to_string()));
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<LoginLockout>, DomainError> {
+        let now = Utc::now();
+        let attempts = self.attempts.read().await;
+
+        let mut lockouts: Vec<LoginLockout> = attempts.iter()
+            .filter(|(_, entry)| !self.is_stale(entry, now))
+            .map(|(key, entry)| self.to_lockout(key, entry))
+            .collect();
+        lockouts.sort_by_key(|lockout| std::cmp::Reverse(lockout.last_failure_at));
+
+        Ok(lockouts)
+    }
+
+    async fn clear(&self, scope: LockoutScope, subject: &str) -> Result<bool, DomainError> {
+        let mut attempts = self.attempts.write().await;
+        Ok(attempts.remove(&(scope, subject.to_string())).is_some())
+    }
+}
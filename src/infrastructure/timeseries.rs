@@ -0,0 +1,244 @@
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use log::debug;
+
+use crate::domain::{
+    models::{LatencyPoint, LatencyRetention, DomainError, UserKey},
+    traits::LatencyStore,
+};
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 3600;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    start: i64,
+    samples: u32,
+    lost: u32,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Bucket {
+    fn empty(start: i64) -> Self {
+        Self {
+            start,
+            samples: 0,
+            lost: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    fn add(&mut self, latency: Option<f64>) {
+        self.samples += 1;
+        match latency {
+            Some(value) => {
+                self.sum += value;
+                self.min = self.min.min(value);
+                self.max = self.max.max(value);
+            }
+            None => self.lost += 1,
+        }
+    }
+
+    fn merge(&mut self, other: &Bucket) {
+        self.samples += other.samples;
+        self.lost += other.lost;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    fn to_point(self) -> LatencyPoint {
+        let received = self.samples - self.lost;
+        let answered = |value: f64| (received > 0).then_some(value);
+
+        LatencyPoint {
+            timestamp: Utc.timestamp_opt(self.start, 0).single().unwrap_or_default(),
+            min: answered(self.min),
+            avg: answered(self.sum / received.max(1) as f64),
+            max: answered(self.max),
+            loss_percent: if self.samples > 0 {
+                self.lost as f64 * 100.0 / self.samples as f64
+            } else {
+                0.0
+            },
+            samples: self.samples,
+        }
+    }
+}
+
+// Fixed-width buckets kept in chronological order
+struct Rollup {
+    width: i64,
+    buckets: VecDeque<Bucket>,
+}
+
+impl Rollup {
+    fn new(width: i64) -> Self {
+        Self {
+            width,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    fn add(&mut self, timestamp: i64, latency: Option<f64>) {
+        let start = timestamp - timestamp.rem_euclid(self.width);
+
+        match self.buckets.iter().rposition(|b| b.start <= start) {
+            Some(index) if self.buckets[index].start == start => self.buckets[index].add(latency),
+            position => {
+                let mut bucket = Bucket::empty(start);
+                bucket.add(latency);
+                self.buckets.insert(position.map_or(0, |i| i + 1), bucket);
+            }
+        }
+    }
+
+    fn prune(&mut self, cutoff: i64) {
+        while self.buckets.front().is_some_and(|b| b.start + self.width <= cutoff) {
+            self.buckets.pop_front();
+        }
+    }
+}
+
+struct UserSeries {
+    raw: VecDeque<(i64, Option<f64>)>,
+    minutes: Rollup,
+    hours: Rollup,
+}
+
+impl UserSeries {
+    fn new() -> Self {
+        Self {
+            raw: VecDeque::new(),
+            minutes: Rollup::new(MINUTE),
+            hours: Rollup::new(HOUR),
+        }
+    }
+
+    fn prune(&mut self, now: i64, retention: &LatencyRetention) {
+        let raw_cutoff = now - retention.raw_seconds as i64;
+        while self.raw.front().is_some_and(|(ts, _)| *ts < raw_cutoff) {
+            self.raw.pop_front();
+        }
+        self.minutes.prune(now - retention.minute_seconds as i64);
+        self.hours.prune(now - retention.hour_seconds as i64);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.raw.is_empty() && self.minutes.buckets.is_empty() && self.hours.buckets.is_empty()
+    }
+}
+
+// Keeps raw samples for a short window and rolls them up into 1-minute and
+// 1-hour buckets, each expiring according to the retention policy
+pub struct InMemoryLatencyStore {
+    series: Arc<RwLock<HashMap<UserKey, UserSeries>>>,
+    retention: LatencyRetention,
+}
+
+impl InMemoryLatencyStore {
+    pub fn new(retention: LatencyRetention) -> Self {
+        Self {
+            series: Arc::new(RwLock::new(HashMap::new())),
+            retention,
+        }
+    }
+}
+
+#[async_trait]
+impl LatencyStore for InMemoryLatencyStore {
+    async fn record(&self, user: &UserKey, timestamp: DateTime<Utc>, latency: Option<f64>) -> Result<(), DomainError> {
+        let ts = timestamp.timestamp();
+        let mut series = self.series.write().await;
+        let user_series = series.entry(user.clone()).or_insert_with(UserSeries::new);
+
+        user_series.raw.push_back((ts, latency));
+        user_series.minutes.add(ts, latency);
+        user_series.hours.add(ts, latency);
+        user_series.prune(ts, &self.retention);
+
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        router: Option<&str>,
+        user_name: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        step_seconds: u64,
+    ) -> Result<Vec<LatencyPoint>, DomainError> {
+        if from >= to {
+            return Err(DomainError::InvalidInput("from must be before to".to_string()));
+        }
+
+        let series = self.series.read().await;
+        let mut matching = series.iter()
+            .filter(|(key, _)| key.name == user_name && (router.is_none() || key.router.as_deref() == router));
+        let (user, user_series) = match (matching.next(), matching.next()) {
+            (None, _) => return Ok(Vec::new()),
+            (Some(found), None) => found,
+            (Some(_), Some(_)) => {
+                let mut routers: Vec<&str> = series.keys()
+                    .filter(|key| key.name == user_name)
+                    .filter_map(|key| key.router.as_deref())
+                    .collect();
+                routers.sort_unstable();
+                return Err(DomainError::InvalidInput(format!(
+                    "{} has latency history on {}, router must be specified", user_name, routers.join(", ")
+                )));
+            }
+        };
+
+        let (from, to) = (from.timestamp(), to.timestamp());
+        let age = Utc::now().timestamp() - from;
+
+        // Use the finest resolution that still covers the start of the range
+        let (width, source): (i64, Vec<Bucket>) = if age <= self.retention.raw_seconds as i64 {
+            let samples = user_series.raw.iter().map(|(ts, latency)| {
+                let mut bucket = Bucket::empty(*ts);
+                bucket.add(*latency);
+                bucket
+            });
+            (1, samples.collect())
+        } else if age <= self.retention.minute_seconds as i64 {
+            (MINUTE, user_series.minutes.buckets.iter().copied().collect())
+        } else {
+            (HOUR, user_series.hours.buckets.iter().copied().collect())
+        };
+
+        let step = (step_seconds as i64).max(width);
+        let mut aggregated: BTreeMap<i64, Bucket> = BTreeMap::new();
+
+        for bucket in source.iter().filter(|b| b.start >= from && b.start < to) {
+            let start = bucket.start - bucket.start.rem_euclid(step);
+            aggregated
+                .entry(start)
+                .or_insert_with(|| Bucket::empty(start))
+                .merge(bucket);
+        }
+
+        debug!("Latency query for {} returned {} points (step {}s)", user, aggregated.len(), step);
+        Ok(aggregated.into_values().map(Bucket::to_point).collect())
+    }
+
+    async fn prune(&self, now: DateTime<Utc>) -> Result<(), DomainError> {
+        let now = now.timestamp();
+        let mut series = self.series.write().await;
+
+        for user_series in series.values_mut() {
+            user_series.prune(now, &self.retention);
+        }
+        series.retain(|_, user_series| !user_series.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use log::{debug, info};
+
+use crate::domain::{
+    models::{ApiToken, DomainError},
+    traits::ApiTokenRepository,
+};
+use crate::infrastructure::json_file::{read_json_file, write_json_file};
+
+// Last-used timestamps are written back at most this often per token
+const TOUCH_INTERVAL_SECONDS: i64 = 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredToken {
+    #[serde(flatten)]
+    token: ApiToken,
+    token_hash: String,
+}
+
+// Keeps API tokens in a JSON file next to `.htpasswd`. The file is loaded once
+// at startup and rewritten atomically (temp file + rename) on every change
+pub struct FileApiTokenRepository {
+    path: PathBuf,
+    tokens: Arc<RwLock<Vec<StoredToken>>>,
+}
+
+impl FileApiTokenRepository {
+    pub fn open(path: &str) -> Result<Self, DomainError> {
+        let path = PathBuf::from(path);
+        let tokens = read_json_file(&path)?;
+        info!("Loaded API tokens from {}", path.display());
+
+        Ok(Self {
+            path,
+            tokens: Arc::new(RwLock::new(tokens)),
+        })
+    }
+
+    // Called with the write lock held so concurrent changes are written in order
+    async fn persist(&self, tokens: &[StoredToken]) -> Result<(), DomainError> {
+        write_json_file(&self.path, &tokens).await
+    }
+}
+
+#[async_trait]
+impl ApiTokenRepository for FileApiTokenRepository {
+    async fn list(&self) -> Result<Vec<ApiToken>, DomainError> {
+        let tokens = self.tokens.read().await;
+        Ok(tokens.iter().map(|stored| stored.token.clone()).collect())
+    }
+
+    async fn create(&self, token: &ApiToken, token_hash: &str) -> Result<(), DomainError> {
+        let mut tokens = self.tokens.write().await;
+        tokens.push(StoredToken {
+            token: token.clone(),
+            token_hash: token_hash.to_string(),
+        });
+
+        if let Err(e) = self.persist(&tokens).await {
+            tokens.pop();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn revoke(&self, id: &str) -> Result<(), DomainError> {
+        let mut tokens = self.tokens.write().await;
+        let Some(index) = tokens.iter().position(|stored| stored.token.id == id) else {
+            return Err(DomainError::TokenNotFound(id.to_string()));
+        };
+
+        let removed = tokens.remove(index);
+        if let Err(e) = self.persist(&tokens).await {
+            tokens.insert(index, removed);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn find_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>, DomainError> {
+        let tokens = self.tokens.read().await;
+        Ok(tokens.iter()
+            .find(|stored| stored.token_hash == token_hash)
+            .map(|stored| stored.token.clone()))
+    }
+
+    async fn touch(&self, id: &str, used_at: DateTime<Utc>) -> Result<(), DomainError> {
+        let mut tokens = self.tokens.write().await;
+        let Some(stored) = tokens.iter_mut().find(|stored| stored.token.id == id) else {
+            return Err(DomainError::TokenNotFound(id.to_string()));
+        };
+
+        let stale = stored.token.last_used_at
+            .is_none_or(|last| used_at - last >= Duration::seconds(TOUCH_INTERVAL_SECONDS));
+        stored.token.last_used_at = Some(used_at);
+
+        if stale {
+            debug!("Recording use of API token {}", id);
+            self.persist(&tokens).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use log::info;
+
+use crate::domain::{
+    models::{TotpRecord, DomainError},
+    traits::TotpRepository,
+};
+use crate::infrastructure::json_file::{read_json_file, write_json_file};
+
+// TOTP records keyed by username in a JSON file readable only by the service
+pub struct FileTotpRepository {
+    path: PathBuf,
+    records: Arc<RwLock<HashMap<String, TotpRecord>>>,
+}
+
+impl FileTotpRepository {
+    pub fn open(path: &str) -> Result<Self, DomainError> {
+        let path = PathBuf::from(path);
+        let records = read_json_file(&path)?;
+        info!("Loaded TOTP enrollments from {}", path.display());
+
+        Ok(Self {
+            path,
+            records: Arc::new(RwLock::new(records)),
+        })
+    }
+
+    // Applies `change` to the record of `username` and writes it, all under
+    // one write lock. Nothing is written when `change` returns false
+    async fn update(&self, username: &str, change: impl FnOnce(&mut TotpRecord) -> bool) -> Result<bool, DomainError> {
+        let mut records = self.records.write().await;
+        let Some(record) = records.get_mut(username) else {
+            return Ok(false);
+        };
+
+        let previous = record.clone();
+        if !change(record) {
+            return Ok(false);
+        }
+        if let Err(e) = write_json_file(&self.path, &*records).await {
+            records.insert(username.to_string(), previous);
+            return Err(e);
+        }
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl TotpRepository for FileTotpRepository {
+    async fn find(&self, username: &str) -> Result<Option<TotpRecord>, DomainError> {
+        let records = self.records.read().await;
+        Ok(records.get(username).cloned())
+    }
+
+    async fn save(&self, record: &TotpRecord) -> Result<(), DomainError> {
+        let mut records = self.records.write().await;
+        let previous = records.insert(record.username.clone(), record.clone());
+
+        if let Err(e) = write_json_file(&self.path, &*records).await {
+            match previous {
+                Some(previous) => records.insert(record.username.clone(), previous),
+                None => records.remove(&record.username),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, username: &str) -> Result<bool, DomainError> {
+        let mut records = self.records.write().await;
+        let Some(removed) = records.remove(username) else {
+            return Ok(false);
+        };
+
+        if let Err(e) = write_json_file(&self.path, &*records).await {
+            records.insert(username.to_string(), removed);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    async fn use_step(&self, username: &str, step: u64) -> Result<bool, DomainError> {
+        self.update(username, |record| {
+            let unused = record.last_used_step.is_none_or(|last| step > last);
+            if unused {
+                record.last_used_step = Some(step);
+            }
+            unused
+        })
+        .await
+    }
+
+    async fn use_recovery_code(&self, username: &str, hash: &str) -> Result<Option<usize>, DomainError> {
+        let mut left = None;
+        self.update(username, |record| {
+            let Some(index) = record.recovery_code_hashes.iter().position(|stored| stored == hash) else {
+                return false;
+            };
+            record.recovery_code_hashes.remove(index);
+            left = Some(record.recovery_code_hashes.len());
+            true
+        })
+        .await?;
+        Ok(left)
+    }
+}
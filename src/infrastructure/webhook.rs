@@ -0,0 +1,348 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, StatusCode};
+use serde::Serialize;
+use sha2::Sha256;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use log::{debug, error, info, warn};
+
+use crate::domain::{
+    models::{
+        Alert, DeadLetter, LatencyUpdate, RedeliveryResult, UserChanged, UserConnected, UserDisconnected, VpnUser, WebhookConfig,
+        WebhookEvent, WebhookEventKind, DomainError,
+    },
+    traits::{AlertNotifier, DeadLetterQueue, EventPublisher},
+};
+use crate::infrastructure::json_file::{read_json_file, write_json_file};
+
+// Oldest entries are dropped beyond this, so an endpoint that stays down
+// cannot grow the file without bound
+const MAX_DEAD_LETTERS: usize = 10_000;
+// Longest wait between two attempts
+const MAX_RETRY_DELAY_SECONDS: u64 = 3600;
+
+// Hex HMAC-SHA256 of "<timestamp>.<body>", sent as `X-Mikriting-Signature: sha256=<hex>`
+pub fn webhook_signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+struct DeliveryError {
+    message: String,
+    retryable: bool,
+}
+
+// Network errors, timeouts, 408, 429 and 5xx are retried; any other answer
+// means the endpoint will not take this event
+async fn send(client: &Client, webhook: &WebhookConfig, event: &WebhookEvent) -> Result<(), DeliveryError> {
+    let body = serde_json::to_string(event).map_err(|e| DeliveryError {
+        message: e.to_string(),
+        retryable: false,
+    })?;
+    let timestamp = Utc::now().timestamp();
+
+    let response = client.post(&webhook.url)
+        .timeout(Duration::from_secs(webhook.timeout_seconds))
+        .header("Content-Type", "application/json")
+        .header("X-Mikriting-Event", event.kind.as_str())
+        .header("X-Mikriting-Delivery", &event.id)
+        .header("X-Mikriting-Timestamp", timestamp.to_string())
+        .header("X-Mikriting-Signature", format!("sha256={}", webhook_signature(&webhook.secret, timestamp, &body)))
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| DeliveryError {
+            message: e.to_string(),
+            retryable: true,
+        })?;
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    Err(DeliveryError {
+        message: format!("HTTP {}", status),
+        retryable: status.is_server_error()
+            || status == StatusCode::REQUEST_TIMEOUT
+            || status == StatusCode::TOO_MANY_REQUESTS,
+    })
+}
+
+// Dead letters in a JSON file, loaded at startup and rewritten on every change
+struct DeadLetterFile {
+    path: PathBuf,
+    entries: Mutex<Vec<DeadLetter>>,
+}
+
+impl DeadLetterFile {
+    async fn push(&self, letter: DeadLetter) {
+        let mut entries = self.entries.lock().await;
+        entries.push(letter);
+        if entries.len() > MAX_DEAD_LETTERS {
+            let excess = entries.len() - MAX_DEAD_LETTERS;
+            warn!("Dropping the {} oldest webhook dead letters", excess);
+            entries.drain(..excess);
+        }
+
+        if let Err(e) = write_json_file(&self.path, &*entries).await {
+            error!("Failed to write webhook dead letters: {}", e);
+        }
+    }
+}
+
+async fn deliver(client: Client, webhook: Arc<WebhookConfig>, event: WebhookEvent, dead_letters: Arc<DeadLetterFile>) {
+    let mut attempt = 1;
+
+    loop {
+        let error = match send(&client, &webhook, &event).await {
+            Ok(()) => {
+                debug!("Delivered {} {} to webhook {}", event.kind.as_str(), event.id, webhook.name);
+                return;
+            }
+            Err(e) => e,
+        };
+
+        if error.retryable && attempt < webhook.max_attempts {
+            let delay = webhook.retry_base_seconds
+                .saturating_mul(1 << (attempt - 1).min(20))
+                .min(MAX_RETRY_DELAY_SECONDS);
+            warn!(
+                "Webhook {} delivery {} failed (attempt {}/{}), retrying in {}s: {}",
+                webhook.name, event.id, attempt, webhook.max_attempts, delay, error.message
+            );
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            attempt += 1;
+            continue;
+        }
+
+        error!("Webhook {} delivery {} failed after {} attempts: {}", webhook.name, event.id, attempt, error.message);
+        dead_letters.push(DeadLetter {
+            webhook: webhook.name.clone(),
+            failed_at: Utc::now(),
+            attempts: attempt,
+            error: error.message,
+            event,
+        }).await;
+        return;
+    }
+}
+
+// POSTs events to the configured `[[webhooks]]` endpoints, each receiving
+// the kinds listed in its `events`. Deliveries run in the background, so
+// publishing never waits for an endpoint, and their order is not guaranteed
+pub struct WebhookEventPublisher {
+    client: Client,
+    webhooks: Vec<Arc<WebhookConfig>>,
+    dead_letters: Arc<DeadLetterFile>,
+}
+
+impl WebhookEventPublisher {
+    pub fn new(webhooks: Vec<WebhookConfig>, dead_letter_file: &str) -> Result<Self, DomainError> {
+        let path = PathBuf::from(dead_letter_file);
+        let entries: Vec<DeadLetter> = read_json_file(&path)?;
+        if !entries.is_empty() {
+            warn!("{} undelivered webhook events in {}", entries.len(), path.display());
+        }
+
+        let client = Client::builder()
+            .build()
+            .map_err(|e| DomainError::NetworkError(e.to_string()))?;
+
+        info!("Publishing events to {} webhooks", webhooks.len());
+        Ok(Self {
+            client,
+            webhooks: webhooks.into_iter().map(Arc::new).collect(),
+            dead_letters: Arc::new(DeadLetterFile {
+                path,
+                entries: Mutex::new(entries),
+            }),
+        })
+    }
+
+    fn dispatch(&self, kind: WebhookEventKind, data: serde_json::Value) {
+        let subscribers = self.webhooks.iter().filter(|webhook| webhook.events.contains(&kind));
+
+        for webhook in subscribers {
+            tokio::spawn(deliver(
+                self.client.clone(),
+                webhook.clone(),
+                WebhookEvent::new(kind, data.clone()),
+                self.dead_letters.clone(),
+            ));
+        }
+    }
+
+    fn user_event<T: Serialize>(&self, kind: WebhookEventKind, user: &str, event: &T) {
+        match serde_json::to_value(event) {
+            Ok(data) => self.dispatch(kind, data),
+            Err(e) => error!("Failed to serialize webhook event for {}: {}", user, e),
+        }
+    }
+}
+
+#[async_trait]
+impl EventPublisher for WebhookEventPublisher {
+    // Snapshots are not sent, endpoints get the user transitions below
+    async fn publish_vpn_users_update(&self, _users: Vec<VpnUser>) -> Result<(), DomainError> {
+        Ok(())
+    }
+
+    async fn publish_latency_update(&self, update: LatencyUpdate) -> Result<(), DomainError> {
+        self.dispatch(WebhookEventKind::Latency, serde_json::json!({
+            "name": update.user_name,
+            "router": update.router,
+            "session_id": update.session_id,
+            "latency": update.latency,
+            "stats": update.stats,
+        }));
+        Ok(())
+    }
+
+    async fn publish_user_connected(&self, event: UserConnected) -> Result<(), DomainError> {
+        self.user_event(WebhookEventKind::UserConnected, &event.user.name, &event);
+        Ok(())
+    }
+
+    async fn publish_user_disconnected(&self, event: UserDisconnected) -> Result<(), DomainError> {
+        self.user_event(WebhookEventKind::UserDisconnected, &event.user.name, &event);
+        Ok(())
+    }
+
+    async fn publish_user_changed(&self, event: UserChanged) -> Result<(), DomainError> {
+        self.user_event(WebhookEventKind::UserChanged, &event.user.name, &event);
+        Ok(())
+    }
+}
+
+// Alerts go to the endpoints subscribed to `alert`
+#[async_trait]
+impl AlertNotifier for WebhookEventPublisher {
+    async fn notify(&self, alert: &Alert) -> Result<(), DomainError> {
+        let data = serde_json::to_value(alert).map_err(|e| DomainError::SerializationError(e.to_string()))?;
+        self.dispatch(WebhookEventKind::Alert, data);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DeadLetterQueue for WebhookEventPublisher {
+    async fn list(&self) -> Result<Vec<DeadLetter>, DomainError> {
+        Ok(self.dead_letters.entries.lock().await.clone())
+    }
+
+    // Holds the file for the whole run, deliveries failing meanwhile are
+    // added once it is done
+    async fn redeliver(&self) -> Result<RedeliveryResult, DomainError> {
+        let mut entries = self.dead_letters.entries.lock().await;
+        let mut remaining = Vec::new();
+        let mut delivered = 0;
+
+        for mut letter in entries.drain(..) {
+            let Some(webhook) = self.webhooks.iter().find(|webhook| webhook.name == letter.webhook) else {
+                letter.error = "webhook is no longer configured".to_string();
+                remaining.push(letter);
+                continue;
+            };
+
+            match send(&self.client, webhook, &letter.event).await {
+                Ok(()) => delivered += 1,
+                Err(e) => {
+                    letter.attempts += 1;
+                    letter.failed_at = Utc::now();
+                    letter.error = e.message;
+                    remaining.push(letter);
+                }
+            }
+        }
+
+        let failed = remaining.len();
+        *entries = remaining;
+        write_json_file(&self.dead_letters.path, &*entries).await?;
+
+        info!("Redelivered {} webhook events, {} failed again", delivered, failed);
+        Ok(RedeliveryResult { delivered, failed })
+    }
+
+    async fn clear(&self) -> Result<usize, DomainError> {
+        let mut entries = self.dead_letters.entries.lock().await;
+        let cleared = entries.len();
+        entries.clear();
+        write_json_file(&self.dead_letters.path, &*entries).await?;
+        Ok(cleared)
+    }
+}
+
+// Hands every event to each publisher, so the dashboard and webhooks both
+// receive it. A failing publisher does not keep the others from theirs
+pub struct CompositeEventPublisher {
+    publishers: Vec<Arc<dyn EventPublisher + Send + Sync>>,
+}
+
+impl CompositeEventPublisher {
+    pub fn new(publishers: Vec<Arc<dyn EventPublisher + Send + Sync>>) -> Self {
+        Self { publishers }
+    }
+}
+
+#[async_trait]
+impl EventPublisher for CompositeEventPublisher {
+    async fn publish_vpn_users_update(&self, users: Vec<VpnUser>) -> Result<(), DomainError> {
+        let mut result = Ok(());
+        for publisher in &self.publishers {
+            if let Err(e) = publisher.publish_vpn_users_update(users.clone()).await {
+                error!("Failed to publish user list: {}", e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    async fn publish_latency_update(&self, update: LatencyUpdate) -> Result<(), DomainError> {
+        let mut result = Ok(());
+        for publisher in &self.publishers {
+            if let Err(e) = publisher.publish_latency_update(update.clone()).await {
+                error!("Failed to publish latency update: {}", e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+    async fn publish_user_connected(&self, event: UserConnected) -> Result<(), DomainError> {
+        let mut result = Ok(());
+        for publisher in &self.publishers {
+            if let Err(e) = publisher.publish_user_connected(event.clone()).await {
+                error!("Failed to publish connection of {}: {}", event.user.name, e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    async fn publish_user_disconnected(&self, event: UserDisconnected) -> Result<(), DomainError> {
+        let mut result = Ok(());
+        for publisher in &self.publishers {
+            if let Err(e) = publisher.publish_user_disconnected(event.clone()).await {
+                error!("Failed to publish disconnection of {}: {}", event.user.name, e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    async fn publish_user_changed(&self, event: UserChanged) -> Result<(), DomainError> {
+        let mut result = Ok(());
+        for publisher in &self.publishers {
+            if let Err(e) = publisher.publish_user_changed(event.clone()).await {
+                error!("Failed to publish change of {}: {}", event.user.name, e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
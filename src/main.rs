@@ -5,12 +5,14 @@ mod infrastructure;
 
 use std::sync::Arc;
 use log::info;
-use env_logger;
 use actix::Actor;
+use clap::Parser;
 
+use crate::domain::models::{AuditAction, AuditRecord, AuthBackend};
 use crate::domain::traits::*;
 use crate::usecase::*;
 use crate::adapter::*;
+use crate::adapter::cli::{run_user_command, Args, Command};
 use crate::infrastructure::*;
 
 #[actix_web::main]
@@ -18,99 +20,198 @@ async fn main() -> std::io::Result<()> {
     // Initialize logger
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
     
-    info!("Starting mikriting-tool application");
+    let args = Args::parse();
     
     // Create configuration service
     let config_service = Arc::new(FileConfigService::new()) as Arc<dyn ConfigService + Send + Sync>;
-    
-    // Load configurations
     let app_config = config_service.get_app_config()
         .expect("Failed to load app configuration");
-    let mikrotik_config = config_service.get_mikrotik_config()
-        .expect("Failed to load MikroTik configuration");
+    let htpasswd_repository = Arc::new(HtpasswdAuthRepository::new(
+        app_config.htpasswd_path.clone(),
+        app_config.roles_file.clone(),
+        app_config.default_role,
+    ));
+    let local_user_use_case = Arc::new(LocalUserUseCase::new(
+        htpasswd_repository.clone() as Arc<dyn LocalUserRepository + Send + Sync>
+    ));
+    
+    // User management commands run without starting the server
+    if let Some(Command::User { action }) = args.command {
+        if let Err(e) = run_user_command(&local_user_use_case, action).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    
+    info!("Starting mikriting-tool application");
+    
+    // Load router configuration
+    let router_configs = config_service.get_router_configs()
+        .expect("Failed to load router configuration");
     
     info!("Configuration loaded successfully");
     
     // Create infrastructure services
-    let vpn_user_repository = Arc::new(InMemoryVpnUserRepository::new()) as Arc<dyn VpnUserRepository + Send + Sync>;
-    let auth_repository = Arc::new(HtpasswdAuthRepository::default()) as Arc<dyn AuthRepository + Send + Sync>;
+    let vpn_user_repository = match app_config.storage_backend.as_str() {
+        "sqlite" => Arc::new(
+            SqliteVpnUserRepository::open(&app_config.database_path)
+                .expect("Failed to open SQLite database")
+        ) as Arc<dyn VpnUserRepository + Send + Sync>,
+        "memory" => Arc::new(InMemoryVpnUserRepository::new()) as Arc<dyn VpnUserRepository + Send + Sync>,
+        other => panic!("Unsupported storage backend '{}', expected memory or sqlite", other),
+    };
+    let mut auth_backends = Vec::new();
+    for backend in &app_config.auth_backends {
+        let repository = match backend {
+            AuthBackend::Htpasswd => htpasswd_repository.clone() as Arc<dyn AuthRepository + Send + Sync>,
+            AuthBackend::Ldap => {
+                let ldap_config = config_service.get_ldap_config()
+                    .expect("Failed to load LDAP configuration")
+                    .expect("auth_backends includes ldap but there is no [ldap] section");
+                Arc::new(
+                    LdapAuthRepository::new(ldap_config)
+                        .expect("Failed to create LDAP authentication")
+                ) as Arc<dyn AuthRepository + Send + Sync>
+            }
+        };
+        auth_backends.push((backend.to_string(), repository));
+    }
+    let auth_repository = match auth_backends.len() {
+        0 => panic!("app.auth_backends must list at least one backend"),
+        1 => auth_backends.remove(0).1,
+        _ => Arc::new(ChainedAuthRepository::new(auth_backends)) as Arc<dyn AuthRepository + Send + Sync>,
+    };
+    let api_token_repository = Arc::new(
+        FileApiTokenRepository::open(&app_config.api_tokens_file)
+            .expect("Failed to load API tokens")
+    ) as Arc<dyn ApiTokenRepository + Send + Sync>;
+    let totp_repository = Arc::new(
+        FileTotpRepository::open(&app_config.totp_file)
+            .expect("Failed to load TOTP enrollments")
+    ) as Arc<dyn TotpRepository + Send + Sync>;
+    let oidc_provider = config_service.get_oidc_config()
+        .expect("Failed to load OpenID Connect configuration")
+        .map(|oidc_config| Arc::new(
+            OidcClient::new(oidc_config).expect("Failed to create OpenID Connect client")
+        ) as Arc<dyn OidcProvider + Send + Sync>);
+    let audit_log = Arc::new(
+        FileAuditLog::open(&app_config.audit_log_file)
+            .expect("Failed to open audit log")
+    ) as Arc<dyn AuditLog + Send + Sync>;
+    let login_throttle = Arc::new(InMemoryLoginThrottle::new(&app_config)) as Arc<dyn LoginThrottle + Send + Sync>;
     let cache_service = Arc::new(InMemoryCache::new()) as Arc<dyn CacheService + Send + Sync>;
+    let latency_store = Arc::new(InMemoryLatencyStore::new(app_config.latency_retention())) as Arc<dyn LatencyStore + Send + Sync>;
+    
+    let metrics = Arc::new(
+        PrometheusMetrics::new(&app_config).expect("Failed to register metrics")
+    ) as Arc<dyn MetricsRecorder + Send + Sync>;
     
-    // Create MikroTik client
-    let mikrotik_service = Arc::new(
-        MikrotikClient::new(mikrotik_config)
-            .expect("Failed to create MikroTik client")
-    ) as Arc<dyn MikrotikService + Send + Sync>;
+    // Create MikroTik clients, one per configured router
+    let router_registry = Arc::new(
+        RouterRegistry::from_configs(router_configs, metrics.clone())
+            .expect("Failed to create MikroTik clients")
+    );
+    let mikrotik_service = router_registry.clone() as Arc<dyn MikrotikService + Send + Sync>;
+    let secret_service = router_registry.clone() as Arc<dyn PppSecretService + Send + Sync>;
+    let router_status_service = router_registry as Arc<dyn RouterStatusService + Send + Sync>;
     
     // Create WebSocket manager and event publisher
-    let websocket_manager = WebSocketManager::new().start();
-    let event_publisher = Arc::new(WebSocketEventPublisher::new(websocket_manager.clone())) as Arc<dyn EventPublisher + Send + Sync>;
-    
-    // Create ping service
-    let ping_service = Arc::new(
-        PingMonitor::new(
-            // We'll create a temp use case for ping monitor
-            Arc::new(VpnUserUseCase::new(
-                vpn_user_repository.clone(),
-                mikrotik_service.clone(),
-                Arc::new(DummyPingService::new()) as Arc<dyn PingService + Send + Sync>,
-                event_publisher.clone(),
-                cache_service.clone(),
-            )),
-            app_config.ping_interval_seconds
+    let websocket_manager = WebSocketManager::new(metrics.clone()).start();
+    let webhook_publisher = Arc::new(
+        WebhookEventPublisher::new(
+            config_service.get_webhook_configs().expect("Failed to load webhook configuration"),
+            &app_config.webhook_dead_letter_file,
         )
-        .await
-        .expect("Failed to create ping monitor")
-    ) as Arc<dyn PingService + Send + Sync>;
+        .expect("Failed to create webhook publisher")
+    );
+    
+    // Alert rules see the same events, and notify through the log and webhooks
+    let alert_engine = Arc::new(
+        AlertEngine::new(
+            config_service.get_alert_rules().expect("Failed to load alert rules"),
+            &app_config,
+            vec![
+                Arc::new(LogAlertNotifier) as Arc<dyn AlertNotifier + Send + Sync>,
+                webhook_publisher.clone() as Arc<dyn AlertNotifier + Send + Sync>,
+            ],
+        )
+        .expect("Failed to create alert engine")
+    );
+    let event_publisher = Arc::new(CompositeEventPublisher::new(vec![
+        Arc::new(WebSocketEventPublisher::new(websocket_manager.clone())) as Arc<dyn EventPublisher + Send + Sync>,
+        webhook_publisher.clone() as Arc<dyn EventPublisher + Send + Sync>,
+        alert_engine.clone() as Arc<dyn EventPublisher + Send + Sync>,
+    ])) as Arc<dyn EventPublisher + Send + Sync>;
+    
+    // Create ping monitor
+    let ping_monitor = Arc::new(PingMonitor::new(&app_config));
     
-    // Create use cases with real ping service
+    // Create use cases
     let vpn_user_use_case = Arc::new(VpnUserUseCase::new(
         vpn_user_repository,
         mikrotik_service,
-        ping_service,
+        ping_monitor.clone() as Arc<dyn PingService + Send + Sync>,
         event_publisher,
+        cache_service.clone(),
+        latency_store,
+    ));
+    let health_use_case = Arc::new(HealthUseCase::new(
         cache_service,
+        ping_monitor.clone() as Arc<dyn PingService + Send + Sync>,
+        router_status_service,
+        app_config.readiness_max_user_list_age_seconds,
+    ));
+    
+    let auth_use_case = Arc::new(AuthUseCase::new(auth_repository, login_throttle, oidc_provider));
+    let ppp_secret_use_case = Arc::new(PppSecretUseCase::new(secret_service));
+    let api_token_use_case = Arc::new(ApiTokenUseCase::new(api_token_repository));
+    let totp_use_case = Arc::new(TotpUseCase::new(
+        totp_repository,
+        app_config.totp_issuer.clone(),
+        app_config.totp_required_roles.clone(),
     ));
+    let audit_use_case = Arc::new(AuditUseCase::new(audit_log));
+    let webhook_use_case = Arc::new(WebhookUseCase::new(webhook_publisher as Arc<dyn DeadLetterQueue + Send + Sync>));
+    let alert_use_case = Arc::new(AlertUseCase::new(alert_engine as Arc<dyn AlertService + Send + Sync>));
     
-    let auth_use_case = Arc::new(AuthUseCase::new(auth_repository));
+    audit_use_case.record(
+        AuditRecord::new("system", None, AuditAction::ServiceStarted)
+            .detail(format!("version {}", env!("CARGO_PKG_VERSION")))
+    ).await;
     
     // Create and start scheduler
-    let scheduler = VpnUserScheduler::new(vpn_user_use_case.clone(), 15); // 15 seconds interval
+    let scheduler = VpnUserScheduler::new(vpn_user_use_case.clone(), 15, metrics.clone(), health_use_case.clone()); // 15 seconds interval
     tokio::spawn(async move {
         let mut scheduler = scheduler;
         scheduler.start().await;
     });
     
+    // Start ping monitoring, publishing through the same use case as the web server
+    let ping_monitor_handle = ping_monitor.start(vpn_user_use_case.clone());
+    
     info!("Background services started");
     
-    // Start the web server
-    adapter::rest_api::start_server(
+    // Start the web server, returns once it has shut down
+    let result = adapter::rest_api::start_server(
         vpn_user_use_case,
         auth_use_case,
+        ppp_secret_use_case,
+        api_token_use_case,
+        totp_use_case,
+        audit_use_case,
+        local_user_use_case,
+        health_use_case,
+        webhook_use_case,
+        alert_use_case,
+        websocket_manager,
+        metrics,
         config_service,
-    ).await
-}
-
-// Dummy ping service for initial setup
-struct DummyPingService;
-
-impl DummyPingService {
-    fn new() -> Self {
-        Self
-    }
-}
-
-#[async_trait::async_trait]
-impl PingService for DummyPingService {
-    async fn ping_user(&self, _user: &domain::models::VpnUser) -> Result<Option<f64>, domain::models::DomainError> {
-        Ok(Some(0.0))
-    }
+        &args,
+    ).await;
     
-    async fn start_monitoring(&self, _user: &domain::models::VpnUser) -> Result<(), domain::models::DomainError> {
-        Ok(())
-    }
+    ping_monitor_handle.stop().await;
+    info!("mikriting-tool stopped");
     
-    async fn stop_monitoring(&self, _user_name: &str) -> Result<(), domain::models::DomainError> {
-        Ok(())
-    }
+    result
 }
@@ -0,0 +1,66 @@
+use crate::domain::{
+    models::{Alert, AlertRule, AlertSilence, AlertState, NewAlertSilence, DomainError},
+    traits::AlertService,
+};
+use chrono::{Duration, Utc};
+use std::sync::Arc;
+use uuid::Uuid;
+use log::info;
+
+pub struct AlertUseCase {
+    alert_service: Arc<dyn AlertService + Send + Sync>,
+}
+
+impl AlertUseCase {
+    pub fn new(alert_service: Arc<dyn AlertService + Send + Sync>) -> Self {
+        Self { alert_service }
+    }
+
+    pub async fn list_alerts(&self, state: Option<AlertState>) -> Result<Vec<Alert>, DomainError> {
+        let mut alerts = self.alert_service.alerts().await?;
+        if let Some(state) = state {
+            alerts.retain(|alert| alert.state == state);
+        }
+        Ok(alerts)
+    }
+
+    pub fn list_rules(&self) -> Vec<AlertRule> {
+        self.alert_service.rules()
+    }
+
+    pub async fn list_silences(&self) -> Result<Vec<AlertSilence>, DomainError> {
+        self.alert_service.silences().await
+    }
+
+    pub async fn create_silence(&self, request: NewAlertSilence, created_by: &str) -> Result<AlertSilence, DomainError> {
+        request.validate()?;
+
+        let rule = request.rule.map(|rule| rule.trim().to_string());
+        if let Some(rule) = &rule
+            && !self.alert_service.rules().iter().any(|known| known.name == *rule)
+        {
+            return Err(DomainError::InvalidInput(format!("unknown alert rule '{}'", rule)));
+        }
+
+        let now = Utc::now();
+        let silence = AlertSilence {
+            id: Uuid::new_v4().to_string(),
+            rule,
+            user: request.user.map(|user| user.trim().to_string()),
+            comment: request.comment.trim().to_string(),
+            created_by: created_by.to_string(),
+            created_at: now,
+            expires_at: now + Duration::minutes(request.duration_minutes as i64),
+        };
+
+        self.alert_service.add_silence(silence.clone()).await?;
+        info!("Alert silence {} created by {} until {}", silence.id, created_by, silence.expires_at);
+        Ok(silence)
+    }
+
+    pub async fn remove_silence(&self, id: &str) -> Result<(), DomainError> {
+        self.alert_service.remove_silence(id).await?;
+        info!("Alert silence {} removed", id);
+        Ok(())
+    }
+}
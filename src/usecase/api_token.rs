@@ -0,0 +1,71 @@
+use crate::domain::{
+    models::{ApiToken, NewApiToken, DomainError},
+    traits::ApiTokenRepository,
+};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use uuid::Uuid;
+use log::{info, debug};
+
+// Prefix of issued tokens, makes them easy to spot in scripts and secret scanners
+const TOKEN_PREFIX: &str = "mkt_";
+
+pub struct ApiTokenUseCase {
+    token_repository: Arc<dyn ApiTokenRepository + Send + Sync>,
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+impl ApiTokenUseCase {
+    pub fn new(token_repository: Arc<dyn ApiTokenRepository + Send + Sync>) -> Self {
+        Self { token_repository }
+    }
+
+    pub async fn list_tokens(&self) -> Result<Vec<ApiToken>, DomainError> {
+        self.token_repository.list().await
+    }
+
+    // Returns the token together with its secret value, which is not stored
+    // and cannot be retrieved again
+    pub async fn issue_token(&self, request: NewApiToken, created_by: &str) -> Result<(ApiToken, String), DomainError> {
+        request.validate()?;
+
+        let secret = format!("{}{}", TOKEN_PREFIX, hex::encode(rand::random::<[u8; 32]>()));
+        let token = ApiToken {
+            id: Uuid::new_v4().to_string(),
+            name: request.name.trim().to_string(),
+            scope: request.scope,
+            created_by: created_by.to_string(),
+            created_at: Utc::now(),
+            last_used_at: None,
+        };
+
+        self.token_repository.create(&token, &hash_token(&secret)).await?;
+        info!("API token {} ({:?}) issued by {}", token.name, token.scope, created_by);
+
+        Ok((token, secret))
+    }
+
+    pub async fn revoke_token(&self, id: &str) -> Result<(), DomainError> {
+        self.token_repository.revoke(id).await?;
+        info!("API token {} revoked", id);
+        Ok(())
+    }
+
+    pub async fn authenticate(&self, secret: &str) -> Result<Option<ApiToken>, DomainError> {
+        if !secret.starts_with(TOKEN_PREFIX) {
+            return Ok(None);
+        }
+
+        let Some(token) = self.token_repository.find_by_hash(&hash_token(secret)).await? else {
+            debug!("Rejected unknown API token");
+            return Ok(None);
+        };
+
+        self.token_repository.touch(&token.id, Utc::now()).await?;
+        Ok(Some(token))
+    }
+}
@@ -0,0 +1,42 @@
+use crate::domain::{
+    models::{AuditEntry, AuditQuery, AuditRecord, AuditVerification, DomainError},
+    traits::AuditLog,
+};
+use std::sync::Arc;
+use log::error;
+
+// Entries returned by a query without `limit`, and the most one may ask for
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 1000;
+
+pub struct AuditUseCase {
+    audit_log: Arc<dyn AuditLog + Send + Sync>,
+}
+
+impl AuditUseCase {
+    pub fn new(audit_log: Arc<dyn AuditLog + Send + Sync>) -> Self {
+        Self { audit_log }
+    }
+
+    // The action has already happened when it is recorded, so a failed write
+    // is logged rather than returned to the caller
+    pub async fn record(&self, record: AuditRecord) {
+        let (action, actor) = (record.action, record.actor.clone());
+        if let Err(e) = self.audit_log.append(record).await {
+            error!("Failed to write audit log entry {:?} by {}: {}", action, actor, e);
+        }
+    }
+
+    pub async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, DomainError> {
+        let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT).clamp(1, MAX_QUERY_LIMIT);
+        self.audit_log.query(query, limit).await
+    }
+
+    pub async fn export(&self) -> Result<Vec<u8>, DomainError> {
+        self.audit_log.export().await
+    }
+
+    pub async fn verify(&self) -> Result<AuditVerification, DomainError> {
+        self.audit_log.verify().await
+    }
+}
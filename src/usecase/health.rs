@@ -0,0 +1,124 @@
+use crate::domain::{
+    models::{HealthStatus, PingerHealth, ReadinessReport, RouterStatus, SchedulerHealth, UserListHealth},
+    traits::{CacheService, PingService, RouterStatusService},
+};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use log::error;
+
+#[derive(Default)]
+struct SchedulerCycles {
+    last_cycle_at: Option<DateTime<Utc>>,
+    last_success_at: Option<DateTime<Utc>>,
+    last_succeeded: bool,
+}
+
+// Readiness of the service for /readyz. The user list is only trusted while
+// the scheduler keeps refreshing it; probes and single routers failing leave
+// the service degraded but ready
+pub struct HealthUseCase {
+    cache_service: Arc<dyn CacheService + Send + Sync>,
+    ping_service: Arc<dyn PingService + Send + Sync>,
+    router_status_service: Arc<dyn RouterStatusService + Send + Sync>,
+    max_user_list_age_seconds: u64,
+    cycles: RwLock<SchedulerCycles>,
+}
+
+impl HealthUseCase {
+    pub fn new(
+        cache_service: Arc<dyn CacheService + Send + Sync>,
+        ping_service: Arc<dyn PingService + Send + Sync>,
+        router_status_service: Arc<dyn RouterStatusService + Send + Sync>,
+        max_user_list_age_seconds: u64,
+    ) -> Self {
+        Self {
+            cache_service,
+            ping_service,
+            router_status_service,
+            max_user_list_age_seconds,
+            cycles: RwLock::new(SchedulerCycles::default()),
+        }
+    }
+
+    pub async fn record_cycle(&self, success: bool) {
+        let now = Utc::now();
+        let mut cycles = self.cycles.write().await;
+        cycles.last_cycle_at = Some(now);
+        cycles.last_succeeded = success;
+        if success {
+            cycles.last_success_at = Some(now);
+        }
+    }
+
+    pub async fn readiness(&self) -> ReadinessReport {
+        let now = Utc::now();
+        let scheduler = self.scheduler_health().await;
+        let user_list = self.user_list_health(now).await;
+        let pinger = self.pinger_health().await;
+        let routers = self.router_status_service.router_statuses().await;
+
+        let status = [scheduler.status, user_list.status, pinger.status, routers_status(&routers)]
+            .into_iter()
+            .max()
+            .unwrap_or(HealthStatus::Ok);
+
+        ReadinessReport {
+            status,
+            checked_at: now,
+            scheduler,
+            user_list,
+            pinger,
+            routers,
+        }
+    }
+
+    async fn scheduler_health(&self) -> SchedulerHealth {
+        let cycles = self.cycles.read().await;
+
+        SchedulerHealth {
+            // Not ready before the first cycle has filled the user list
+            status: if cycles.last_succeeded { HealthStatus::Ok } else { HealthStatus::Down },
+            last_cycle_at: cycles.last_cycle_at,
+            last_success_at: cycles.last_success_at,
+        }
+    }
+
+    async fn user_list_health(&self, now: DateTime<Utc>) -> UserListHealth {
+        let updated_at = self.cache_service.vpn_users_updated_at()
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to read user list age: {}", e);
+                None
+            });
+        let age_seconds = updated_at.map(|updated_at| (now - updated_at).num_seconds());
+        let fresh = age_seconds.is_some_and(|age| age <= self.max_user_list_age_seconds as i64);
+
+        UserListHealth {
+            status: if fresh { HealthStatus::Ok } else { HealthStatus::Down },
+            updated_at,
+            age_seconds,
+            max_age_seconds: self.max_user_list_age_seconds,
+        }
+    }
+
+    async fn pinger_health(&self) -> PingerHealth {
+        let probes = self.ping_service.probe_health().await;
+
+        PingerHealth {
+            status: if probes.iter().all(|probe| probe.available) { HealthStatus::Ok } else { HealthStatus::Degraded },
+            probes,
+        }
+    }
+}
+
+// Down when no router answers, degraded when only some do
+fn routers_status(routers: &[RouterStatus]) -> HealthStatus {
+    let down = routers.iter().filter(|router| router.status == HealthStatus::Down).count();
+
+    match down {
+        0 => HealthStatus::Ok,
+        down if down == routers.len() => HealthStatus::Down,
+        _ => HealthStatus::Degraded,
+    }
+}
@@ -0,0 +1,89 @@
+use crate::domain::{
+    models::{LocalUser, NewLocalUser, DomainError, validate_password, validate_username},
+    traits::LocalUserRepository,
+};
+use bcrypt::Version;
+use std::sync::Arc;
+use tokio::task;
+use log::info;
+
+// Work factor of new password hashes
+const BCRYPT_COST: u32 = 12;
+
+// Hashes in the `$2y$` form written by Apache's `htpasswd -B`
+async fn hash_password(password: &str) -> Result<String, DomainError> {
+    validate_password(password)?;
+
+    let password = password.to_string();
+    task::spawn_blocking(move || bcrypt::non_truncating_hash_with_result(password, BCRYPT_COST))
+        .await
+        .map_err(|e| DomainError::StorageError(format!("Password hash task failed: {}", e)))?
+        .map(|hash| hash.format_for_version(Version::TwoY))
+        .map_err(|e| DomainError::InvalidInput(format!("Cannot hash password: {}", e)))
+}
+
+// Dashboard accounts in `.htpasswd`, managed by admins through the API and
+// from the command line
+pub struct LocalUserUseCase {
+    user_repository: Arc<dyn LocalUserRepository + Send + Sync>,
+}
+
+impl LocalUserUseCase {
+    pub fn new(user_repository: Arc<dyn LocalUserRepository + Send + Sync>) -> Self {
+        Self { user_repository }
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<LocalUser>, DomainError> {
+        self.user_repository.list().await
+    }
+
+    pub async fn create_user(&self, user: NewLocalUser) -> Result<LocalUser, DomainError> {
+        user.validate()?;
+
+        let password_hash = hash_password(&user.password).await?;
+        self.user_repository.create(&user.username, &password_hash, user.role).await?;
+
+        // Read back so the role reflects default_role when none was given
+        let created = self.user_repository.list().await?
+            .into_iter()
+            .find(|created| created.username == user.username)
+            .ok_or_else(|| DomainError::UserNotFound(user.username.clone()))?;
+        info!("Login user {} created as {}", created.username, created.role);
+        Ok(created)
+    }
+
+    pub async fn delete_user(&self, username: &str) -> Result<(), DomainError> {
+        validate_username(username)?;
+        self.user_repository.delete(username).await
+    }
+
+    pub async fn reset_password(&self, username: &str, password: &str) -> Result<(), DomainError> {
+        validate_username(username)?;
+
+        let password_hash = hash_password(password).await?;
+        self.user_repository.set_password(username, &password_hash).await
+    }
+
+    pub async fn credential_stamp(&self, username: &str) -> Result<Option<String>, DomainError> {
+        self.user_repository.credential_stamp(username).await
+    }
+
+    // Self-service change, only with the current password. Users from LDAP
+    // or single sign-on have no password here to change
+    pub async fn change_password(&self, username: &str, current_password: &str, new_password: &str) -> Result<(), DomainError> {
+        if !self.user_repository.list().await?.iter().any(|user| user.username == username) {
+            return Err(DomainError::InvalidInput(format!(
+                "the password of {} is not managed by this server", username
+            )));
+        }
+        if !self.user_repository.check_password(username, current_password).await? {
+            return Err(DomainError::AuthenticationFailed);
+        }
+        if current_password == new_password {
+            return Err(DomainError::InvalidInput("the new password must differ from the current one".to_string()));
+        }
+
+        let password_hash = hash_password(new_password).await?;
+        self.user_repository.set_password(username, &password_hash).await
+    }
+}
@@ -1,3 +1,19 @@
 pub mod vpn_user;
+pub mod ppp_secret;
+pub mod api_token;
+pub mod totp;
+pub mod audit;
+pub mod local_user;
+pub mod health;
+pub mod webhook;
+pub mod alert;
 
-pub use vpn_user::*;
\ No newline at end of file
+pub use vpn_user::*;
+pub use ppp_secret::*;
+pub use api_token::*;
+pub use totp::*;
+pub use audit::*;
+pub use local_user::*;
+pub use health::*;
+pub use webhook::*;
+pub use alert::*;
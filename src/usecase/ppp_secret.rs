@@ -0,0 +1,50 @@
+use crate::domain::{
+    models::{PppSecret, NewPppSecret, PppSecretUpdate, DomainError, validate_secret_name},
+    traits::PppSecretService,
+};
+use std::sync::Arc;
+use log::{info, debug};
+
+pub struct PppSecretUseCase {
+    secret_service: Arc<dyn PppSecretService + Send + Sync>,
+}
+
+impl PppSecretUseCase {
+    pub fn new(secret_service: Arc<dyn PppSecretService + Send + Sync>) -> Self {
+        Self { secret_service }
+    }
+
+    pub async fn list_secrets(&self, router: Option<&str>) -> Result<Vec<PppSecret>, DomainError> {
+        debug!("Listing PPP secrets (router: {:?})", router);
+        self.secret_service.list_secrets(router).await
+    }
+
+    pub async fn create_secret(&self, router: Option<&str>, secret: NewPppSecret) -> Result<PppSecret, DomainError> {
+        secret.validate()?;
+        
+        info!("Creating PPP secret: {}", secret.name);
+        self.secret_service.create_secret(router, &secret).await
+    }
+
+    pub async fn update_secret(&self, router: Option<&str>, name: &str, update: PppSecretUpdate) -> Result<PppSecret, DomainError> {
+        validate_secret_name(name)?;
+        update.validate()?;
+        
+        info!("Updating PPP secret: {}", name);
+        self.secret_service.update_secret(router, name, &update).await
+    }
+
+    pub async fn set_secret_disabled(&self, router: Option<&str>, name: &str, disabled: bool) -> Result<PppSecret, DomainError> {
+        validate_secret_name(name)?;
+        
+        info!("{} PPP secret: {}", if disabled { "Disabling" } else { "Enabling" }, name);
+        self.secret_service.set_secret_disabled(router, name, disabled).await
+    }
+
+    pub async fn delete_secret(&self, router: Option<&str>, name: &str) -> Result<(), DomainError> {
+        validate_secret_name(name)?;
+        
+        info!("Deleting PPP secret: {}", name);
+        self.secret_service.delete_secret(router, name).await
+    }
+}
@@ -0,0 +1,297 @@
+use crate::domain::{
+    models::{Role, TotpEnrollment, TotpRecord, DomainError},
+    traits::TotpRepository,
+};
+use chrono::Utc;
+use qrcode::{render::svg, QrCode};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use totp_rs::{Algorithm, Secret, TOTP};
+use log::{info, warn};
+
+// RFC 6238 defaults understood by every authenticator app
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+// Codes from one step before or after are accepted to allow for clock drift
+const SKEW_STEPS: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+pub struct TotpUseCase {
+    totp_repository: Arc<dyn TotpRepository + Send + Sync>,
+    issuer: String,
+    required_roles: Vec<Role>,
+}
+
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    hex::encode(Sha256::digest(normalize_code(code).as_bytes()))
+}
+
+fn generate_recovery_code() -> String {
+    let code = hex::encode(rand::random::<[u8; 5]>());
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+// Compares without short-circuiting so timing does not leak matching digits
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl TotpUseCase {
+    pub fn new(
+        totp_repository: Arc<dyn TotpRepository + Send + Sync>,
+        issuer: String,
+        required_roles: Vec<Role>,
+    ) -> Self {
+        Self {
+            totp_repository,
+            issuer,
+            required_roles,
+        }
+    }
+
+    pub fn is_required(&self, role: Role) -> bool {
+        self.required_roles.contains(&role)
+    }
+
+    pub async fn is_enabled(&self, username: &str) -> Result<bool, DomainError> {
+        Ok(self.totp_repository.find(username).await?.is_some_and(|record| record.enabled))
+    }
+
+    fn totp(&self, username: &str, secret: &str) -> Result<TOTP, DomainError> {
+        let secret = Secret::Encoded(secret.to_string())
+            .to_bytes()
+            .map_err(|e| DomainError::StorageError(format!("Invalid TOTP secret of {}: {:?}", username, e)))?;
+
+        TOTP::new(
+            Algorithm::SHA1,
+            DIGITS,
+            SKEW_STEPS as u8,
+            STEP_SECONDS,
+            secret,
+            Some(self.issuer.clone()),
+            username.to_string(),
+        )
+        .map_err(|e| DomainError::ConfigurationError(format!("Invalid TOTP settings: {}", e)))
+    }
+
+    // Starts (or restarts) an enrollment. It only takes effect once a code
+    // from the authenticator app is confirmed
+    pub async fn start_enrollment(&self, username: &str) -> Result<TotpEnrollment, DomainError> {
+        if self.is_enabled(username).await? {
+            return Err(DomainError::InvalidInput("TOTP is already enabled".to_string()));
+        }
+
+        let secret = match Secret::generate_secret().to_encoded() {
+            Secret::Encoded(secret) => secret,
+            Secret::Raw(_) => unreachable!("to_encoded always returns an encoded secret"),
+        };
+        let otpauth_uri = self.totp(username, &secret)?.get_url();
+        let qr_svg = QrCode::new(otpauth_uri.as_bytes())
+            .map_err(|e| DomainError::SerializationError(format!("Failed to render QR code: {}", e)))?
+            .render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .build();
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect();
+
+        self.totp_repository.save(&TotpRecord {
+            username: username.to_string(),
+            secret: secret.clone(),
+            enabled: false,
+            recovery_code_hashes: recovery_codes.iter().map(|code| hash_recovery_code(code)).collect(),
+            last_used_step: None,
+            created_at: Utc::now(),
+        }).await?;
+
+        info!("TOTP enrollment started for {}", username);
+        Ok(TotpEnrollment {
+            secret,
+            otpauth_uri,
+            qr_svg,
+            recovery_codes,
+        })
+    }
+
+    pub async fn confirm_enrollment(&self, username: &str, code: &str) -> Result<(), DomainError> {
+        let Some(mut record) = self.totp_repository.find(username).await?.filter(|record| !record.enabled) else {
+            return Err(DomainError::InvalidInput("No TOTP enrollment in progress".to_string()));
+        };
+
+        let Some(step) = self.check_totp(&record, code).await? else {
+            return Err(DomainError::InvalidInput("Invalid TOTP code".to_string()));
+        };
+
+        record.last_used_step = Some(step);
+        record.enabled = true;
+        self.totp_repository.save(&record).await?;
+        info!("TOTP enabled for {}", username);
+        Ok(())
+    }
+
+    // Accepts a current TOTP code or one of the unused recovery codes
+    pub async fn verify(&self, username: &str, code: &str) -> Result<bool, DomainError> {
+        let Some(record) = self.totp_repository.find(username).await?.filter(|record| record.enabled) else {
+            return Ok(false);
+        };
+
+        if self.check_totp(&record, code).await?.is_some() {
+            return Ok(true);
+        }
+
+        let Some(left) = self.totp_repository.use_recovery_code(username, &hash_recovery_code(code)).await? else {
+            return Ok(false);
+        };
+        warn!("Recovery code used by {}, {} left", username, left);
+        Ok(true)
+    }
+
+    pub async fn disable(&self, username: &str) -> Result<bool, DomainError> {
+        let removed = self.totp_repository.delete(username).await?;
+        if removed {
+            info!("TOTP disabled for {}", username);
+        }
+        Ok(removed)
+    }
+
+    // The time step of a matching code. Each step can be used once, so an
+    // observed code cannot be replayed, also not by a request running at the
+    // same time: the repository records the step only if it is still unused
+    async fn check_totp(&self, record: &TotpRecord, code: &str) -> Result<Option<u64>, DomainError> {
+        let code = normalize_code(code);
+        if code.len() != DIGITS || !code.chars().all(|c| c.is_ascii_digit()) {
+            return Ok(None);
+        }
+
+        let totp = self.totp(&record.username, &record.secret)?;
+        let current_step = Utc::now().timestamp() as u64 / STEP_SECONDS;
+
+        let Some(step) = (current_step.saturating_sub(SKEW_STEPS)..=current_step + SKEW_STEPS)
+            .filter(|step| record.last_used_step.is_none_or(|last| *step > last))
+            .find(|step| constant_time_eq(&totp.generate(step * STEP_SECONDS), &code))
+        else {
+            return Ok(None);
+        };
+
+        Ok(self.totp_repository.use_step(&record.username, step).await?.then_some(step))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::FileTotpRepository;
+    use async_trait::async_trait;
+    use futures::future::join_all;
+    use std::sync::Mutex;
+    use tokio::sync::Barrier;
+
+    const PARALLEL_REQUESTS: usize = 8;
+
+    // Holds every lookup back until all parallel requests have read the
+    // record, the interleaving in which a replayed code used to pass
+    struct RacingRepository {
+        inner: FileTotpRepository,
+        barrier: Mutex<Option<Arc<Barrier>>>,
+    }
+
+    #[async_trait]
+    impl TotpRepository for RacingRepository {
+        async fn find(&self, username: &str) -> Result<Option<TotpRecord>, DomainError> {
+            let record = self.inner.find(username).await;
+            let barrier = self.barrier.lock().unwrap().clone();
+            if let Some(barrier) = barrier {
+                barrier.wait().await;
+            }
+            record
+        }
+
+        async fn save(&self, record: &TotpRecord) -> Result<(), DomainError> {
+            self.inner.save(record).await
+        }
+
+        async fn delete(&self, username: &str) -> Result<bool, DomainError> {
+            self.inner.delete(username).await
+        }
+
+        async fn use_step(&self, username: &str, step: u64) -> Result<bool, DomainError> {
+            self.inner.use_step(username, step).await
+        }
+
+        async fn use_recovery_code(&self, username: &str, hash: &str) -> Result<Option<usize>, DomainError> {
+            self.inner.use_recovery_code(username, hash).await
+        }
+    }
+
+    // An enabled enrollment in a temporary file, removed when dropped
+    struct Enrolled {
+        use_case: Arc<TotpUseCase>,
+        repository: Arc<RacingRepository>,
+        path: std::path::PathBuf,
+        secret: String,
+        recovery_codes: Vec<String>,
+    }
+
+    impl Enrolled {
+        async fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("mikriting-totp-{}.json", uuid::Uuid::new_v4()));
+            let repository = Arc::new(RacingRepository {
+                inner: FileTotpRepository::open(path.to_str().unwrap()).unwrap(),
+                barrier: Mutex::new(None),
+            });
+            let use_case = Arc::new(TotpUseCase::new(repository.clone(), "mikriting".to_string(), Vec::new()));
+
+            let enrollment = use_case.start_enrollment("alice").await.unwrap();
+            let enrolled = Self { use_case, repository, path, secret: enrollment.secret, recovery_codes: enrollment.recovery_codes };
+            // Confirmed with the code of the previous step, leaving the current one unused
+            let previous = enrolled.code(Utc::now().timestamp() as u64 - STEP_SECONDS);
+            enrolled.use_case.confirm_enrollment("alice", &previous).await.unwrap();
+            enrolled
+        }
+
+        fn code(&self, timestamp: u64) -> String {
+            self.use_case.totp("alice", &self.secret).unwrap().generate(timestamp)
+        }
+
+        // How many of several requests sending `code` at once were let in
+        async fn accepted_in_parallel(&self, code: &str) -> usize {
+            *self.repository.barrier.lock().unwrap() = Some(Arc::new(Barrier::new(PARALLEL_REQUESTS)));
+            let attempts = (0..PARALLEL_REQUESTS).map(|_| {
+                let (use_case, code) = (self.use_case.clone(), code.to_string());
+                tokio::spawn(async move { use_case.verify("alice", &code).await.unwrap() })
+            });
+            let accepted = join_all(attempts).await.into_iter().filter(|accepted| *accepted.as_ref().unwrap()).count();
+            *self.repository.barrier.lock().unwrap() = None;
+            accepted
+        }
+    }
+
+    impl Drop for Enrolled {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[tokio::test]
+    async fn code_is_accepted_once_by_parallel_requests() {
+        let enrolled = Enrolled::new().await;
+        let code = enrolled.code(Utc::now().timestamp() as u64);
+
+        assert_eq!(enrolled.accepted_in_parallel(&code).await, 1);
+        assert!(!enrolled.use_case.verify("alice", &code).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn recovery_code_is_accepted_once_by_parallel_requests() {
+        let enrolled = Enrolled::new().await;
+        let code = enrolled.recovery_codes[0].clone();
+
+        assert_eq!(enrolled.accepted_in_parallel(&code).await, 1);
+        assert!(enrolled.use_case.verify("alice", &enrolled.recovery_codes[1]).await.unwrap());
+    }
+}
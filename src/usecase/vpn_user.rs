@@ -1,9 +1,12 @@
 use crate::domain::{
-    models::{VpnUser, LatencyUpdate, DomainError},
-    traits::{VpnUserRepository, MikrotikService, PingService, EventPublisher, CacheService}
+    models::{VpnUser, VpnSession, UserKey, UserConnected, UserDisconnected, UserChanged, DisconnectReason, LatencyUpdate, LatencyPoint, LoginLockout, LockoutScope, OidcAuthorization, DomainError},
+    traits::{VpnUserRepository, MikrotikService, PingService, EventPublisher, CacheService, LatencyStore, LoginThrottle, OidcProvider}
 };
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::Arc;
-use log::{info, error, debug};
+use log::{info, error, debug, warn};
 
 pub struct VpnUserUseCase {
     vpn_user_repository: Arc<dyn VpnUserRepository + Send + Sync>,
@@ -11,6 +14,7 @@ pub struct VpnUserUseCase {
     ping_service: Arc<dyn PingService + Send + Sync>,
     event_publisher: Arc<dyn EventPublisher + Send + Sync>,
     cache_service: Arc<dyn CacheService + Send + Sync>,
+    latency_store: Arc<dyn LatencyStore + Send + Sync>,
 }
 
 impl VpnUserUseCase {
@@ -20,6 +24,7 @@ impl VpnUserUseCase {
         ping_service: Arc<dyn PingService + Send + Sync>,
         event_publisher: Arc<dyn EventPublisher + Send + Sync>,
         cache_service: Arc<dyn CacheService + Send + Sync>,
+        latency_store: Arc<dyn LatencyStore + Send + Sync>,
     ) -> Self {
         Self {
             vpn_user_repository,
@@ -27,6 +32,7 @@ impl VpnUserUseCase {
             ping_service,
             event_publisher,
             cache_service,
+            latency_store,
         }
     }
 
@@ -43,6 +49,9 @@ impl VpnUserUseCase {
         // Process new and disconnected users
         self.process_user_changes(&cached_users, &fresh_users).await?;
         
+        // Record session history
+        self.persist_sessions(&fresh_users).await;
+        
         // Update cache
         self.cache_service.set_vpn_users(fresh_users.clone()).await?;
         
@@ -53,35 +62,91 @@ impl VpnUserUseCase {
         Ok(fresh_users)
     }
 
+    // Publishes the transitions between the cached and the fresh user list.
+    // A session that ended while another one of the same user on the same
+    // router began is a reconnect. Sessions found by the first poll after
+    // startup are monitored but not reported as connected
     async fn process_user_changes(
         &self,
         old_users: &[VpnUser],
         new_users: &[VpnUser],
     ) -> Result<(), DomainError> {
-        let old_names: std::collections::HashSet<_> = old_users.iter()
-            .map(|u| u.name.clone())
-            .collect();
-        
-        let new_names: std::collections::HashSet<_> = new_users.iter()
-            .map(|u| u.name.clone())
+        let first_poll = self.cache_service.vpn_users_updated_at().await?.is_none();
+        let old_sessions: HashSet<_> = old_users.iter().map(VpnUser::session_key).collect();
+        let new_sessions: HashSet<_> = new_users.iter().map(VpnUser::session_key).collect();
+
+        let mut ended: HashMap<UserKey, Vec<&VpnUser>> = HashMap::new();
+        for user in old_users.iter().filter(|u| !new_sessions.contains(&u.session_key())) {
+            ended.entry(user.key()).or_default().push(user);
+        }
+        let began: Vec<(&VpnUser, Option<&VpnUser>)> = new_users.iter()
+            .filter(|u| !old_sessions.contains(&u.session_key()))
+            .map(|u| (u, ended.get_mut(&u.key()).and_then(Vec::pop)))
             .collect();
 
         // Handle disconnected users
-        for disconnected_user in old_names.difference(&new_names) {
-            debug!("User disconnected: {}", disconnected_user);
-            self.ping_service.stop_monitoring(disconnected_user).await?;
-            self.cache_service.clear_user(disconnected_user).await?;
+        for disconnected_user in ended.into_values().flatten() {
+            let key = disconnected_user.session_key();
+            debug!("User disconnected: {}", key);
+            self.ping_service.stop_monitoring(&key).await?;
+            self.cache_service.clear_user(&key).await?;
+            self.event_publisher.publish_user_disconnected(
+                UserDisconnected::new(disconnected_user.clone(), DisconnectReason::SessionEnded)
+            ).await?;
         }
 
-        // Handle new users
-        for new_user in new_users.iter().filter(|u| !old_names.contains(&u.name)) {
-            debug!("New user connected: {}", new_user.name);
-            self.ping_service.start_monitoring(new_user).await?;
+        for (user, previous) in began {
+            self.ping_service.start_monitoring(user).await?;
+            match previous {
+                // Handle new users
+                None => {
+                    debug!("New user connected: {}", user.session_key());
+                    if !first_poll {
+                        self.event_publisher.publish_user_connected(UserConnected::new(user.clone())).await?;
+                    }
+                }
+                // Handle reconnects, reported when the address or caller ID changed
+                Some(previous) => {
+                    debug!("User reconnected: {}", user.session_key());
+                    self.ping_service.stop_monitoring(&previous.session_key()).await?;
+                    self.cache_service.clear_user(&previous.session_key()).await?;
+                    if let Some(changed) = UserChanged::between(previous, user) {
+                        self.event_publisher.publish_user_changed(changed).await?;
+                    }
+                }
+            }
         }
 
         Ok(())
     }
 
+    // Compares against the repository rather than the cache so sessions that
+    // ended while the application was down get closed as well. Storage
+    // failures are logged, the poll still updates the cache and events
+    async fn persist_sessions(&self, fresh_users: &[VpnUser]) {
+        let fresh_sessions: HashSet<_> = fresh_users.iter().map(VpnUser::session_key).collect();
+
+        match self.vpn_user_repository.find_all().await {
+            Ok(stored_users) => {
+                for stored_user in stored_users {
+                    if fresh_sessions.contains(&stored_user.session_key()) {
+                        continue;
+                    }
+                    if let Err(e) = self.vpn_user_repository.delete(&stored_user).await {
+                        error!("Failed to close session of user {}: {}", stored_user.session_key(), e);
+                    }
+                }
+            }
+            Err(e) => error!("Failed to load open sessions: {}", e),
+        }
+        
+        for user in fresh_users {
+            if let Err(e) = self.vpn_user_repository.save(user).await {
+                error!("Failed to record session of user {}: {}", user.session_key(), e);
+            }
+        }
+    }
+
     pub async fn get_all_users(&self) -> Result<Vec<VpnUser>, DomainError> {
         // Try cache first
         if let Some(cached_users) = self.cache_service.get_vpn_users().await? {
@@ -96,7 +161,10 @@ impl VpnUserUseCase {
         debug!("Updating latency for user: {} -> {:?}", update.user_name, update.latency);
         
         // Update cache
-        self.cache_service.set_user_latency(&update.user_name, update.latency).await?;
+        self.cache_service.set_user_latency(&update).await?;
+        
+        // Keep history
+        self.latency_store.record(&update.key(), Utc::now(), update.latency).await?;
         
         // Publish latency update
         self.event_publisher.publish_latency_update(update).await?;
@@ -104,47 +172,175 @@ impl VpnUserUseCase {
         Ok(())
     }
 
-    pub async fn disconnect_user(&self, user_name: &str) -> Result<(), DomainError> {
-        info!("Disconnecting user: {}", user_name);
+    pub async fn get_latency_series(
+        &self,
+        router: Option<&str>,
+        user_name: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        step_seconds: u64,
+    ) -> Result<Vec<LatencyPoint>, DomainError> {
+        self.latency_store.query(router, user_name, from, to, step_seconds).await
+    }
+
+    pub async fn prune_latency_history(&self) -> Result<(), DomainError> {
+        self.latency_store.prune(Utc::now()).await
+    }
+
+    pub async fn disconnect_user(&self, router: Option<&str>, user_name: &str) -> Result<(), DomainError> {
+        info!("Disconnecting user: {}", UserKey::new(router, user_name));
         
         // Disconnect from MikroTik
-        self.mikrotik_service.disconnect_user(user_name).await?;
-        
-        // Stop monitoring
-        self.ping_service.stop_monitoring(user_name).await?;
+        self.mikrotik_service.disconnect_user(router, user_name).await?;
         
-        // Clear from cache
-        self.cache_service.clear_user(user_name).await?;
+        // Without a router the name had a single session to end
+        let ended: Vec<_> = self.cache_service.get_vpn_users().await?
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|u| u.name == user_name && (router.is_none() || u.router.as_deref() == router))
+            .collect();
+
+        for user in ended {
+            let key = user.session_key();
+
+            // Stop monitoring
+            self.ping_service.stop_monitoring(&key).await?;
+
+            // Clear from cache, the next poll then has nothing to report for it
+            self.cache_service.clear_user(&key).await?;
+
+            self.event_publisher.publish_user_disconnected(
+                UserDisconnected::new(user, DisconnectReason::ManualDisconnect)
+            ).await?;
+        }
         
         Ok(())
     }
 
+    pub async fn get_user_sessions(&self, name: &str, limit: usize) -> Result<Vec<VpnSession>, DomainError> {
+        self.vpn_user_repository.find_sessions(name, limit).await
+    }
+
     #[allow(dead_code)]
     pub async fn get_user_by_name(&self, name: &str) -> Result<Option<VpnUser>, DomainError> {
         self.vpn_user_repository.find_by_name(name).await
     }
 }
 
+// How long the identity provider may take to send the browser back
+const OIDC_LOGIN_MINUTES: i64 = 10;
+
 pub struct AuthUseCase {
     auth_repository: Arc<dyn crate::domain::traits::AuthRepository + Send + Sync>,
+    login_throttle: Arc<dyn LoginThrottle + Send + Sync>,
+    oidc_provider: Option<Arc<dyn OidcProvider + Send + Sync>>,
 }
 
 impl AuthUseCase {
-    pub fn new(auth_repository: Arc<dyn crate::domain::traits::AuthRepository + Send + Sync>) -> Self {
-        Self { auth_repository }
+    pub fn new(
+        auth_repository: Arc<dyn crate::domain::traits::AuthRepository + Send + Sync>,
+        login_throttle: Arc<dyn LoginThrottle + Send + Sync>,
+        oidc_provider: Option<Arc<dyn OidcProvider + Send + Sync>>,
+    ) -> Self {
+        Self {
+            auth_repository,
+            login_throttle,
+            oidc_provider,
+        }
     }
 
-    pub async fn authenticate(&self, username: &str, password: &str) -> Result<crate::domain::models::AuthUser, DomainError> {
+    // Refuses throttled clients before the password hash is checked, so a
+    // flood of guesses does not turn into a flood of bcrypt work
+    pub async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        client_ip: IpAddr,
+    ) -> Result<crate::domain::models::AuthUser, DomainError> {
         debug!("Attempting authentication for user: {}", username);
+        self.check_throttle(client_ip, username).await?;
         
-        let auth_user = self.auth_repository.authenticate(username, password).await?;
+        let auth_user = match self.auth_repository.authenticate(username, password).await {
+            Ok(auth_user) if auth_user.is_authenticated => auth_user,
+            Ok(_) | Err(DomainError::AuthenticationFailed) => {
+                error!("Authentication failed for user: {}", username);
+                self.record_failure(client_ip, username).await?;
+                return Err(DomainError::AuthenticationFailed);
+            }
+            Err(e) => return Err(e),
+        };
+        
+        info!("User authenticated successfully: {}", username);
+        Ok(auth_user)
+    }
+
+    pub async fn check_throttle(&self, client_ip: IpAddr, username: &str) -> Result<(), DomainError> {
+        match self.login_throttle.retry_after(client_ip, username).await? {
+            Some(seconds) => {
+                warn!("Login for {} from {} throttled for {}s", username, client_ip, seconds);
+                Err(DomainError::TooManyAttempts(seconds))
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Also used by the second login step for wrong TOTP codes
+    pub async fn record_failure(&self, client_ip: IpAddr, username: &str) -> Result<(), DomainError> {
+        self.login_throttle.record_failure(client_ip, username).await
+    }
+
+    // Called once every login step passed
+    pub async fn login_completed(&self, username: &str) -> Result<(), DomainError> {
+        self.login_throttle.record_success(username).await
+    }
+
+    pub async fn list_lockouts(&self) -> Result<Vec<LoginLockout>, DomainError> {
+        self.login_throttle.list().await
+    }
+
+    pub async fn clear_lockout(&self, scope: LockoutScope, subject: &str) -> Result<bool, DomainError> {
+        let cleared = self.login_throttle.clear(scope, subject).await?;
+        if cleared {
+            info!("Cleared failed logins of {} {}", scope, subject);
+        }
+        Ok(cleared)
+    }
+
+    // Name of the single sign-on button, if OpenID Connect is configured
+    pub fn oidc_display_name(&self) -> Option<&str> {
+        self.oidc_provider.as_ref().map(|provider| provider.display_name())
+    }
+
+    fn oidc_provider(&self) -> Result<&Arc<dyn OidcProvider + Send + Sync>, DomainError> {
+        self.oidc_provider.as_ref()
+            .ok_or_else(|| DomainError::ConfigurationError("OpenID Connect is not configured".to_string()))
+    }
+
+    pub async fn start_oidc_login(&self) -> Result<(String, OidcAuthorization), DomainError> {
+        self.oidc_provider()?.authorization_url().await
+    }
+
+    // Completes the login once the identity provider redirected back with
+    // `code`; `state` must belong to the request stored in this browser's session
+    pub async fn finish_oidc_login(
+        &self,
+        code: &str,
+        state: &str,
+        request: &OidcAuthorization,
+    ) -> Result<crate::domain::models::AuthUser, DomainError> {
+        let provider = self.oidc_provider()?;
         
-        if auth_user.is_authenticated {
-            info!("User authenticated successfully: {}", username);
-        } else {
-            error!("Authentication failed for user: {}", username);
+        if request.state != state {
+            warn!("OIDC callback with a state that does not match the session");
+            return Err(DomainError::AuthenticationFailed);
+        }
+        if Utc::now() - request.created_at > chrono::Duration::minutes(OIDC_LOGIN_MINUTES) {
+            debug!("OIDC login request expired");
+            return Err(DomainError::AuthenticationFailed);
         }
         
+        let auth_user = provider.exchange_code(code, request).await?;
+        info!("User authenticated through OpenID Connect: {}", auth_user.username);
         Ok(auth_user)
     }
 
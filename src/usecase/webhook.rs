@@ -0,0 +1,27 @@
+use crate::domain::{
+    models::{DeadLetter, RedeliveryResult, DomainError},
+    traits::DeadLetterQueue,
+};
+use std::sync::Arc;
+
+pub struct WebhookUseCase {
+    dead_letters: Arc<dyn DeadLetterQueue + Send + Sync>,
+}
+
+impl WebhookUseCase {
+    pub fn new(dead_letters: Arc<dyn DeadLetterQueue + Send + Sync>) -> Self {
+        Self { dead_letters }
+    }
+
+    pub async fn list_dead_letters(&self) -> Result<Vec<DeadLetter>, DomainError> {
+        self.dead_letters.list().await
+    }
+
+    pub async fn redeliver_dead_letters(&self) -> Result<RedeliveryResult, DomainError> {
+        self.dead_letters.redeliver().await
+    }
+
+    pub async fn clear_dead_letters(&self) -> Result<usize, DomainError> {
+        self.dead_letters.clear().await
+    }
+}